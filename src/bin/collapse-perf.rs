@@ -1,24 +1,53 @@
-use std::io;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 use env_logger::Env;
-use inferno::collapse::perf::{Folder, Options};
-use inferno::collapse::{Collapse, DEFAULT_NTHREADS};
+use inferno::collapse::perf::{
+    parse_stack_line, read_events, CategoryMap, Domain, DumpedLine, Event, EventAnnotation,
+    EventSink, Fields, FlatProfile, Folder, HotLeaf, Options, RegexAnnotation, Stats, Warning,
+};
+use inferno::collapse::{perf_trace, Collapse, DEFAULT_NTHREADS};
+use inferno::differential;
+use inferno::flamegraph::{self, Palette};
+use libflate::gzip;
 use once_cell::sync::Lazy;
+use regex::Regex;
 
 static NTHREADS: Lazy<String> = Lazy::new(|| DEFAULT_NTHREADS.to_string());
 
+/// The environment variable that holds default arguments; see `args`.
+const OPTS_ENV_VAR: &str = "INFERNO_COLLAPSE_PERF_OPTS";
+
+/// The number of events examined by --check.
+const CHECK_NEVENTS: usize = 10;
+
+/// The first bytes of a file written by --write-cache, including the version of its format.
+const CACHE_MAGIC: &[u8] = b"inferno-collapse-perf cache 1\n";
+
 #[derive(Debug, Parser)]
 #[clap(
     name = "inferno-collapse-perf",
     about,
+    args_override_self = true,
     after_help = "\
 [1] perf script must emit both PID and TIDs for these to work; eg, Linux < 4.1:
         perf script -f comm,pid,tid,cpu,time,event,ip,sym,dso,trace
     for Linux >= 4.1:
         perf script -F comm,pid,tid,cpu,time,event,ip,sym,dso,trace
-    If you save this output add --header on Linux >= 3.14 to include perf info."
+    If you save this output add --header on Linux >= 3.14 to include perf info.
+
+Default arguments can be given as a whitespace-separated list in the
+INFERNO_COLLAPSE_PERF_OPTS environment variable (e.g. \"--kernel --jit\"). They are
+parsed as if given before the arguments on the command line, which take precedence:
+an option given in both places takes its value from the command line only, except
+that the values of repeatable options (such as --ignore-prefix) are combined."
 )]
 struct Opt {
     // ************* //
@@ -28,10 +57,89 @@ struct Opt {
     #[clap(long = "addrs")]
     addrs: bool,
 
+    /// Prefix raw addresses included by --addrs with `0x`
+    #[clap(long = "addr-prefix")]
+    addr_prefix: bool,
+
     /// All annotations (--kernel --jit)
     #[clap(long = "all")]
     all: bool,
 
+    /// Remove modifiers from --event-filter, so that e.g. `cycles:uppp` matches `cycles` events
+    #[clap(long = "canonical-event")]
+    canonical_event: bool,
+
+    /// Report which perf script fields the input has and which options they support, then exit
+    /// without collapsing
+    #[clap(long = "check")]
+    check: bool,
+
+    /// Describe the perf script fields of the first event as JSON, with an example value of
+    /// each, then exit without collapsing
+    #[clap(long = "emit-schema", conflicts_with = "check")]
+    emit_schema: bool,
+
+    /// Instead of the folded stacks, write the number of unique stacks, and the share of the
+    /// samples that the top 1% of them account for, to see how concentrated the profile is
+    #[clap(
+        long = "count-unique-stacks-only",
+        conflicts_with_all = &["diff", "flamegraph", "group-output", "merge-into", "report", "timeline"]
+    )]
+    count_unique_stacks_only: bool,
+
+    /// Cut stacks at the leafmost frame matching --cut-above or --cut-below, rather than the
+    /// rootmost one
+    #[clap(long = "cut-leafmost")]
+    cut_leafmost: bool,
+
+    /// Write a `# total_samples N` comment line with the sum of all counts before the stacks
+    #[clap(long = "emit-total")]
+    emit_total: bool,
+
+    /// Write `# event EVENT` and `# countname UNIT` comment lines before the stacks, with the
+    /// event that was collapsed and the unit of the counts (e.g. samples, or cycles with
+    /// --weight-from-header); inferno-flamegraph uses the latter unless --countname is given
+    #[clap(
+        long = "emit-metadata",
+        conflicts_with_all = &["diff", "merge-into", "read-cache", "report", "timeline"]
+    )]
+    emit_metadata: bool,
+
+    /// Render the folded stacks as a flame graph SVG right away, as inferno-flamegraph would,
+    /// instead of writing them out
+    #[clap(
+        long = "flamegraph",
+        conflicts_with_all = &["diff", "merge-into", "report", "timeline"]
+    )]
+    flamegraph: bool,
+
+    /// Attribute Rust closures and C++ lambdas to their enclosing function
+    #[clap(long = "fold-closures")]
+    fold_closures: bool,
+
+    /// Merge GCC clones (like parse.isra.0, parse.part.1, or parse.cold) with their base function
+    #[clap(long = "fold-gcc-clones")]
+    fold_gcc_clones: bool,
+
+    /// Merge each run of adjacent frames from a module whose path contains STRING (e.g. libssl)
+    /// into a single frame named after the module (repeatable)
+    #[clap(long = "fold-module", value_name = "STRING")]
+    fold_module: Vec<String>,
+
+    /// Remove PLT stubs (like malloc@plt) and dynamic linker trampolines, attributing their
+    /// samples to the caller
+    #[clap(long = "fold-plt")]
+    fold_plt: bool,
+
+    /// Keep the innermost frame of each chain merged by --fold-wrappers, rather than the outermost
+    #[clap(long = "fold-wrappers-innermost", requires = "fold-wrappers")]
+    fold_wrappers_innermost: bool,
+
+    /// Read folded stacks (e.g. an earlier output of this tool) instead of perf script output,
+    /// and re-apply frame-level options such as --keep-frame and --annotate-regex to them
+    #[clap(long = "from-folded")]
+    from_folded: bool,
+
     /// Annotate jit functions with a `_[j]`
     #[clap(long = "jit")]
     jit: bool,
@@ -40,6 +148,52 @@ struct Opt {
     #[clap(long = "kernel")]
     kernel: bool,
 
+    /// Annotate frames that call between user space and the kernel with a `_[t]`
+    #[clap(long = "mark-transitions")]
+    mark_transitions: bool,
+
+    /// Reduce module paths to their file names before inspecting them, so that output from hosts
+    /// with different library paths is comparable
+    #[clap(long = "module-basename")]
+    module_basename: bool,
+
+    /// Only keep samples whose leaf frame is in the kernel
+    #[clap(long = "kernel-only", conflicts_with = "user-only")]
+    kernel_only: bool,
+
+    /// Only keep samples whose leaf frame is in user space
+    #[clap(long = "user-only", conflicts_with = "kernel-only")]
+    user_only: bool,
+
+    /// With --kernel-only or --user-only, keep every sample but remove the frames outside
+    /// the requested domain (samples left empty are dropped)
+    #[clap(long = "trim-to-domain")]
+    trim_to_domain: bool,
+
+    /// The process name to use for events whose comm is `-` or empty, as perf sometimes reports
+    /// for unknown or exiting processes
+    #[clap(long = "unknown-comm", value_name = "NAME", default_value = "unknown")]
+    unknown_comm: String,
+
+    /// Write one `TIMESTAMP STACK` line per event, in input order, instead of aggregated folded
+    /// stacks; for timeline tools, not for inferno-flamegraph
+    #[clap(long = "timeline")]
+    timeline: bool,
+
+    /// Don't annotate frames with an [unknown] symbol that are named after their module
+    #[clap(long = "no-annotate-unknown")]
+    no_annotate_unknown: bool,
+
+    /// Drop idle time, to see only the time that CPUs were busy: the samples of the swapper
+    /// process, and those with a kernel idle function (like native_safe_halt) on their stack
+    #[clap(long = "no-idle")]
+    no_idle: bool,
+
+    /// Keep the spaces in process names (e.g. `V8 WorkerThread`) instead of replacing them with
+    /// underscores; this may confuse tools that split folded lines on whitespace
+    #[clap(long = "keep-pname-spaces")]
+    keep_pname_spaces: bool,
+
     /// Include PID with process names
     #[clap(long = "pid")]
     pid: bool,
@@ -48,6 +202,63 @@ struct Opt {
     #[clap(long = "tid")]
     tid: bool,
 
+    /// Ignore stack frames and count samples per process, for a flat CPU-by-process view
+    #[clap(long = "process-only")]
+    process_only: bool,
+
+    /// Print statistics about the collapsed events to STDERR
+    #[clap(long = "stats", conflicts_with = "read-cache")]
+    stats: bool,
+
+    /// With --stats, also report the longest run of consecutive events with identical stacks,
+    /// which may indicate that the program was stuck
+    #[clap(long = "runs", requires = "stats")]
+    runs: bool,
+
+    /// With --stats, also list the total count of the samples with each frame on their stack,
+    /// to debug the collapsing
+    #[clap(long = "debug-counts", requires = "stats")]
+    debug_counts: bool,
+
+    /// With --stats, also count the stacks with suspicious program counters, such as a frame at
+    /// address 0 that isn't the outermost one, which indicate unwinding problems
+    #[clap(long = "validate-pcs", requires = "stats")]
+    validate_pcs: bool,
+
+    /// Remove ANSI escape sequences (e.g. color codes) from the input lines before parsing them
+    #[clap(long = "strip-ansi")]
+    strip_ansi: bool,
+
+    /// Fail on the first event or stack line that can't be parsed, naming it and its line number,
+    /// instead of warning about it; lines skipped by --ignore-prefix never fail
+    #[clap(long = "strict")]
+    strict: bool,
+
+    /// Weight samples by their period if the `perf script --header` metadata shows that it was
+    /// recorded; the unit of the counts (e.g. cycles, for inferno-flamegraph --countname) is
+    /// logged with -v
+    #[clap(long = "weight-from-header", conflicts_with = "weight-column")]
+    weight_from_header: bool,
+
+    /// With several threads, log warnings in input order, exactly like a single thread would,
+    /// for reproducible output in CI; this costs a little performance and delays warnings until
+    /// the end
+    #[clap(long = "deterministic")]
+    deterministic: bool,
+
+    /// Merge frames whose names only differ in case, naming them as first seen; for captures
+    /// converted from other platforms (e.g. Windows ETW), not for native perf output
+    #[clap(long = "case-fold-frames")]
+    case_fold_frames: bool,
+
+    /// Separate the folded stacks of every process (i.e., every first frame) with an empty line,
+    /// e.g. to split them up with shell tools; inferno-flamegraph skips empty lines
+    #[clap(
+        long = "group-output",
+        conflicts_with_all = &["diff", "merge-into", "report", "timeline"]
+    )]
+    group_output: bool,
+
     /// Silence all log output
     #[clap(short = 'q', long = "quiet")]
     quiet: bool,
@@ -59,10 +270,154 @@ struct Opt {
     // *************** //
     // *** OPTIONS *** //
     // *************** //
-    /// Event filter [default: first encountered event]
+    /// Append SUFFIX to functions matching RE; prefix with `module:` to match modules instead.
+    /// Repeatable; every matching annotation is applied, in the order given, after --kernel/--jit
+    #[clap(long = "annotate-regex", value_name = "RE=SUFFIX")]
+    annotate_regex: Vec<RegexAnnotation>,
+
+    /// Append SUFFIX (e.g. `_[o]`) to every function in the stacks of EVENT, which may be a glob
+    /// like --event-filter; to tell events apart when --event-filter matches several of them.
+    /// Repeatable; the first matching annotation wins
+    #[clap(long = "event-annotate", value_name = "EVENT=SUFFIX")]
+    event_annotate: Vec<EventAnnotation>,
+
+    /// Add each process's category as a root frame, using a file of `RE=CATEGORY` lines that
+    /// map process names to categories (the first match wins)
+    #[clap(long = "category-map", value_name = "FILE")]
+    category_map: Option<PathBuf>,
+
+    /// Add a root frame to the events after each `# STRING...` comment, named after the rest of
+    /// the comment, until the next such comment (e.g. to group samples by request)
+    #[clap(long = "comment-prefix", value_name = "STRING")]
+    comment_prefix: Option<String>,
+
+    /// Color palette of the flame graph rendered with --flamegraph
+    #[clap(
+        long = "colors",
+        requires = "flamegraph",
+        default_value = flamegraph::defaults::COLORS,
+        possible_values = &["aqua","blue","green","hot","io","java","js","mem","orange","perl","purple","red","rust","wakeup","yellow"],
+        value_name = "STRING"
+    )]
+    colors: Palette,
+
+    /// Add the NUMA node of each sample's CPU as a root frame, assuming N consecutive CPUs per node
+    #[clap(long = "cpus-per-node", value_name = "N")]
+    cpus_per_node: Option<NonZeroUsize>,
+
+    /// Remove the frames that a frame matching RE calls, keeping it and its callers
+    #[clap(long = "cut-above", value_name = "RE")]
+    cut_above: Option<Regex>,
+
+    /// Remove the callers of a frame matching RE, keeping it and the frames it calls (e.g. to
+    /// focus on a subsystem)
+    #[clap(long = "cut-below", value_name = "RE")]
+    cut_below: Option<Regex>,
+
+    /// The category of processes that match nothing in --category-map
+    #[clap(
+        long = "default-category",
+        value_name = "NAME",
+        default_value = "uncategorized",
+        requires = "category-map"
+    )]
+    default_category: String,
+
+    /// Annotate deoptimized or interpreted JIT frames whose function name matches RE with _[d]
+    /// (e.g. '^(LazyCompile|JS):~|^Interpreter$' for V8 and the JVM)
+    #[clap(long = "deopt-regex", value_name = "RE")]
+    deopt_regex: Option<Regex>,
+
+    /// Annotate vDSO functions whose name matches RE with _[vdso], whatever their module
+    /// (e.g. '^__(vdso|kernel)_' for __vdso_gettimeofday or __kernel_clock_gettime)
+    #[clap(long = "vdso-regex", value_name = "RE")]
+    vdso_regex: Option<Regex>,
+
+    /// Compare the stacks with those in the folded FILE (e.g. an earlier output of this tool),
+    /// and write `STACK BEFORE AFTER` lines, where BEFORE is the count in FILE, for
+    /// inferno-flamegraph to draw a differential flame graph
+    #[clap(long = "diff", value_name = "FILE", conflicts_with_all = &["emit-total", "timeline"])]
+    diff: Option<PathBuf>,
+
+    /// Print the first N non-comment input lines to STDERR, each with how it was interpreted
+    /// (event, stack, blank, ignored, or folded), to debug unexpected output
+    #[clap(long = "dump-lines", value_name = "N")]
+    dump_lines: Option<usize>,
+
+    /// Event filter, which may be a glob where * matches any characters and ? any one character
+    /// (e.g. 'sched:*' for all scheduler tracepoints) [default: first encountered event]
     #[clap(long = "event-filter", value_name = "STRING")]
     event_filter: Option<String>,
 
+    /// Merge each chain of adjacent frames matching RE (e.g. the glibc wrappers of a system call)
+    /// into a single frame
+    #[clap(long = "fold-wrappers", value_name = "RE")]
+    fold_wrappers: Option<Regex>,
+
+    /// The format of the input: the output of `perf script`, or of `perf trace` (e.g.
+    /// `perf trace --call-graph dwarf -o trace.txt -- CMD`), which counts each system call.
+    /// With perf-trace, only --tid, --top, and the logging options apply
+    #[clap(
+        long = "format",
+        value_name = "FORMAT",
+        default_value = "perf-script",
+        possible_values = &["perf-script", "perf-trace"]
+    )]
+    format: String,
+
+    /// Advanced: treat a TID that reappears after more than SECS seconds without events as a new
+    /// thread, and number its generations (implies --tid)
+    #[clap(long = "group-by-tid-lifetime", value_name = "SECS")]
+    group_by_tid_lifetime: Option<f64>,
+
+    /// Skip lines starting with STRING (repeatable); common perf warnings are always skipped
+    #[clap(long = "ignore-prefix", value_name = "STRING")]
+    ignore_prefix: Vec<String>,
+
+    /// Only keep frames matching RE; children of removed frames attach to the nearest kept ancestor
+    #[clap(long = "keep-frame", value_name = "RE")]
+    keep_frame: Option<Regex>,
+
+    /// Stop after counting N events, without reading the rest of the input (e.g. to profile just
+    /// the startup of a program)
+    #[clap(long = "limit-events", value_name = "N")]
+    limit_events: Option<NonZeroUsize>,
+
+    /// Add the folded stacks to those in FILE (created if missing) instead of writing them out,
+    /// e.g. to accumulate a rolling profile across many captures. FILE is replaced atomically,
+    /// but concurrent merges into the same FILE are not coordinated, and all but one of them may
+    /// be lost
+    #[clap(
+        long = "merge-into",
+        value_name = "FILE",
+        conflicts_with_all = &["diff", "emit-total", "output", "report", "timeline"]
+    )]
+    merge_into: Option<PathBuf>,
+
+    /// Fail if more than N event or stack lines could not be parsed, instead of writing partial
+    /// results
+    #[clap(long = "max-malformed", value_name = "N")]
+    max_malformed: Option<usize>,
+
+    /// Drop events with more than N frames, to guard against corrupted input (0 for no limit)
+    /// [default: 100000]
+    #[clap(long = "max-stack-frames", value_name = "N")]
+    max_stack_frames: Option<usize>,
+
+    /// Fail if more than RATIO (from 0.0 to 1.0) of all frames have an unknown symbol, e.g.
+    /// because of missing debug symbols
+    #[clap(long = "max-unknown-ratio", value_name = "RATIO")]
+    max_unknown_ratio: Option<f64>,
+
+    /// Drop the stacks whose count is less than P percent (from 0 to 100) of the total, like
+    /// frames too narrow to see in a flame graph, regardless of the size of the capture
+    #[clap(long = "min-width-pct", value_name = "P", conflicts_with = "timeline")]
+    min_width_pct: Option<f64>,
+
+    /// With --min-width-pct, count the dropped stacks together as a single [other] stack
+    #[clap(long = "min-width-other", requires = "min-width-pct")]
+    min_width_other: bool,
+
     /// Number of threads to use
     #[clap(
         short = 'n',
@@ -72,11 +427,168 @@ struct Opt {
     )]
     nthreads: usize,
 
+    /// Write the folded stacks to FILE instead of STDOUT, gzip-compressed if it ends in `.gz`
+    #[clap(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Split the folded stacks across the files PREFIX.0 to PREFIX.N-1 of --shards N instead of
+    /// writing them out, e.g. to render or store parts of an enormous profile in parallel. Every
+    /// stack goes to the shard picked by a stable hash of the stack, so its count is never split
+    /// across shards, the same stack always lands in the same shard, and every shard is sorted
+    /// like the whole output would be
+    #[clap(
+        long = "shard-output",
+        value_name = "PREFIX",
+        requires = "shards",
+        conflicts_with_all = &[
+            "count-unique-stacks-only",
+            "diff",
+            "emit-metadata",
+            "emit-total",
+            "flamegraph",
+            "group-output",
+            "merge-into",
+            "output",
+            "report",
+            "timeline"
+        ]
+    )]
+    shard_output: Option<PathBuf>,
+
+    /// The number of files to split the folded stacks across with --shard-output
+    #[clap(long = "shards", value_name = "N", requires = "shard-output")]
+    shards: Option<NonZeroUsize>,
+
+    /// Remove frames matching RE with --fold-plt, instead of the built-in PLT and trampoline
+    /// patterns
+    #[clap(long = "plt-regex", value_name = "RE", requires = "fold-plt")]
+    plt_regex: Option<Regex>,
+
+    /// Truncate process names longer than N characters, marking them with `...`; the PID/TID
+    /// of --pid and --tid is kept
+    #[clap(long = "pname-max-len", value_name = "N")]
+    pname_max_len: Option<NonZeroUsize>,
+
+    /// Read the folded stacks from a FILE written by --write-cache, instead of collapsing the
+    /// input. Options that act on frames or events have no effect, as those are applied while
+    /// collapsing
+    #[clap(
+        long = "read-cache",
+        value_name = "FILE",
+        conflicts_with_all = &["infile", "from-folded", "timeline"]
+    )]
+    read_cache: Option<PathBuf>,
+
+    /// Write a report instead of the folded stacks; `flat` lists the inclusive and self counts
+    /// of every function, hottest first, and `hot-leaf-per-process` the most sampled leaf frame
+    /// of every process, and its share of the samples of the process
+    #[clap(
+        long = "report",
+        value_name = "KIND",
+        possible_values = &["flat", "hot-leaf-per-process"],
+        conflicts_with_all = &["diff", "emit-total", "read-cache", "timeline", "top", "write-cache"]
+    )]
+    report: Option<String>,
+
+    /// Weight samples by the given column of the event line (1-based, after the timestamp)
+    #[clap(long = "weight-column", value_name = "INDEX")]
+    weight_column: Option<NonZeroUsize>,
+
+    /// Add a frame with the given name at the root of every stack
+    #[clap(long = "root-frame", value_name = "NAME")]
+    root_frame: Option<String>,
+
+    /// Add a frame with the given name at the leaf of every stack, e.g. the host the capture came
+    /// from, to tell captures apart when merging them into one flamegraph
+    #[clap(long = "tag-all", value_name = "FRAME")]
+    tag_all: Option<String>,
+
+    /// Only process every Nth event and multiply the counts by N, to collapse large captures
+    /// faster. Counts are approximate and rare stacks may be missing; off by default
+    #[clap(long = "sample-rate", value_name = "N")]
+    sample_rate: Option<NonZeroUsize>,
+
+    /// Divide every stack's count by N, rounding down (stacks that round down to zero are
+    /// omitted), e.g. to turn nanosecond periods into microseconds with N=1000
+    #[clap(long = "scale-divisor", value_name = "N")]
+    scale_divisor: Option<NonZeroUsize>,
+
+    /// The order of the folded stacks: `alphabetical`, or `appearance` for the order in which
+    /// they first appear in the input (at the cost of some memory per stack)
+    #[clap(
+        long = "sort",
+        value_name = "ORDER",
+        default_value = "alphabetical",
+        possible_values = &["alphabetical", "appearance"],
+        conflicts_with_all = &["diff", "group-output", "merge-into", "report", "timeline", "topk"]
+    )]
+    sort: String,
+
+    /// Remove STRING from the start of process names (repeatable; the first match is removed)
+    #[clap(long = "strip-comm-prefix", value_name = "STRING")]
+    strip_comm_prefix: Vec<String>,
+
+    /// With --no-idle, drop the samples with FUNCTION on their stack instead of those with a
+    /// built-in kernel idle function (repeatable)
+    #[clap(long = "idle-frame", value_name = "FUNCTION", requires = "no-idle")]
+    idle_frame: Vec<String>,
+
+    /// Remove STRING from the start of module paths and of paths in function names, e.g. a build
+    /// directory, for output that is comparable across hosts (repeatable; the longest match is
+    /// removed)
+    #[clap(long = "strip-path-prefix", value_name = "STRING")]
+    strip_path_prefix: Vec<String>,
+
+    /// Name frames with an unknown symbol after their address, using a file of `0xADDR NAME`
+    /// lines (e.g. for stripped binaries)
+    #[clap(long = "symbol-map", value_name = "FILE")]
+    symbol_map: Option<PathBuf>,
+
+    /// Only collapse the events before SECS, as compared with perf's timestamp column (so
+    /// relative to the start of the capture only if the timestamps are)
+    #[clap(long = "time-end", value_name = "SECS")]
+    time_end: Option<f64>,
+
+    /// Only collapse the events at or after SECS, as compared with perf's timestamp column
+    #[clap(long = "time-start", value_name = "SECS")]
+    time_start: Option<f64>,
+
+    /// Title of the flame graph rendered with --flamegraph
+    #[clap(
+        long = "title",
+        requires = "flamegraph",
+        default_value = flamegraph::defaults::TITLE,
+        value_name = "STRING"
+    )]
+    title: String,
+
+    /// Print the N hottest stacks to STDERR as a table
+    #[clap(long = "top", value_name = "UINT")]
+    top: Option<usize>,
+
+    /// Only keep (approximately) the N most frequent stacks, to bound memory use. Counts may
+    /// be overestimated and less frequent stacks may be missing; off by default
+    #[clap(long = "topk", value_name = "N")]
+    topk: Option<NonZeroUsize>,
+
+    /// Write the warnings about input lines to FILE as JSON, rather than logging them: an array
+    /// of objects like {"line": 5, "category": "weird-stack", "text": "..."}, by line number.
+    /// The categories are weird-event, weird-stack, weird-folded, ignored-line (perf noise and
+    /// --ignore-prefix), too-many-frames, missing-timestamp, and weird-weight-column
+    #[clap(long = "warnings-json", value_name = "FILE")]
+    warnings_json: Option<PathBuf>,
+
+    /// Also write the folded stacks to FILE in a compact binary form, for --read-cache to
+    /// render them again without collapsing the input
+    #[clap(long = "write-cache", value_name = "FILE", conflicts_with = "timeline")]
+    write_cache: Option<PathBuf>,
+
     // ************ //
     // *** ARGS *** //
     // ************ //
     #[clap(value_name = "PATH")]
-    /// Perf script output file, or STDIN if not specified
+    /// Perf script output file, or STDIN if not specified. With the `remote` feature, this may
+    /// also be an http://, https://, or s3:// URL (of a publicly readable S3 object)
     infile: Option<PathBuf>,
 
     #[clap(long = "skip-after", value_name = "STRING")]
@@ -87,22 +599,129 @@ struct Opt {
 }
 
 impl Opt {
-    fn into_parts(self) -> (Option<PathBuf>, Options) {
+    fn into_parts(self) -> io::Result<(Option<PathBuf>, Options)> {
         let mut options = Options::default();
+        if let Some(ref path) = self.category_map {
+            let mut category_map: CategoryMap = fs::read_to_string(path)?.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            category_map.default = self.default_category.clone();
+            options.category_map = Some(category_map);
+        }
+        if let Some(ref path) = self.symbol_map {
+            let symbol_map = fs::read_to_string(path)?.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            options.symbol_map = Some(symbol_map);
+        }
+        options.comment_prefix = self.comment_prefix;
         options.include_pid = self.pid;
         options.include_tid = self.tid;
         options.include_addrs = self.addrs;
+        options.addr_prefix = self.addr_prefix;
+        options.annotate_event = self.event_annotate;
         options.annotate_jit = self.jit || self.all;
         options.annotate_kernel = self.kernel || self.all;
+        options.annotate_regex = self.annotate_regex;
+        options.canonical_event = self.canonical_event;
+        options.case_fold_frames = self.case_fold_frames;
+        options.collect_warnings = self.warnings_json.is_some();
+        options.cpus_per_node = self.cpus_per_node.map(NonZeroUsize::get);
+        options.cut_above = self.cut_above;
+        options.cut_below = self.cut_below;
+        options.cut_leafmost = self.cut_leafmost;
+        options.debug_counts = self.debug_counts;
+        options.validate_pcs = self.validate_pcs;
+        options.deopt_regex = self.deopt_regex;
+        options.vdso_regex = self.vdso_regex;
+        options.deterministic = self.deterministic;
+        options.dump_lines = self.dump_lines;
         options.event_filter = self.event_filter;
+        options.fold_closures = self.fold_closures;
+        options.fold_gcc_clones = self.fold_gcc_clones;
+        options.fold_module = self.fold_module;
+        options.fold_plt = self.fold_plt;
+        options.fold_wrappers = self.fold_wrappers;
+        options.fold_wrappers_innermost = self.fold_wrappers_innermost;
+        options.from_folded = self.from_folded;
+        options.group_by_tid_lifetime = self.group_by_tid_lifetime;
+        options.ignore_prefix = self.ignore_prefix;
+        options.keep_frame = self.keep_frame;
+        options.keep_pname_spaces = self.keep_pname_spaces;
+        options.limit_events = self.limit_events.map(NonZeroUsize::get);
+        options.mark_transitions = self.mark_transitions;
+        options.max_malformed = self.max_malformed;
+        if let Some(max_stack_frames) = self.max_stack_frames {
+            options.max_stack_frames = max_stack_frames;
+        }
+        if let Some(ratio) = self.max_unknown_ratio {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--max-unknown-ratio must be from 0.0 to 1.0, not {}", ratio),
+                ));
+            }
+            options.max_unknown_ratio = Some(ratio);
+        }
+        if let Some(pct) = self.min_width_pct {
+            if !(0.0..=100.0).contains(&pct) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--min-width-pct must be from 0 to 100, not {}", pct),
+                ));
+            }
+            options.min_width_pct = Some(pct);
+        }
+        options.min_width_other = self.min_width_other;
+        options.module_basename = self.module_basename;
+        options.no_annotate_unknown = self.no_annotate_unknown;
+        options.no_idle = self.no_idle;
+        options.idle_frames = self.idle_frame;
         options.nthreads = self.nthreads;
+        options.only_domain = if self.kernel_only {
+            Some(Domain::Kernel)
+        } else if self.user_only {
+            Some(Domain::User)
+        } else {
+            None
+        };
+        options.trim_to_domain = self.trim_to_domain;
+        options.unknown_comm = self.unknown_comm;
+        options.plt_regex = self.plt_regex;
+        options.pname_max_len = self.pname_max_len.map(NonZeroUsize::get);
+        options.process_only = self.process_only;
+        options.root_frame = self.root_frame;
+        options.tag_all = self.tag_all;
+        options.runs = self.runs;
+        options.sample_rate = self.sample_rate.map(NonZeroUsize::get);
+        options.scale_divisor = self.scale_divisor.map(NonZeroUsize::get);
         options.skip_after = self.skip_after;
-        (self.infile, options)
+        options.sort_by_appearance = self.sort == "appearance";
+        options.flat_profile = self.report.as_deref() == Some("flat");
+        options.hot_leaves = self.report.as_deref() == Some("hot-leaf-per-process");
+        options.stats = self.stats;
+        options.strip_ansi = self.strip_ansi;
+        options.strict = self.strict;
+        options.strip_comm_prefix = self.strip_comm_prefix;
+        options.strip_path_prefix = self.strip_path_prefix;
+        options.time_end = self.time_end;
+        options.time_start = self.time_start;
+        options.timeline = self.timeline;
+        options.topk = self.topk.map(NonZeroUsize::get);
+        options.weight_column = self.weight_column.map(NonZeroUsize::get);
+        options.weight_from_header = self.weight_from_header;
+        Ok((self.infile, options))
     }
 }
 
 fn main() -> io::Result<()> {
-    let opt = Opt::parse();
+    let opt = Opt::parse_from(args());
 
     // Initialize logger
     if !opt.quiet {
@@ -116,6 +735,956 @@ fn main() -> io::Result<()> {
         .init();
     }
 
-    let (infile, options) = opt.into_parts();
-    Folder::from(options).collapse_file_to_stdout(infile.as_ref())
+    if opt.check || opt.emit_schema {
+        let reader: Box<dyn BufRead> = match opt.infile {
+            Some(ref path) => match remote_url(path) {
+                Some(url) => open_remote(&url)?,
+                None => Box::new(BufReader::new(File::open(path)?)),
+            },
+            None => Box::new(BufReader::new(io::stdin())),
+        };
+        if opt.emit_schema {
+            return write_schema(reader, io::stdout().lock());
+        }
+        let fields = Fields::detect(reader, CHECK_NEVENTS)?;
+        return write_check(&fields, io::stdout().lock());
+    }
+
+    let output = OutputOptions {
+        path: opt.output.clone(),
+        top: opt.top,
+        emit_total: opt.emit_total,
+        metadata: opt.emit_metadata,
+        group: opt.group_output,
+        cardinality: opt.count_unique_stacks_only,
+        flamegraph: if opt.flamegraph {
+            Some((opt.title.clone(), opt.colors))
+        } else {
+            None
+        },
+        diff: opt.diff.clone(),
+        read_cache: opt.read_cache.clone(),
+        write_cache: opt.write_cache.clone(),
+        merge_into: opt.merge_into.clone(),
+        shards: opt
+            .shard_output
+            .clone()
+            .zip(opt.shards)
+            .map(|(prefix, shards)| (prefix, shards.get())),
+    };
+    if opt.format == "perf-trace" {
+        let mut options = perf_trace::Options::default();
+        options.include_tid = opt.tid;
+        let mut folder = perf_trace::Folder::from(options);
+        return collapse(&mut folder, opt.infile, &output);
+    }
+    let stats = opt.stats;
+    let dump_lines = opt.dump_lines.is_some();
+    let warnings_json = opt.warnings_json.clone();
+    let (infile, options) = opt.into_parts()?;
+    let hot_leaves = options.hot_leaves;
+    let report = options.flat_profile || hot_leaves;
+    let mut folder = Folder::from(options);
+    let collapsed = if report {
+        collapse_input(&mut folder, infile.as_deref(), io::sink())
+    } else {
+        collapse(&mut folder, infile, &output)
+    };
+    // the lines and warnings are written even if collapsing failed, e.g. because of
+    // --max-malformed
+    if dump_lines {
+        write_dumped_lines(folder.dumped_lines(), io::stderr().lock())?;
+    }
+    if let Some(ref path) = warnings_json {
+        write_warnings_json(folder.warnings(), BufWriter::new(File::create(path)?))?;
+    }
+    collapsed?;
+    if report {
+        let writer: Box<dyn Write> = match output.path {
+            Some(ref path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout().lock()),
+        };
+        if hot_leaves {
+            write_hot_leaves(&folder.hot_leaves(), writer)?;
+        } else {
+            write_flat_profile(folder.flat_profile(), writer)?;
+        }
+    }
+    if stats {
+        write_stats(folder.stats(), io::stderr().lock())?;
+    }
+    Ok(())
+}
+
+/// What to do with the folded stacks once they are collapsed.
+struct OutputOptions {
+    /// The file to write to (gzip-compressed if its name ends in `.gz`), or STDOUT if `None`.
+    path: Option<PathBuf>,
+    /// The number of hottest stacks to print to STDERR.
+    top: Option<usize>,
+    /// Whether to write a `# total_samples N` line first.
+    emit_total: bool,
+    /// Whether to write `# event EVENT` and `# countname UNIT` lines first.
+    metadata: bool,
+    /// Whether to write an empty line between the stacks of different processes.
+    group: bool,
+    /// Whether to write the number of unique stacks and their concentration instead of the
+    /// folded stacks.
+    cardinality: bool,
+    /// The title and palette of the flame graph to render instead of the folded stacks, if any.
+    flamegraph: Option<(String, Palette)>,
+    /// The folded file to compare the stacks against.
+    diff: Option<PathBuf>,
+    /// The cache to read the folded stacks from, instead of collapsing the input.
+    read_cache: Option<PathBuf>,
+    /// The cache to also write the folded stacks to.
+    write_cache: Option<PathBuf>,
+    /// The folded file to add the folded stacks to, instead of writing them out.
+    merge_into: Option<PathBuf>,
+    /// The prefix of the files to split the folded stacks across, and their number, instead of
+    /// writing them out.
+    shards: Option<(PathBuf, usize)>,
+}
+
+impl OutputOptions {
+    /// Whether the folded stacks must be collapsed into a buffer before they can be written.
+    fn is_buffered(&self) -> bool {
+        self.top.is_some()
+            || self.emit_total
+            || self.metadata
+            || self.group
+            || self.diff.is_some()
+            || self.read_cache.is_some()
+            || self.write_cache.is_some()
+    }
+}
+
+/// A folder that can describe the folded stacks it wrote, for --emit-metadata.
+trait Metadata {
+    /// Returns the event that was collapsed, if known, and the unit of the counts.
+    fn metadata(&self) -> (Option<&str>, &str);
+}
+
+impl Metadata for Folder {
+    fn metadata(&self) -> (Option<&str>, &str) {
+        (self.event(), self.count_name().unwrap_or("samples"))
+    }
+}
+
+impl Metadata for perf_trace::Folder {
+    fn metadata(&self) -> (Option<&str>, &str) {
+        // every system call is counted once
+        (None, "calls")
+    }
+}
+
+/// Collapses `infile` (or STDIN) and writes the result as given by `output`.
+fn collapse<C: Collapse + Metadata>(
+    folder: &mut C,
+    infile: Option<PathBuf>,
+    output: &OutputOptions,
+) -> io::Result<()> {
+    if let Some(ref path) = output.merge_into {
+        let mut folded = Vec::new();
+        write_folded(folder, infile, &mut folded, output)?;
+        return merge_into(&folded, path);
+    }
+
+    if let Some((ref prefix, shards)) = output.shards {
+        let mut folded = Vec::new();
+        write_folded(folder, infile, &mut folded, output)?;
+        return write_shards(&folded, prefix, shards);
+    }
+
+    if output.cardinality {
+        let mut folded = Vec::new();
+        write_folded(folder, infile, &mut folded, output)?;
+        return match output.path {
+            Some(ref path) => write_to_file(path, |writer| write_cardinality(&folded, writer)),
+            None => write_cardinality(&folded, io::stdout().lock()),
+        };
+    }
+
+    if let Some((ref title, colors)) = output.flamegraph {
+        let mut folded = Vec::new();
+        write_folded(folder, infile, &mut folded, output)?;
+        let mut options = flamegraph::Options::default();
+        options.title = title.clone();
+        options.colors = colors;
+        return match output.path {
+            Some(ref path) => write_to_file(path, |writer| {
+                write_flamegraph(&folded, &mut options, writer)
+            }),
+            None => write_flamegraph(&folded, &mut options, io::stdout().lock()),
+        };
+    }
+
+    let path = match output.path {
+        Some(ref path) => path,
+        None if !output.is_buffered() && infile.as_deref().and_then(remote_url).is_none() => {
+            return folder.collapse_file_to_stdout(infile.as_ref());
+        }
+        None => return write_folded(folder, infile, io::stdout().lock(), output),
+    };
+
+    write_to_file(path, |writer| write_folded(folder, infile, writer, output))
+}
+
+/// Creates the file at `path` and calls `write` with a writer to it, which gzip-compresses what
+/// is written if `path` ends in `.gz`.
+fn write_to_file<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+    if path.extension() == Some(OsStr::new("gz")) {
+        let mut encoder = gzip::Encoder::new(writer)?;
+        write(&mut encoder)?;
+        // the gzip trailer is only written when the encoder is finished
+        writer = encoder.finish().into_result()?;
+    } else {
+        write(&mut writer)?;
+    }
+    writer.flush()
+}
+
+/// Collapses `infile` (or STDIN) into `writer`, reading it from the web if it is a URL.
+fn collapse_input<C: Collapse, W: Write>(
+    folder: &mut C,
+    infile: Option<&Path>,
+    writer: W,
+) -> io::Result<()> {
+    match infile.and_then(remote_url) {
+        Some(url) => folder.collapse(open_remote(&url)?, writer),
+        None => folder.collapse_file(infile, writer),
+    }
+}
+
+/// Returns the URL to read `infile` from, if it is an `http://`, `https://`, or `s3://` URL
+/// rather than a path. `s3://BUCKET/KEY` is read from the public HTTPS endpoint of the bucket.
+fn remote_url(infile: &Path) -> Option<String> {
+    let infile = infile.to_str()?;
+    if infile.starts_with("http://") || infile.starts_with("https://") {
+        return Some(infile.to_string());
+    }
+    let (bucket, key) = infile.strip_prefix("s3://")?.split_once('/')?;
+    Some(format!("https://{}.s3.amazonaws.com/{}", bucket, key))
+}
+
+/// Streams the body of `url`, following redirects, and decompressing it if it is sent with
+/// `Content-Encoding: gzip`.
+#[cfg(feature = "remote")]
+fn open_remote(url: &str) -> io::Result<Box<dyn BufRead>> {
+    // the errors of ureq already name the URL
+    let response = ureq::get(url)
+        .call()
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(Box::new(BufReader::with_capacity(
+        128 * 1024,
+        response.into_reader(),
+    )))
+}
+
+#[cfg(not(feature = "remote"))]
+fn open_remote(url: &str) -> io::Result<Box<dyn BufRead>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{}: reading from a URL requires inferno to be built with the `remote` feature",
+            url
+        ),
+    ))
+}
+
+fn write_folded<C: Collapse + Metadata, W: Write>(
+    folder: &mut C,
+    infile: Option<PathBuf>,
+    mut writer: W,
+    output: &OutputOptions,
+) -> io::Result<()> {
+    if !output.is_buffered() {
+        return collapse_input(folder, infile.as_deref(), writer);
+    }
+
+    let folded = match output.read_cache {
+        Some(ref path) => read_cache(path)?,
+        None => {
+            let mut folded = Vec::new();
+            collapse_input(folder, infile.as_deref(), &mut folded)?;
+            folded
+        }
+    };
+    if let Some(ref path) = output.write_cache {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_cache(&folded, &mut writer)?;
+        writer.flush()?;
+    }
+    if output.metadata {
+        let (event, count_name) = folder.metadata();
+        if let Some(event) = event {
+            writeln!(writer, "# event {}", event)?;
+        }
+        writeln!(writer, "# countname {}", count_name)?;
+    }
+    if output.emit_total {
+        let total: usize = folded_stacks(&folded).map(|(_, count)| count).sum();
+        writeln!(writer, "# total_samples {}", total)?;
+    }
+    match output.diff {
+        Some(ref before) => {
+            let before = BufReader::new(File::open(before)?);
+            let options = differential::Options::default();
+            differential::from_readers(options, before, &folded[..], &mut writer)?;
+        }
+        None if output.group => write_grouped(&folded, &mut writer)?,
+        None => writer.write_all(&folded)?,
+    }
+    writer.flush()?;
+    match output.top {
+        Some(n) => write_top(&folded, n, io::stderr().lock()),
+        None => Ok(()),
+    }
+}
+
+/// Renders the stacks in `folded` as a flame graph SVG.
+fn write_flamegraph<W: Write>(
+    folded: &[u8],
+    options: &mut flamegraph::Options<'_>,
+    writer: W,
+) -> io::Result<()> {
+    let folded = String::from_utf8_lossy(folded);
+    let mut writer = BufWriter::new(writer);
+    flamegraph::from_lines(options, folded.lines(), &mut writer).map_err(|e| match e {
+        quick_xml::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    })?;
+    writer.flush()
+}
+
+/// Writes the stacks in `folded` with an empty line between those of different processes (i.e.,
+/// with different first frames). The stacks of a process are adjacent, as they are sorted.
+fn write_grouped<W: Write>(folded: &[u8], mut writer: W) -> io::Result<()> {
+    let mut process = None;
+    for (stack, count) in folded_stacks(folded) {
+        let first_frame = stack.split(';').next();
+        if process.is_some() && process != first_frame {
+            writeln!(writer)?;
+        }
+        process = first_frame;
+        writeln!(writer, "{} {}", stack, count)?;
+    }
+    Ok(())
+}
+
+/// Adds the stacks in `folded` to those in the folded file at `path`, which is created if it
+/// doesn't exist. The merged stacks are written to a temporary file next to it first, which then
+/// replaces it, so that readers never see a partially written file.
+fn merge_into(folded: &[u8], path: &Path) -> io::Result<()> {
+    let existing = match fs::read(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut stacks: BTreeMap<&str, usize> = BTreeMap::new();
+    for (stack, count) in folded_stacks(&existing).chain(folded_stacks(folded)) {
+        let total = stacks.entry(stack).or_insert(0);
+        *total = total.saturating_add(count);
+    }
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for (stack, count) in stacks {
+        writeln!(writer, "{} {}", stack, count)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&tmp_path, path)
+}
+
+/// Writes the stacks in `folded` to the files `prefix.0` to `prefix.N-1`, for `shards` N. Every
+/// stack goes to the shard picked by its FNV-1a hash, which unlike the hashers of the standard
+/// library is fixed, so that the same stack lands in the same shard across runs and versions.
+/// The stacks keep their order, so every shard is sorted like `folded` is. All the shards are
+/// created, even if some end up empty.
+fn write_shards(folded: &[u8], prefix: &Path, shards: usize) -> io::Result<()> {
+    let mut writers = Vec::with_capacity(shards);
+    for shard in 0..shards {
+        let mut path = prefix.as_os_str().to_owned();
+        path.push(format!(".{}", shard));
+        writers.push(BufWriter::new(File::create(path)?));
+    }
+    for (stack, count) in folded_stacks(folded) {
+        let writer = &mut writers[(fnv1a(stack.as_bytes()) % shards as u64) as usize];
+        writeln!(writer, "{} {}", stack, count)?;
+    }
+    for mut writer in writers {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Returns the 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Returns the command-line arguments, with the whitespace-separated arguments from the
+/// `INFERNO_COLLAPSE_PERF_OPTS` environment variable inserted before the explicit ones, so that
+/// the latter take precedence.
+fn args() -> Vec<OsString> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    if let Some(opts) = env::var_os(OPTS_ENV_VAR) {
+        let defaults = opts
+            .to_string_lossy()
+            .split_whitespace()
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let program = if args.is_empty() { 0 } else { 1 };
+        args.splice(program..program, defaults);
+    }
+    args
+}
+
+/// Returns the stacks in `folded`, along with their counts. Comment lines, like
+/// `# total_samples N`, are skipped.
+fn folded_stacks(folded: &[u8]) -> impl Iterator<Item = (&str, usize)> {
+    folded
+        .split(|&b| b == b'\n')
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (stack, count) = line.rsplit_once(' ')?;
+            Some((stack, count.parse().ok()?))
+        })
+}
+
+/// Writes the stacks in `folded` in a compact binary form: after `CACHE_MAGIC`, a table of the
+/// distinct frames, and then every stack as the indices of its frames in that table, followed by
+/// its count. Each frame is written as its length followed by its bytes, and every number
+/// (including the lengths of the table and of each stack) as a LEB128 varint.
+fn write_cache<W: Write>(folded: &[u8], mut writer: W) -> io::Result<()> {
+    let mut frames: HashMap<&str, u64> = HashMap::new();
+    let mut frame_table = Vec::new();
+    let mut stacks = Vec::new();
+    for (stack, count) in folded_stacks(folded) {
+        let indices: Vec<u64> = stack
+            .split(';')
+            .map(|frame| {
+                *frames.entry(frame).or_insert_with(|| {
+                    frame_table.push(frame);
+                    frame_table.len() as u64 - 1
+                })
+            })
+            .collect();
+        stacks.push((indices, count));
+    }
+
+    writer.write_all(CACHE_MAGIC)?;
+    write_varint(&mut writer, frame_table.len() as u64)?;
+    for frame in frame_table {
+        write_varint(&mut writer, frame.len() as u64)?;
+        writer.write_all(frame.as_bytes())?;
+    }
+    write_varint(&mut writer, stacks.len() as u64)?;
+    for (indices, count) in stacks {
+        write_varint(&mut writer, indices.len() as u64)?;
+        for index in indices {
+            write_varint(&mut writer, index)?;
+        }
+        write_varint(&mut writer, count as u64)?;
+    }
+    Ok(())
+}
+
+/// Reads the folded stacks from a cache written by `write_cache`.
+fn read_cache(path: &Path) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: not a valid cache file", path.display()),
+        )
+    };
+    let data = fs::read(path)?;
+    let mut data = data.strip_prefix(CACHE_MAGIC).ok_or_else(invalid)?;
+    let read_usize = |data: &mut &[u8]| -> io::Result<usize> {
+        let n = read_varint(data).ok_or_else(invalid)?;
+        usize::try_from(n).map_err(|_| invalid())
+    };
+
+    let nframes = read_usize(&mut data)?;
+    let mut frame_table = Vec::new();
+    for _ in 0..nframes {
+        let len = read_usize(&mut data)?;
+        if len > data.len() {
+            return Err(invalid());
+        }
+        let (frame, rest) = data.split_at(len);
+        frame_table.push(std::str::from_utf8(frame).map_err(|_| invalid())?);
+        data = rest;
+    }
+
+    let mut folded = Vec::new();
+    let nstacks = read_usize(&mut data)?;
+    for _ in 0..nstacks {
+        for i in 0..read_usize(&mut data)? {
+            if i > 0 {
+                folded.push(b';');
+            }
+            let frame = frame_table
+                .get(read_usize(&mut data)?)
+                .ok_or_else(invalid)?;
+            folded.extend_from_slice(frame.as_bytes());
+        }
+        writeln!(folded, " {}", read_usize(&mut data)?)?;
+    }
+    if !data.is_empty() {
+        return Err(invalid());
+    }
+    Ok(folded)
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut n: u64) -> io::Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Reads a varint written by `write_varint` off the start of `data`, or returns `None` if it is
+/// truncated or longer than ten bytes.
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        n |= u64::from(byte & 0x7f).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+/// Writes the `n` stacks with the highest counts in `folded` as a table, hottest first.
+fn write_top<W: Write>(folded: &[u8], n: usize, mut writer: W) -> io::Result<()> {
+    let mut stacks: Vec<(&str, usize)> = folded_stacks(folded).collect();
+    // Hottest first; ties are broken by stack so the output is stable.
+    stacks.sort_by(|(s1, c1), (s2, c2)| c2.cmp(c1).then_with(|| s1.cmp(s2)));
+    stacks.truncate(n);
+
+    let count_width = stacks
+        .iter()
+        .map(|(_, count)| count.to_string().len())
+        .chain(Some("COUNT".len()))
+        .max()
+        .unwrap_or_default();
+    let stack_width = stacks
+        .iter()
+        .map(|(stack, _)| stack.len())
+        .chain(Some("STACK".len()))
+        .max()
+        .unwrap_or_default();
+
+    writeln!(writer, "{:>cw$}  STACK", "COUNT", cw = count_width)?;
+    writeln!(
+        writer,
+        "{:-<cw$}  {:-<sw$}",
+        "",
+        "",
+        cw = count_width,
+        sw = stack_width
+    )?;
+    for (stack, count) in stacks {
+        writeln!(writer, "{:>cw$}  {}", count, stack, cw = count_width)?;
+    }
+    writer.flush()
+}
+
+/// Writes the number of unique stacks in `folded`, and the share of the samples that the top 1%
+/// of them (at least one) account for.
+fn write_cardinality<W: Write>(folded: &[u8], mut writer: W) -> io::Result<()> {
+    let mut counts: Vec<usize> = folded_stacks(folded).map(|(_, count)| count).collect();
+    counts.sort_unstable_by(|c1, c2| c2.cmp(c1));
+    let total: usize = counts.iter().sum();
+    writeln!(writer, "unique stacks: {}", counts.len())?;
+    writeln!(writer, "samples: {}", total)?;
+    if total != 0 {
+        let ntop = counts.len().saturating_sub(1) / 100 + 1;
+        let top: usize = counts[..ntop].iter().sum();
+        writeln!(
+            writer,
+            "top 1% of stacks ({}): {:.2}% of samples",
+            ntop,
+            100.0 * top as f64 / total as f64
+        )?;
+    }
+    writer.flush()
+}
+
+/// Writes the statistics gathered by --stats.
+fn write_stats<W: Write>(stats: &Stats, mut writer: W) -> io::Result<()> {
+    writeln!(writer, "events: {}", stats.nevents)?;
+    if let (Some(min), Some(median), Some(p95), Some(max)) = (
+        stats.depth_percentile(0),
+        stats.depth_percentile(50),
+        stats.depth_percentile(95),
+        stats.depth_percentile(100),
+    ) {
+        writeln!(
+            writer,
+            "stack depth: min {}, median {}, p95 {}, max {}",
+            min, median, p95, max
+        )?;
+    }
+    if let Some((length, ref stack)) = stats.longest_run {
+        writeln!(writer, "longest run: {} events of {}", length, stack)?;
+    }
+    if let Some(suspicious_pcs) = stats.suspicious_pcs {
+        writeln!(writer, "stacks with suspicious PCs: {}", suspicious_pcs)?;
+    }
+    if !stats.frame_counts.is_empty() {
+        let mut frames: Vec<(&str, usize)> = stats
+            .frame_counts
+            .iter()
+            .map(|(frame, &count)| (frame.as_str(), count))
+            .collect();
+        // Ties are broken by name, so the output is stable.
+        frames.sort_by(|(f1, c1), (f2, c2)| c2.cmp(c1).then_with(|| f1.cmp(f2)));
+        let count_width = frames
+            .iter()
+            .map(|(_, count)| count.to_string().len())
+            .chain(Some("COUNT".len()))
+            .max()
+            .unwrap_or_default();
+        writeln!(writer, "{:>cw$}  FRAME", "COUNT", cw = count_width)?;
+        for (frame, count) in frames {
+            writeln!(writer, "{:>cw$}  {}", count, frame, cw = count_width)?;
+        }
+    }
+    writer.flush()
+}
+
+/// Writes the functions in `profile` as a table, with the most inclusive first.
+fn write_flat_profile<W: Write>(profile: &FlatProfile, mut writer: W) -> io::Result<()> {
+    let mut functions: Vec<(&str, usize, usize)> = profile
+        .functions
+        .iter()
+        .map(|(func, &(inclusive, self_count))| (func.as_str(), inclusive, self_count))
+        .collect();
+    // Ties are broken by self count, and then by name, so the output is stable.
+    functions.sort_by(|(f1, i1, s1), (f2, i2, s2)| {
+        i2.cmp(i1).then_with(|| s2.cmp(s1)).then_with(|| f1.cmp(f2))
+    });
+
+    let percent = |count: usize| format!("{:.2}%", 100.0 * count as f64 / profile.total as f64);
+    let inclusive_width = functions
+        .iter()
+        .map(|(_, inclusive, _)| inclusive.to_string().len())
+        .chain(Some("INCLUSIVE".len()))
+        .max()
+        .unwrap_or_default();
+    let self_width = functions
+        .iter()
+        .map(|(_, _, self_count)| self_count.to_string().len())
+        .chain(Some("SELF".len()))
+        .max()
+        .unwrap_or_default();
+    let percent_width = "100.00%".len();
+
+    writeln!(
+        writer,
+        "{:>iw$}  {:>pw$}  {:>sw$}  {:>pw$}  FUNCTION",
+        "INCLUSIVE",
+        "INCL%",
+        "SELF",
+        "SELF%",
+        iw = inclusive_width,
+        sw = self_width,
+        pw = percent_width
+    )?;
+    for (func, inclusive, self_count) in functions {
+        writeln!(
+            writer,
+            "{:>iw$}  {:>pw$}  {:>sw$}  {:>pw$}  {}",
+            inclusive,
+            percent(inclusive),
+            self_count,
+            percent(self_count),
+            func,
+            iw = inclusive_width,
+            sw = self_width,
+            pw = percent_width
+        )?;
+    }
+    writer.flush()
+}
+
+/// Writes the most sampled leaf frame of every process, for `--report hot-leaf-per-process`.
+fn write_hot_leaves<W: Write>(hot_leaves: &[HotLeaf], mut writer: W) -> io::Result<()> {
+    let count_width = hot_leaves
+        .iter()
+        .map(|hot_leaf| hot_leaf.count.to_string().len())
+        .chain(Some("COUNT".len()))
+        .max()
+        .unwrap_or_default();
+    let process_width = hot_leaves
+        .iter()
+        .map(|hot_leaf| hot_leaf.process.chars().count())
+        .chain(Some("PROCESS".len()))
+        .max()
+        .unwrap_or_default();
+    let percent_width = "100.00%".len();
+
+    writeln!(
+        writer,
+        "{:>cw$}  {:>pw$}  {:<nw$}  LEAF",
+        "COUNT",
+        "SHARE",
+        "PROCESS",
+        cw = count_width,
+        pw = percent_width,
+        nw = process_width
+    )?;
+    for hot_leaf in hot_leaves {
+        writeln!(
+            writer,
+            "{:>cw$}  {:>pw$}  {:<nw$}  {}",
+            hot_leaf.count,
+            format!(
+                "{:.2}%",
+                100.0 * hot_leaf.count as f64 / hot_leaf.total as f64
+            ),
+            hot_leaf.process,
+            hot_leaf.leaf,
+            cw = count_width,
+            pw = percent_width,
+            nw = process_width
+        )?;
+    }
+    writer.flush()
+}
+
+/// Captures example values of the fields of the first event, for `write_schema`.
+#[derive(Default)]
+struct SchemaExamples {
+    event: Option<[Option<String>; 7]>,
+    frame: Option<(String, String)>,
+}
+
+impl EventSink for SchemaExamples {
+    fn on_event(&mut self, event: &Event<'_>) {
+        if self.event.is_none() {
+            self.event = Some([
+                Some(event.comm.to_string()),
+                event.pid.map(str::to_string),
+                Some(event.tid.to_string()),
+                event.cpu.map(|cpu| cpu.to_string()),
+                event.timestamp.map(str::to_string),
+                event.period.map(|period| period.to_string()),
+                event.name.map(str::to_string),
+            ]);
+        }
+    }
+
+    fn on_frame(&mut self, func: &str, module: &str) {
+        if self.frame.is_none() {
+            self.frame = Some((func.to_string(), module.to_string()));
+        }
+    }
+
+    fn on_event_end(&mut self) {}
+}
+
+/// Writes a JSON description of the fields of the first event in `reader`, with the same
+/// detection as --check, along with an example value of each field that is present.
+fn write_schema<R: BufRead, W: Write>(mut reader: R, mut writer: W) -> io::Result<()> {
+    // read up to the end of the first event, so that it can be examined more than once
+    let mut first_event = String::new();
+    let mut in_event = false;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() && in_event {
+            break;
+        }
+        in_event |= !trimmed.is_empty() && !trimmed.starts_with('#');
+        first_event.push_str(&line);
+    }
+
+    let fields = Fields::detect(first_event.as_bytes(), 1)?;
+    let mut examples = SchemaExamples::default();
+    read_events(first_event.as_bytes(), &mut examples)?;
+    let [comm, pid, tid, cpu, time, period, event] = examples.event.unwrap_or_default();
+    let (sym, dso) = match examples.frame {
+        Some((sym, dso)) => (Some(sym), Some(dso)),
+        None => (None, None),
+    };
+    // the address of the first frame, which is either right before its symbol on the event line
+    // (if the stack has only one frame), or on the first stack line
+    let mut lines = first_event.lines().filter(|line| {
+        let line = line.trim();
+        !line.is_empty() && !line.starts_with('#')
+    });
+    let event_line = lines.next().unwrap_or_default();
+    let ip = sym
+        .as_deref()
+        .and_then(|sym| event_line.rfind(&format!(" {} (", sym)))
+        .and_then(|frame_start| event_line[..frame_start].split_whitespace().last())
+        .filter(|pc| pc.bytes().all(|b| b.is_ascii_hexdigit()))
+        .or_else(|| Some(parse_stack_line(lines.next()?)?.0))
+        .map(str::to_string);
+
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"nevents\": {},", fields.nevents)?;
+    writeln!(writer, "  \"fields\": {{")?;
+    let schema = [
+        ("comm", fields.comm, comm),
+        ("pid", fields.pid, pid),
+        ("tid", fields.tid, tid),
+        ("cpu", fields.cpu, cpu),
+        ("time", fields.time, time),
+        ("period", fields.period, period),
+        ("event", fields.event, event),
+        ("ip", fields.ip, ip),
+        ("sym", fields.sym, sym),
+        ("dso", fields.dso, dso),
+    ];
+    let nfields = schema.len();
+    for (i, (field, present, example)) in schema.into_iter().enumerate() {
+        let example = match example {
+            Some(ref example) if present => json_string(example),
+            _ => "null".to_string(),
+        };
+        writeln!(
+            writer,
+            "    \"{}\": {{ \"present\": {}, \"example\": {} }}{}",
+            field,
+            present,
+            example,
+            if i + 1 < nfields { "," } else { "" }
+        )?;
+    }
+    writeln!(writer, "  }}")?;
+    writeln!(writer, "}}")?;
+    writer.flush()
+}
+
+/// Writes the `lines` recorded for --dump-lines, one per line, with their number and kind.
+fn write_dumped_lines<W: Write>(lines: &[DumpedLine], mut writer: W) -> io::Result<()> {
+    for line in lines {
+        let dumped = format!(
+            "{:>6} {:<7} {}",
+            line.line_number,
+            line.kind.name(),
+            line.text
+        );
+        // blank lines would otherwise leave the padding of their kind behind
+        writeln!(writer, "{}", dumped.trim_end())?;
+    }
+    writer.flush()
+}
+
+/// Writes the `warnings` collected for --warnings-json as a JSON array.
+fn write_warnings_json<W: Write>(warnings: &[Warning], mut writer: W) -> io::Result<()> {
+    if warnings.is_empty() {
+        writeln!(writer, "[]")?;
+        return writer.flush();
+    }
+    writeln!(writer, "[")?;
+    for (i, warning) in warnings.iter().enumerate() {
+        writeln!(
+            writer,
+            "  {{ \"line\": {}, \"category\": \"{}\", \"text\": {} }}{}",
+            warning.line_number,
+            warning.kind.name(),
+            json_string(&warning.text),
+            if i + 1 < warnings.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(writer, "]")?;
+    writer.flush()
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes a report of the detected `fields`, and of which options they support.
+fn write_check<W: Write>(fields: &Fields, mut writer: W) -> io::Result<()> {
+    if fields.nevents == 0 {
+        writeln!(
+            writer,
+            "No events found; is the input the output of `perf script`?"
+        )?;
+        return writer.flush();
+    }
+
+    let yes_no = |present| if present { "yes" } else { "no" };
+    writeln!(writer, "Examined {} event(s).", fields.nevents)?;
+    writeln!(writer)?;
+    writeln!(writer, "{:<6}  PRESENT", "FIELD")?;
+    for (field, present) in [
+        ("comm", fields.comm),
+        ("pid", fields.pid),
+        ("tid", fields.tid),
+        ("cpu", fields.cpu),
+        ("time", fields.time),
+        ("period", fields.period),
+        ("event", fields.event),
+        ("ip", fields.ip),
+        ("sym", fields.sym),
+        ("dso", fields.dso),
+    ] {
+        writeln!(writer, "{:<6}  {}", field, yes_no(present))?;
+    }
+
+    writeln!(writer)?;
+    writeln!(writer, "{:<22}  SUPPORTED", "OPTION")?;
+    for (option, supported, needs) in [
+        ("--pid", fields.pid, "pid,tid"),
+        ("--tid", fields.pid && fields.tid, "pid,tid"),
+        ("--event-filter", fields.event, "event"),
+        (
+            "--weight-column",
+            fields.time && fields.period,
+            "time,period",
+        ),
+        ("--kernel, --jit, --all", fields.dso, "dso"),
+        ("--addrs", fields.ip, "ip"),
+    ] {
+        if supported {
+            writeln!(writer, "{:<22}  yes", option)?;
+        } else {
+            writeln!(writer, "{:<22}  no (needs -F {})", option, needs)?;
+        }
+    }
+    writer.flush()
 }
@@ -91,7 +91,8 @@ struct Opt {
     #[clap(long = "colordiffusion", conflicts_with = "colors")]
     color_diffusion: bool,
 
-    /// Count type label
+    /// Count type label. By default, that of a `# countname STRING` line in the input (see
+    /// inferno-collapse-perf --emit-metadata), if any
     #[clap(
         long = "countname",
         default_value = defaults::COUNT_NAME,
@@ -0,0 +1,127 @@
+/// A well-known annotation that collapsers append to frame names, and that the flame graph
+/// palettes use to pick colors.
+///
+/// Annotations are suffixes of the form `_[x]`, such as the `_[k]` in `tcp_sendmsg_[k]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Annotation {
+    /// A deoptimized or interpreted function of a just-in-time compiler (`_[d]`).
+    Deopt,
+    /// An inlined function (`_[i]`).
+    Inline,
+    /// A just-in-time compiled function (`_[j]`).
+    Jit,
+    /// A kernel function (`_[k]`).
+    Kernel,
+    /// A frame that calls into another domain, e.g. a user function making a system call
+    /// (`_[t]`).
+    Transition,
+    /// A function of the vDSO, the shared object that the kernel maps into every process to
+    /// speed up some system calls (`_[vdso]`).
+    Vdso,
+    /// A waker stack in an off-wake time flame graph (`_[w]`).
+    Waker,
+}
+
+impl Annotation {
+    /// All the well-known annotations.
+    pub const ALL: [Annotation; 7] = [
+        Annotation::Deopt,
+        Annotation::Inline,
+        Annotation::Jit,
+        Annotation::Kernel,
+        Annotation::Transition,
+        Annotation::Vdso,
+        Annotation::Waker,
+    ];
+
+    /// Returns the suffix that marks a frame with this annotation, e.g. `_[k]`.
+    pub const fn suffix(self) -> &'static str {
+        match self {
+            Annotation::Deopt => "_[d]",
+            Annotation::Inline => "_[i]",
+            Annotation::Jit => "_[j]",
+            Annotation::Kernel => "_[k]",
+            Annotation::Transition => "_[t]",
+            Annotation::Vdso => "_[vdso]",
+            Annotation::Waker => "_[w]",
+        }
+    }
+
+    /// Returns the annotation marked by the given suffix (e.g. `_[k]`), if it is well-known.
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|a| a.suffix() == suffix)
+    }
+}
+
+/// Splits the well-known annotations off the end of a frame name.
+///
+/// Returns the frame name without any trailing annotations, along with those annotations in the
+/// order they appeared in. Stripping stops at the first suffix that isn't a well-known
+/// annotation, so `foo_[x]_[k]` becomes `foo_[x]` and `[Annotation::Kernel]`.
+pub fn strip_annotations(frame: &str) -> (&str, Vec<Annotation>) {
+    let mut name = frame;
+    let mut annotations = Vec::new();
+    while let Some(annotation) = trailing_annotation(name) {
+        name = &name[..name.len() - annotation.suffix().len()];
+        annotations.push(annotation);
+    }
+    annotations.reverse();
+    (name, annotations)
+}
+
+/// Returns the well-known annotation at the end of `frame`, if any.
+pub(crate) fn trailing_annotation(frame: &str) -> Option<Annotation> {
+    Annotation::ALL
+        .iter()
+        .copied()
+        .find(|a| frame.ends_with(a.suffix()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffix_round_trips() {
+        for annotation in Annotation::ALL.iter() {
+            assert_eq!(
+                Annotation::from_suffix(annotation.suffix()),
+                Some(*annotation)
+            );
+        }
+        assert_eq!(Annotation::from_suffix("_[x]"), None);
+        assert_eq!(Annotation::from_suffix("_[kk]"), None);
+    }
+
+    #[test]
+    fn strip_annotations_works() {
+        assert_eq!(strip_annotations("foo"), ("foo", vec![]));
+        assert_eq!(
+            strip_annotations("tcp_sendmsg_[k]"),
+            ("tcp_sendmsg", vec![Annotation::Kernel])
+        );
+        assert_eq!(
+            strip_annotations("foo_[i]_[w]"),
+            ("foo", vec![Annotation::Inline, Annotation::Waker])
+        );
+        assert_eq!(
+            strip_annotations("foo_[x]_[j]"),
+            ("foo_[x]", vec![Annotation::Jit])
+        );
+        assert_eq!(strip_annotations("_[k]"), ("", vec![Annotation::Kernel]));
+        assert_eq!(
+            strip_annotations("日本_[k]"),
+            ("日本", vec![Annotation::Kernel])
+        );
+        assert_eq!(strip_annotations("ab日"), ("ab日", vec![]));
+        assert_eq!(
+            strip_annotations("__vdso_gettimeofday_[vdso]"),
+            ("__vdso_gettimeofday", vec![Annotation::Vdso])
+        );
+        assert_eq!(
+            strip_annotations("foo_[vdso]_[t]"),
+            ("foo", vec![Annotation::Vdso, Annotation::Transition])
+        );
+    }
+}
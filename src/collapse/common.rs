@@ -1,6 +1,7 @@
 use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::BTreeSet;
 use std::io;
-#[cfg(feature = "multithreaded")]
 use std::mem;
 #[cfg(feature = "multithreaded")]
 use std::sync::Arc;
@@ -152,7 +153,7 @@ pub(crate) trait CollapsePrivate: Send + Sized {
         R: io::BufRead,
         W: io::Write,
     {
-        let mut occurrences = Occurrences::new(self.nthreads());
+        let mut occurrences = self.new_occurrences();
 
         // Consume the header, if any, and do any other pre-processing
         // that needs to occur.
@@ -165,10 +166,38 @@ pub(crate) trait CollapsePrivate: Send + Sized {
             self.collapse_single_threaded(reader, &mut occurrences)?;
         }
 
+        // Adjust the aggregated counts, if needed.
+        self.post_process(&mut occurrences)?;
+
         // Write results.
         occurrences.write_and_clear(writer)
     }
 
+    /// Creates the map that samples are aggregated in.
+    ///
+    /// By default, this keeps exact counts, in a map that can be shared by `nthreads` threads.
+    /// If the returned map is not concurrent, all samples are processed on the main thread.
+    fn new_occurrences(&self) -> Occurrences {
+        Occurrences::new(self.nthreads())
+    }
+
+    /// Prepare to process a chunk of input on a worker thread.
+    ///
+    /// This is called on a worker thread's collapser right before it is given a chunk of input,
+    /// with the number of lines that precede the chunk (not counting those consumed by
+    /// `pre_process`), so that the collapser can refer to input lines by their number (e.g. in
+    /// warnings). By default, it does nothing.
+    fn start_chunk(&mut self, _nlines_before: usize) {}
+
+    /// Adjust the aggregated counts once all samples have been processed.
+    ///
+    /// This is called on the main thread right before the results are written, and is a good
+    /// place for transformations that need the final counts (e.g. scaling them to a different
+    /// unit). By default, it does nothing.
+    fn post_process(&mut self, _occurrences: &mut Occurrences) -> io::Result<()> {
+        Ok(())
+    }
+
     #[cfg(not(feature = "multithreaded"))]
     fn collapse_multi_threaded<R>(&mut self, _: R, _: &mut Occurrences) -> io::Result<()>
     where
@@ -201,7 +230,9 @@ pub(crate) trait CollapsePrivate: Send + Sized {
             // Channel for sending input data from the main thread to the worker threads.
             // We choose `2 * nthreads` as the channel size here in order to limit memory
             // usage in the case of particularly large input files.
-            let (tx_input, rx_input) = crossbeam_channel::bounded::<Vec<u8>>(2 * nthreads);
+            // Each chunk is sent along with the number of lines that precede it.
+            let (tx_input, rx_input) =
+                crossbeam_channel::bounded::<(usize, Vec<u8>)>(2 * nthreads);
 
             // Channel for worker threads that have errored to signal to all the other
             // worker threads that they should stop work immediately and return.
@@ -221,14 +252,15 @@ pub(crate) trait CollapsePrivate: Send + Sized {
                     crossbeam_channel::select! {
                         recv(rx_input) -> input => {
                             // Receive input from the main thread.
-                            let data = match input {
-                                Ok(data) => data,
+                            let (nlines_before, data) = match input {
+                                Ok(input) => input,
                                 // The main threads drops it's handle to the input sender once it's
                                 // finished sending data; so if we get an error here, it means
                                 // there is no more data to be sent and we should exit.
                                 Err(_) => return,
                             };
                             // If there is input data, process it.
+                            folder.start_chunk(nlines_before);
                             if let Err(e) = folder.collapse_single_threaded(&data[..], &mut occurrences) {
                                 // In the event of an error...
                                 //
@@ -282,6 +314,7 @@ pub(crate) trait CollapsePrivate: Send + Sized {
             let buf_capacity = usize::next_power_of_two(NBYTES_PER_STACK_GUESS * nstacks_per_job);
             let mut buf = Vec::with_capacity(buf_capacity);
             let (mut index, mut nstacks) = (0, 0);
+            let (mut nlines, mut nlines_before_chunk) = (0, 0);
 
             loop {
                 let n = reader.read_until(b'\n', &mut buf)?;
@@ -291,11 +324,12 @@ pub(crate) trait CollapsePrivate: Send + Sized {
                     // be alive (depending on if one errored in between the sending of the last
                     // chunk and the sending of this one), but either way we should break the loop;
                     // so there's no need to check for a `SendError` here.
-                    let _ = tx_input.send(buf);
+                    let _ = tx_input.send((nlines_before_chunk, buf));
                     break;
                 }
                 let line = &buf[index..index + n];
                 index += n;
+                nlines += 1;
                 if self.would_end_stack(line) {
                     // If we've reached the end of a stack, count it.
                     nstacks += 1;
@@ -304,7 +338,7 @@ pub(crate) trait CollapsePrivate: Send + Sized {
                         // worker threads, try to send it.
                         let buf_capacity = usize::next_power_of_two(buf.capacity());
                         let chunk = mem::replace(&mut buf, Vec::with_capacity(buf_capacity));
-                        if tx_input.send(chunk).is_err() {
+                        if tx_input.send((nlines_before_chunk, chunk)).is_err() {
                             // If sending the chunk produces a `SendError`, this means that one
                             // of the worker threads has errored, sent a signal to all the other
                             // worker threads to shut down, and they have all shutdown, in which
@@ -314,6 +348,7 @@ pub(crate) trait CollapsePrivate: Send + Sized {
                         }
                         index = 0;
                         nstacks = 0;
+                        nlines_before_chunk = nlines;
                     }
                     continue
                 }
@@ -350,10 +385,15 @@ pub(crate) trait CollapsePrivate: Send + Sized {
 
 /// Occurrences is a HashMap, which uses:
 /// * AHashMap if single-threaded
+/// * AHashMap with the position of every key if keeping keys in order of appearance
 /// * DashMap if multi-threaded
 #[derive(Clone, Debug)]
 pub(crate) enum Occurrences {
     SingleThreaded(AHashMap<String, usize>),
+    /// Counts by key, along with the number of keys that were added before it.
+    Appearance(AHashMap<String, (usize, usize)>),
+    Bounded(SpaceSaving),
+    Timeline(Vec<String>),
     #[cfg(feature = "multithreaded")]
     MultiThreaded(Arc<DashMap<String, usize, ahash::RandomState>>),
 }
@@ -388,6 +428,26 @@ impl Occurrences {
         Occurrences::MultiThreaded(Arc::new(map))
     }
 
+    /// Creates a map that writes its keys in the order they were first added, rather than
+    /// sorted. This takes an extra word of memory for every key, to remember its position.
+    pub(crate) fn new_by_appearance() -> Self {
+        let map =
+            AHashMap::with_capacity_and_hasher(CAPACITY_HASHMAP, ahash::RandomState::default());
+        Occurrences::Appearance(map)
+    }
+
+    /// Creates a map that only keeps (approximate) counts for the `capacity` most frequent keys.
+    /// See [`SpaceSaving`].
+    pub(crate) fn new_bounded(capacity: usize) -> Self {
+        Occurrences::Bounded(SpaceSaving::new(capacity))
+    }
+
+    /// Creates a list that keeps every key separately, in the order they are added, and ignores
+    /// their counts. This is for output that isn't aggregated, like a timeline of samples.
+    pub(crate) fn new_timeline() -> Self {
+        Occurrences::Timeline(Vec::new())
+    }
+
     /// Inserts a key-count pair into the map. If the map did not have this key
     /// present, `None` is returned. If the map did have this key present, the
     /// value is updated, and the old value is returned.
@@ -395,6 +455,21 @@ impl Occurrences {
         use self::Occurrences::*;
         match self {
             SingleThreaded(map) => map.insert(key, count),
+            Appearance(map) => {
+                let position = map.len();
+                match map.entry(key) {
+                    Entry::Occupied(mut entry) => Some(mem::replace(&mut entry.get_mut().1, count)),
+                    Entry::Vacant(entry) => {
+                        entry.insert((position, count));
+                        None
+                    }
+                }
+            }
+            Bounded(map) => map.insert(key, count),
+            Timeline(keys) => {
+                keys.push(key);
+                None
+            }
             #[cfg(feature = "multithreaded")]
             MultiThreaded(arc) => arc.insert(key, count),
         }
@@ -407,15 +482,81 @@ impl Occurrences {
         use self::Occurrences::*;
         match self {
             SingleThreaded(map) => *map.entry(key).or_insert(0) += count,
+            Appearance(map) => {
+                let position = map.len();
+                map.entry(key).or_insert((position, 0)).1 += count;
+            }
+            Bounded(map) => {
+                map.add(key, count);
+            }
+            Timeline(keys) => keys.push(key),
             #[cfg(feature = "multithreaded")]
             MultiThreaded(arc) => *arc.entry(key).or_insert(0) += count,
         }
     }
 
+    /// Multiplies every count in the map by `multiplier` and then divides it by `divisor`,
+    /// rounding down. Keys whose count becomes zero are removed.
+    pub(crate) fn scale_counts(&mut self, multiplier: usize, divisor: usize) {
+        use self::Occurrences::*;
+        assert_ne!(divisor, 0);
+        let scale = |_: &String, count: &mut usize| {
+            *count = count.saturating_mul(multiplier) / divisor;
+            *count != 0
+        };
+        match self {
+            SingleThreaded(map) => map.retain(scale),
+            Appearance(map) => map.retain(|key, (_, count)| scale(key, count)),
+            Bounded(map) => map.retain(scale),
+            Timeline(_) => {}
+            #[cfg(feature = "multithreaded")]
+            MultiThreaded(arc) => arc.retain(scale),
+        }
+    }
+
+    /// Returns the sum of all counts in the map. Every key of a timeline counts once.
+    pub(crate) fn total(&self) -> usize {
+        use self::Occurrences::*;
+        match self {
+            SingleThreaded(map) => map.values().sum(),
+            Appearance(map) => map.values().map(|(_, count)| count).sum(),
+            Bounded(map) => map.counts.values().sum(),
+            Timeline(keys) => keys.len(),
+            #[cfg(feature = "multithreaded")]
+            MultiThreaded(arc) => arc.iter().map(|entry| *entry.value()).sum(),
+        }
+    }
+
+    /// Removes the keys whose count `keep` returns `false` for, and returns the sum of their
+    /// counts. Timelines are left as they are.
+    pub(crate) fn retain_counts<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(usize) -> bool,
+    {
+        use self::Occurrences::*;
+        let mut removed = 0;
+        let mut retain = |_: &String, count: &mut usize| {
+            let kept = keep(*count);
+            if !kept {
+                removed += *count;
+            }
+            kept
+        };
+        match self {
+            SingleThreaded(map) => map.retain(retain),
+            Appearance(map) => map.retain(|key, (_, count)| retain(key, count)),
+            Bounded(map) => map.retain(retain),
+            Timeline(_) => {}
+            #[cfg(feature = "multithreaded")]
+            MultiThreaded(arc) => arc.retain(retain),
+        }
+        removed
+    }
+
     pub(crate) fn is_concurrent(&self) -> bool {
         use self::Occurrences::*;
         match self {
-            SingleThreaded(_) => false,
+            SingleThreaded(_) | Appearance(_) | Bounded(_) | Timeline(_) => false,
             #[cfg(feature = "multithreaded")]
             MultiThreaded(_) => true,
         }
@@ -434,6 +575,28 @@ impl Occurrences {
                     writeln!(writer, "{} {}", key, value)?;
                 }
             }
+            Appearance(ref mut map) => {
+                // keys may have been removed after they were added, so positions can repeat
+                let mut contents: Vec<_> = map.drain().collect();
+                contents.sort_unstable_by(|(k1, (p1, _)), (k2, (p2, _))| {
+                    p1.cmp(p2).then_with(|| k1.cmp(k2))
+                });
+                for (key, (_, value)) in contents {
+                    writeln!(writer, "{} {}", key, value)?;
+                }
+            }
+            Bounded(ref mut map) => {
+                let mut contents: Vec<_> = map.drain().collect();
+                contents.sort();
+                for (key, value) in contents {
+                    writeln!(writer, "{} {}", key, value)?;
+                }
+            }
+            Timeline(ref mut keys) => {
+                for key in keys.drain(..) {
+                    writeln!(writer, "{}", key)?;
+                }
+            }
             #[cfg(feature = "multithreaded")]
             MultiThreaded(ref mut arc) => {
                 let map = match Arc::get_mut(arc) {
@@ -464,6 +627,86 @@ impl Occurrences {
     }
 }
 
+/// A map that keeps approximate counts for at most `capacity` keys, using the Space-Saving
+/// algorithm (Metwally et al., 2005).
+///
+/// When a new key arrives while the map is full, the key with the smallest count is evicted and
+/// the new key inherits its count. Counts may therefore be overestimated, by at most the smallest
+/// count in the map, but a key that makes up more than `1 / capacity` of the total is never
+/// evicted.
+#[derive(Clone, Debug)]
+pub(crate) struct SpaceSaving {
+    capacity: usize,
+    counts: AHashMap<String, usize>,
+    /// The same entries as `counts`, ordered by count, to find the next key to evict.
+    by_count: BTreeSet<(usize, String)>,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        assert_ne!(capacity, 0);
+        SpaceSaving {
+            capacity,
+            counts: AHashMap::with_capacity_and_hasher(
+                capacity.min(CAPACITY_HASHMAP),
+                ahash::RandomState::default(),
+            ),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    /// Adds `count` to the count of `key`, evicting the key with the smallest count if `key` is
+    /// new and the map is full.
+    fn add(&mut self, key: String, mut count: usize) {
+        if let Some(old) = self.counts.get_mut(&key) {
+            let mut entry = (*old, key);
+            self.by_count.remove(&entry);
+            *old += count;
+            entry.0 = *old;
+            self.by_count.insert(entry);
+            return;
+        }
+
+        if self.counts.len() == self.capacity {
+            let smallest = self.by_count.iter().next().cloned();
+            if let Some(smallest) = smallest {
+                self.by_count.remove(&smallest);
+                self.counts.remove(&smallest.1);
+                count += smallest.0;
+            }
+        }
+        self.counts.insert(key.clone(), count);
+        self.by_count.insert((count, key));
+    }
+
+    /// Sets the count of `key`, returning its previous count, if any.
+    fn insert(&mut self, key: String, count: usize) -> Option<usize> {
+        let old = self.counts.remove(&key);
+        if let Some(old) = old {
+            self.by_count.remove(&(old, key.clone()));
+        }
+        self.add(key, count);
+        old
+    }
+
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&String, &mut usize) -> bool,
+    {
+        self.counts.retain(f);
+        self.by_count = self
+            .counts
+            .iter()
+            .map(|(key, count)| (*count, key.clone()))
+            .collect();
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = (String, usize)> + '_ {
+        self.by_count.clear();
+        self.counts.drain()
+    }
+}
+
 /// Demangles partially demangled Rust symbols that were demangled incorrectly by profilers like
 /// `sample` and `DTrace`.
 ///
@@ -816,6 +1059,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn space_saving() {
+        let mut map = super::SpaceSaving::new(2);
+        for (key, count) in [("a", 5), ("b", 1), ("a", 2), ("c", 1), ("b", 3)] {
+            map.add(key.to_string(), count);
+        }
+        // `c` evicted `b` (1 + 1), and was then evicted by `b` (2 + 3)
+        let mut contents: Vec<_> = map.drain().collect();
+        contents.sort();
+        assert_eq!(contents, vec![("a".to_string(), 7), ("b".to_string(), 5)]);
+        assert!(map.by_count.is_empty());
+
+        // keys making up more than a quarter of the total are kept with 4 slots
+        let mut map = super::SpaceSaving::new(4);
+        for i in 0..100 {
+            map.add(
+                if i % 3 == 0 {
+                    "x".to_string()
+                } else {
+                    "hot".to_string()
+                },
+                1,
+            );
+            map.add(i.to_string(), 1);
+        }
+        assert!(map.counts.contains_key("hot"));
+        assert!(map.counts.len() <= 4);
+        assert_eq!(map.by_count.len(), map.counts.len());
+    }
+
     #[test]
     fn fix_partially_demangled_rust_symbols() {
         t!(
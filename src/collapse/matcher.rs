@@ -23,6 +23,44 @@ pub(super) fn is_kernel(s: &str) -> bool {
     (s.starts_with('[') || s.ends_with(".ko") || is_vmlinux(s)) && s != "[unknown]"
 }
 
+// Matches `s` against a glob `pattern`, in which `*` matches any (possibly empty) sequence of
+// characters, `?` matches any single (ASCII) character, and every other character matches
+// itself.
+//
+// Examples:
+//
+// sched:* matches sched:sched_switch
+// cycles* matches cycles:uppp
+pub(super) fn is_glob_match(pattern: &str, s: &str) -> bool {
+    let (pattern, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut p, mut i) = (0, 0);
+    // the position of the latest `*` in the pattern, and of the byte of `s` that it matches up
+    // to (but excluding) thus far
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&b) if b == b'?' || b == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                // let the `*` match one more byte, and try again from there
+                Some((star_p, star_i)) => {
+                    star = Some((star_p, star_i + 1));
+                    p = star_p + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -80,6 +118,22 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn is_glob_match_works() {
+        assert!(is_glob_match("cycles", "cycles"));
+        assert!(is_glob_match("sched:*", "sched:sched_switch"));
+        assert!(is_glob_match("sched:*", "sched:"));
+        assert!(is_glob_match("*", ""));
+        assert!(is_glob_match("*:sched_*", "sched:sched_wakeup"));
+        assert!(is_glob_match("cycle?", "cycles"));
+        assert!(is_glob_match("*a*b", "xaybab"));
+        assert!(!is_glob_match("cycles", "cycles:u"));
+        assert!(!is_glob_match("sched:*", "sched"));
+        assert!(!is_glob_match("cycle?", "cycle"));
+        assert!(!is_glob_match("*a*b", "xaybac"));
+        assert!(!is_glob_match("", "cycles"));
+    }
+
     #[test]
     fn is_kernel_false() {
         assert!(!is_kernel("[unknown]"));
@@ -1,6 +1,9 @@
 #[macro_use]
 pub(crate) mod common;
 
+/// Well-known annotations on frame names, such as `_[k]` for kernel functions.
+pub(crate) mod annotation;
+
 /// Stack collapsing for the output of [`dtrace`](https://www.joyent.com/dtrace).
 ///
 /// See the [crate-level documentation] for details.
@@ -18,6 +21,13 @@ pub mod guess;
 ///   [crate-level documentation]: ../../index.html
 pub mod perf;
 
+/// Stack collapsing for the output of [`perf trace`](https://man7.org/linux/man-pages/man1/perf-trace.1.html).
+///
+/// See the [crate-level documentation] for details.
+///
+///   [crate-level documentation]: ../../index.html
+pub mod perf_trace;
+
 /// Internal string match helper functions for perf
 pub(crate) mod matcher;
 
@@ -47,6 +57,8 @@ pub mod vsprof;
 #[doc(hidden)]
 pub use self::common::DEFAULT_NTHREADS;
 
+pub use self::annotation::{strip_annotations, Annotation};
+
 use std::fs::File;
 use std::io;
 use std::path::Path;
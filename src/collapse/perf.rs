@@ -1,25 +1,260 @@
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::io::{self, BufRead};
+use std::iter;
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use regex::Regex;
 
 use crate::collapse::common::{self, CollapsePrivate, Occurrences};
-use crate::collapse::matcher::is_kernel;
+use crate::collapse::matcher::{is_glob_match, is_kernel};
+use crate::collapse::{strip_annotations, Annotation};
 
 const TIDY_GENERIC: bool = true;
 const TIDY_JAVA: bool = true;
 
+/// The default for `Options::max_stack_frames`.
+const DEFAULT_MAX_STACK_FRAMES: usize = 100_000;
+
+/// The default for `Options::plt_regex`, which matches PLT stubs like `malloc@plt`, and the
+/// trampolines of the dynamic linker.
+const DEFAULT_PLT_REGEX: &str = r"@plt$|^_dl_runtime_resolve|^__tls_get_addr$|^_init$";
+
+/// The default for `Options::idle_frames`, which are the functions that the kernel idles in.
+const DEFAULT_IDLE_FRAMES: [&str; 7] = [
+    "cpu_idle",
+    "cpu_idle_poll",
+    "default_idle",
+    "do_idle",
+    "intel_idle",
+    "native_safe_halt",
+    "poll_idle",
+];
+
+/// The suffixes of the clones that GCC makes of functions; see `Options::fold_gcc_clones`.
+const GCC_CLONE_SUFFIXES: [&str; 4] = [".constprop", ".isra", ".part", ".cold"];
+
+/// The stack that counts the stacks dropped by `Options::min_width_pct`, with
+/// `Options::min_width_other`.
+const OTHER_STACK: &str = "[other]";
+
+/// Prefixes of lines that `perf` writes to STDERR, which end up in the input if it was captured
+/// with `perf script 2>&1`.
+const PERF_NOISE_PREFIXES: &[&str] = &[
+    "Warning:",
+    "WARNING:",
+    "Error:",
+    "Failed to open ",
+    "no symbols found in ",
+];
+
+/// Suffixes of lines that `perf` writes to STDERR; see `PERF_NOISE_PREFIXES`.
+const PERF_NOISE_SUFFIXES: &[&str] = &["not found, continuing without symbols"];
+
 mod logging {
-    use log::{info, warn};
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use log::{debug, info, warn};
+
+    use super::{Warning, WarningKind};
+
+    /// Where warnings about input lines go: to the log right away, or to a buffer that is shared
+    /// by all threads, and logged in the order of the lines once they are all done (see
+    /// `Options::deterministic`), or returned in that order instead (see
+    /// `Options::collect_warnings`).
+    #[derive(Clone, Debug, Default)]
+    pub(super) struct Warnings {
+        deferred: Option<Arc<Mutex<Vec<DeferredWarning>>>>,
+        collect: bool,
+    }
+
+    #[derive(Debug)]
+    struct DeferredWarning {
+        warning: Warning,
+        /// Set for warnings that are only logged for the first line they apply to.
+        once: Option<&'static str>,
+        /// `None` for lines that are only logged at the debug level, and only ever collected.
+        message: Option<String>,
+    }
+
+    impl Warnings {
+        /// Returns warnings that are held back until `flush` is called.
+        pub(super) fn deferred() -> Self {
+            Warnings {
+                deferred: Some(Arc::default()),
+                collect: false,
+            }
+        }
+
+        /// Returns warnings that are returned by `flush`, rather than logged.
+        pub(super) fn collected() -> Self {
+            Warnings {
+                deferred: Some(Arc::default()),
+                collect: true,
+            }
+        }
+
+        fn warn(
+            &self,
+            kind: WarningKind,
+            line_number: usize,
+            line: &str,
+            once: Option<&'static str>,
+            message: fmt::Arguments,
+        ) {
+            match self.deferred {
+                Some(ref deferred) => deferred.lock().unwrap().push(DeferredWarning {
+                    warning: Warning {
+                        line_number,
+                        kind,
+                        text: line.to_string(),
+                    },
+                    once,
+                    message: Some(message.to_string()),
+                }),
+                None => warn!("line {}: {}", line_number, message),
+            }
+        }
+
+        /// Logs the deferred warnings (if any) by line number, as if they were logged by a
+        /// single thread, or returns them in that order if they are collected.
+        pub(super) fn flush(&self) -> Vec<Warning> {
+            let mut warnings = match self.deferred {
+                Some(ref deferred) => std::mem::take(&mut *deferred.lock().unwrap()),
+                None => return Vec::new(),
+            };
+            // the sort is stable, so warnings about the same line stay in order
+            warnings.sort_by_key(|deferred| deferred.warning.line_number);
+            let mut logged_once = Vec::new();
+            let mut collected = Vec::new();
+            for deferred in warnings {
+                if let Some(once) = deferred.once {
+                    // every thread logged this once, but a single thread would have logged it
+                    // only for the first line
+                    if logged_once.contains(&once) {
+                        continue;
+                    }
+                    logged_once.push(once);
+                }
+                if self.collect {
+                    collected.push(deferred.warning);
+                } else if let Some(message) = deferred.message {
+                    warn!("line {}: {}", deferred.warning.line_number, message);
+                }
+            }
+            collected
+        }
+    }
 
     pub(super) fn filtering_for_events_of_type(ty: &str) {
         info!("Filtering for events of type: {}", ty);
     }
 
-    pub(super) fn weird_event_line(line: &str) {
-        warn!("Weird event line: {}", line);
+    pub(super) fn ignoring_line(warnings: &Warnings, line_number: usize, line: &str) {
+        debug!("Ignoring line: {}", line);
+        if warnings.collect {
+            if let Some(ref deferred) = warnings.deferred {
+                deferred.lock().unwrap().push(DeferredWarning {
+                    warning: Warning {
+                        line_number,
+                        kind: WarningKind::IgnoredLine,
+                        text: line.to_string(),
+                    },
+                    once: None,
+                    message: None,
+                });
+            }
+        }
+    }
+
+    pub(super) fn weird_event_line(warnings: &Warnings, line_number: usize, line: &str) {
+        warnings.warn(
+            WarningKind::WeirdEvent,
+            line_number,
+            line,
+            None,
+            format_args!("Weird event line: {}", line),
+        );
+    }
+
+    pub(super) fn weird_folded_line(warnings: &Warnings, line_number: usize, line: &str) {
+        warnings.warn(
+            WarningKind::WeirdFolded,
+            line_number,
+            line,
+            None,
+            format_args!("Weird folded stack line: {}", line),
+        );
+    }
+
+    pub(super) fn weird_stack_line(warnings: &Warnings, line_number: usize, line: &str) {
+        warnings.warn(
+            WarningKind::WeirdStack,
+            line_number,
+            line,
+            None,
+            format_args!("Weird stack line: {}", line),
+        );
+    }
+
+    pub(super) fn too_many_frames(
+        warnings: &Warnings,
+        line_number: usize,
+        line: &str,
+        pname: &str,
+        max_stack_frames: usize,
+    ) {
+        warnings.warn(
+            WarningKind::TooManyFrames,
+            line_number,
+            line,
+            None,
+            format_args!(
+                "Dropping event of {} with more than {} frames; is an empty line missing after it?",
+                pname, max_stack_frames
+            ),
+        );
+    }
+
+    pub(super) fn missing_timestamp(warnings: &Warnings, line_number: usize, line: &str) {
+        warnings.warn(
+            WarningKind::MissingTimestamp,
+            line_number,
+            line,
+            Some("missing_timestamp"),
+            format_args!("Dropping events without a timestamp, like: {}", line),
+        );
+    }
+
+    pub(super) fn weighting_by_period(event: &str, unit: &str) {
+        info!(
+            "Weighting events of type {} by their period in {}",
+            event, unit
+        );
     }
 
-    pub(super) fn weird_stack_line(line: &str) {
-        warn!("Weird stack line: {}", line);
+    pub(super) fn weird_weight_column(
+        warnings: &Warnings,
+        line_number: usize,
+        line: &str,
+        column: usize,
+    ) {
+        warnings.warn(
+            WarningKind::WeirdWeightColumn,
+            line_number,
+            line,
+            Some("weird_weight_column"),
+            format_args!(
+                "Weight column {} is missing or not numeric on event line: {}",
+                column, line
+            ),
+        );
     }
 }
 
@@ -34,6 +269,28 @@ enum StackFilter {
 #[derive(Clone, Debug)]
 #[non_exhaustive]
 pub struct Options {
+    /// Prefix raw addresses included by `include_addrs` with `0x`.
+    ///
+    /// Regardless of this option, such addresses are always normalized to lowercase hex
+    /// without leading zeros, so that the same address always folds together.
+    ///
+    /// Default is `false`.
+    pub addr_prefix: bool,
+
+    /// Append a custom suffix to every function in the stacks of some events, such as `_[o]` to
+    /// tell off-CPU samples (e.g. `sched:sched_switch`) from on-CPU ones (e.g. `cycles`) when
+    /// `event_filter` matches both.
+    ///
+    /// The event matches either the event name (e.g. `cycles`) or the whole event as it appears
+    /// on the event line (e.g. `sched:sched_switch`), and may be a glob pattern as in
+    /// `event_filter`. The first matching annotation wins. Its suffix is appended to every frame
+    /// once the stack is complete, after all other annotations.
+    ///
+    /// See [`EventAnnotation`] for the syntax used to parse these from strings.
+    ///
+    /// Default is no annotations.
+    pub annotate_event: Vec<EventAnnotation>,
+
     /// Annotate JIT functions with a `_[j]` suffix.
     ///
     /// Default is `false`.
@@ -44,65 +301,1713 @@ pub struct Options {
     /// Default is `false`.
     pub annotate_kernel: bool,
 
+    /// Append a custom suffix to every function (or module) matching a regular expression.
+    ///
+    /// These annotations are applied after the built-in ones (`_[i]`, `_[vdso]`, `_[k]`,
+    /// `_[d]`, and `_[j]`, of which at most one is added), and are matched against the function
+    /// name without any suffix. Unlike the built-in annotations, these accumulate: every
+    /// matching annotation appends its suffix, in the order given. For example, `annotate_kernel`
+    /// behaves much like `module:^\[kernel\.kallsyms\]=_[k]`, and `annotate_jit` much like
+    /// `module:^/tmp/perf-.*\.map$=_[j]`.
+    ///
+    /// See [`RegexAnnotation`] for the syntax used to parse these from strings.
+    ///
+    /// Default is no annotations.
+    pub annotate_regex: Vec<RegexAnnotation>,
+
+    /// Reduce `event_filter` to its canonical event name, by removing any modifiers after the
+    /// first `:` (e.g. `cycles:uppp` becomes `cycles`).
+    ///
+    /// Event names are always read from the event lines without their modifiers, so without
+    /// this, an `event_filter` with modifiers matches no events at all. Glob patterns (see
+    /// `event_filter`) are left as is.
+    ///
+    /// Default is `false`.
+    pub canonical_event: bool,
+
+    /// Merge frames whose names only differ in case, such as `KERNEL32!foo` and `kernel32!foo`,
+    /// and name them as they were first seen in the input. This is meant for captures converted
+    /// from other platforms (e.g. from ETW on Windows), where the casing of symbols varies; native
+    /// `perf` symbols are case-sensitive. Every event is processed on a single thread, so that
+    /// the first-seen casing doesn't depend on the number of threads.
+    ///
+    /// Default is `false`.
+    pub case_fold_frames: bool,
+
+    /// Group processes into categories (e.g., by team or service), by adding a frame with the
+    /// category of each sample's process name at the root of its stack, above the process name
+    /// (but below `root_frame`, if set).
+    ///
+    /// See [`CategoryMap`] for the syntax used to parse a mapping from a string.
+    ///
+    /// Default is `None`.
+    pub category_map: Option<CategoryMap>,
+
+    /// Collect the warnings about input lines, which [`Folder::warnings`] returns once the input
+    /// has been collapsed, rather than logging them. This is meant for tools that keep track of
+    /// the quality of captures. Lines that are skipped as `perf` noise (see `ignore_prefix`) are
+    /// collected too, as [`WarningKind::IgnoredLine`]. As with `deterministic`, the warnings
+    /// are in the order of the lines, however many threads collapse the input.
+    ///
+    /// Default is `false`.
+    pub collect_warnings: bool,
+
+    /// Treat comment lines that start with the given prefix (after the `#` and any whitespace
+    /// following it) as markers, e.g. of request boundaries that were injected into the input by
+    /// a post-processing step. The rest of such a comment is added as a frame at the root of the
+    /// stack of every event that follows it, above the process name (but below `root_frame`, the
+    /// category, and the NUMA node, if set), until the next marker. A marker with nothing after
+    /// the prefix stops adding a frame. Any `;` in a marker is replaced with `:`, since `;`
+    /// separates frames. Other comment lines are skipped as usual.
+    ///
+    /// For example, with a prefix of `request:`, the events after `# request: 42` get a `42` frame.
+    /// As markers apply to the events that follow them in the input, samples are processed on a
+    /// single thread.
+    ///
+    /// Default is `None`.
+    pub comment_prefix: Option<String>,
+
+    /// Group samples by NUMA node, by adding a frame like `node1` at the root of every stack,
+    /// above the process name (but below `root_frame` and the category, if set). The node is the
+    /// CPU number (the `[001]` on event lines) divided by this number of CPUs per node, so this
+    /// assumes that each node has a contiguous range of CPUs. Samples without a CPU number get
+    /// `node?`. A value of zero is ignored.
+    ///
+    /// Default is `None`.
+    pub cpus_per_node: Option<usize>,
+
+    /// Parse the input with the given [`CustomParser`] instead of as `perf script` output, while
+    /// still applying all other options to the events and frames it reports. See [`LineParser`]
+    /// for how the parser reports them.
+    ///
+    /// The parser is given every line of the input (including comments) except those skipped by
+    /// `ignore_prefix` and the `perf` warnings that are always skipped, and `from_folded` and
+    /// `comment_prefix` have no effect. As the parser may keep state across lines, samples are
+    /// processed on a single thread.
+    ///
+    /// Default is `None`.
+    pub custom_parser: Option<CustomParser>,
+
+    /// Only keep the frames from the root of each stack down to (and including) the first frame
+    /// whose function name (without annotations) matches this regular expression, and remove
+    /// the frames it calls. Stacks without a matching frame are left as is, and the process name
+    /// is always kept. Of several matching frames, the rootmost one is used, unless
+    /// `cut_leafmost` is set.
+    ///
+    /// For example, `main;run;lock;futex_wait` with a regex of `^lock$` becomes `main;run;lock`.
+    ///
+    /// Default is `None`.
+    pub cut_above: Option<Regex>,
+
+    /// Only keep the frames from the first frame whose function name (without annotations)
+    /// matches this regular expression down to the leaf of each stack, and remove its callers.
+    /// This is useful to focus on a subsystem. Stacks without a matching frame are left as is,
+    /// and the process name is always kept. Of several matching frames, the rootmost one is
+    /// used, unless `cut_leafmost` is set. If `cut_above` is set as well, this is applied first.
+    ///
+    /// For example, `main;run;lock;futex_wait` with a regex of `^run$` becomes
+    /// `run;lock;futex_wait`.
+    ///
+    /// Default is `None`.
+    pub cut_below: Option<Regex>,
+
+    /// Make `cut_above` and `cut_below` cut at the leafmost matching frame of each stack, rather
+    /// than the rootmost one. This matters for recursive code, where the frame may appear
+    /// several times in a stack.
+    ///
+    /// Default is `false`.
+    pub cut_leafmost: bool,
+
+    /// Count the samples of every frame in [`Stats::frame_counts`], to check that annotating
+    /// and folding neither dropped nor duplicated samples. Has no effect unless `stats` is set.
+    ///
+    /// Default is `false`.
+    pub debug_counts: bool,
+
+    /// Annotate functions whose name matches this regular expression with a `_[d]` suffix, to
+    /// tell deoptimized or interpreted frames of a just-in-time compiler apart from compiled
+    /// ones. Examples are V8's `~` frames (like `LazyCompile:~main` or `JS:~main`) and bytecode
+    /// handlers (like `Builtin:InterpreterEntryTrampoline`), and the JVM's `Interpreter` frames.
+    /// Matching frames are annotated with `_[d]` rather than `_[j]` when `annotate_jit` is set.
+    ///
+    /// Default is `None`.
+    pub deopt_regex: Option<Regex>,
+
+    /// Make collapsing on several threads (see `nthreads`) log the same warnings, in the same
+    /// order, as collapsing on a single thread, so that the whole output is reproducible. The
+    /// folded stacks are written in sorted order either way, so they never depend on the number
+    /// of threads. Warnings about input lines are held back until all lines were processed, and
+    /// are then logged in the order of the lines, which costs a little performance. Warnings
+    /// that are only logged for the first line they apply to are not repeated by every thread.
+    ///
+    /// Default is `false`.
+    pub deterministic: bool,
+
+    /// Record how the first this many non-comment lines of the input are classified (as event,
+    /// stack, or blank lines, etc.), to see how the input is interpreted when the output looks
+    /// wrong; see [`Folder::dumped_lines`]. Lines are not recorded with `custom_parser`, and
+    /// samples are processed on a single thread. A value of zero is ignored.
+    ///
+    /// Default is `None`.
+    pub dump_lines: Option<usize>,
+
     /// Only consider samples of the given event type (see `perf list`). If this option is
     /// set to `None`, it will be set to the first encountered event type.
     ///
+    /// This may also be a glob pattern, in which `*` matches any sequence of characters and `?`
+    /// any single character, such as `sched:*` for all scheduler tracepoints. A pattern matches
+    /// an event if it matches either its name (e.g. `cycles`) or the whole event as it appears
+    /// on the event line, without the `:` after it (e.g. `cycles:uppp` or
+    /// `sched:sched_switch`). Names without `*` or `?` only match events of that name exactly.
+    ///
     /// Default is `None`.
     pub event_filter: Option<String>,
 
+    /// Gather a flat profile of the collapsed events, which [`Folder::flat_profile`] returns once
+    /// the input has been collapsed. Samples are processed on a single thread.
+    ///
+    /// Default is `false`.
+    pub flat_profile: bool,
+
+    /// Attribute Rust closures and C++ lambdas to their enclosing function, by removing
+    /// everything from the first closure or lambda in the frame name onwards. For example,
+    /// `app::run::{{closure}}::{{closure}}` becomes `app::run`, and
+    /// `main::{lambda(int)#1}::operator()` becomes `main`. A closure called directly by its
+    /// enclosing function is merged with the frame of that function. This reduces the
+    /// fragmentation of a flame graph into many small closure frames.
+    ///
+    /// Default is `false`.
+    pub fold_closures: bool,
+
+    /// Merge the clones that GCC makes of a function with the function itself, by removing
+    /// their `.constprop.N`, `.isra.N`, `.part.N`, and `.cold` (or `.cold.N`) suffixes, in any
+    /// combination, and the ` [clone .cold]` form that demangling gives them. For example,
+    /// `parse.isra.0` and `parse.part.1.cold` both become `parse`. Like a closure with
+    /// `fold_closures`, a clone called directly by its base function is merged with the frame of
+    /// that function.
+    ///
+    /// Default is `false`.
+    pub fold_gcc_clones: bool,
+
+    /// Merge every run of adjacent frames from a module whose path contains one of these
+    /// strings into a single frame named after the module, like `[libssl.so.3]`. This hides
+    /// the internals of a library, while keeping the frames around it. A stack that enters the
+    /// module several times gets a frame for each time.
+    ///
+    /// Default is empty.
+    pub fold_module: Vec<String>,
+
+    /// Remove PLT stubs (like `malloc@plt`) and other trampolines (like `_dl_runtime_resolve`)
+    /// from each stack, which attributes their samples to their caller. Frames are recognized by
+    /// matching their function name against `plt_regex`.
+    ///
+    /// Default is `false`.
+    pub fold_plt: bool,
+
+    /// Merge every chain of adjacent frames whose function names match this regular expression
+    /// into a single frame, such as the layers of wrappers that glibc calls a system call
+    /// through (e.g. `__libc_write`, `write`, and `__write`). The outermost frame of the chain
+    /// is kept, unless `fold_wrappers_innermost` is set.
+    ///
+    /// Default is `None`.
+    pub fold_wrappers: Option<Regex>,
+
+    /// Make `fold_wrappers` keep the innermost frame of each chain, rather than the outermost.
+    ///
+    /// Default is `false`.
+    pub fold_wrappers_innermost: bool,
+
+    /// Read already folded stacks (lines of `stack count`, as written by this folder) instead
+    /// of `perf script` output, and re-apply the frame-level options to them before
+    /// aggregating them again. This allows experimenting with those options without
+    /// re-running `perf`.
+    ///
+    /// The first frame of each stack is taken to be the process name. The options that apply
+    /// are `annotate_regex` (matching function names only, as modules are not known),
+    /// `category_map`, `cut_above`, `cut_below`, `fold_plt`, `fold_wrappers`, `keep_frame`,
+    /// `root_frame`, `scale_divisor`, `stack_hook`, `strip_path_prefix`, `tag_all`, and
+    /// `topk`. Annotations already in the input are kept, and ignored when matching frames.
+    /// Options that need information only found in `perf script` output have no effect.
+    ///
+    /// Default is `false`.
+    pub from_folded: bool,
+
+    /// Treat a thread whose TID reappears after more than this many seconds without events as a
+    /// new thread that reused the TID, and suffix its process name with a generation counter,
+    /// like `app-1001/1002#2`. This is an advanced option for long captures, over which TIDs
+    /// may be recycled. Implies `include_tid`.
+    ///
+    /// Events without a timestamp are counted for the current generation of their thread.
+    /// Samples are processed on a single thread.
+    ///
+    /// Default is `None`.
+    pub group_by_tid_lifetime: Option<f64>,
+
+    /// Gather the most sampled leaf frame of every process, which [`Folder::hot_leaves`] returns
+    /// once the input has been collapsed, for a quick view of what each process was busy in.
+    /// Samples are processed on a single thread.
+    ///
+    /// Default is `false`.
+    pub hot_leaves: bool,
+
+    /// The functions that mark a sample with any of them on its stack as idle time for
+    /// `no_idle`, instead of the default ones: `cpu_idle`, `cpu_idle_poll`, `default_idle`,
+    /// `do_idle`, `intel_idle`, `native_safe_halt`, and `poll_idle`. Names are matched exactly,
+    /// without any symbol offset. Has no effect unless `no_idle` is set.
+    ///
+    /// Default is no functions, which means the default ones.
+    pub idle_frames: Vec<String>,
+
+    /// Skip lines that start with any of the given strings (ignoring leading whitespace).
+    ///
+    /// Lines with common `perf` warning and error prefixes (such as `Warning:`) are always
+    /// skipped, since they show up when `perf script`'s STDERR is merged into its output. This
+    /// option is for other, site-specific noise.
+    ///
+    /// Default is no additional prefixes.
+    pub ignore_prefix: Vec<String>,
+
     /// Include raw addresses (e.g., `0xbfff0836`) where symbols can't be found.
     ///
     /// Default is `false`.
     pub include_addrs: bool,
 
-    /// Include PID in the root frame. If disabled, the root frame is given the name of the
-    /// profiled process.
-    ///
-    /// Default is `false`.
-    pub include_pid: bool,
+    /// Include PID in the root frame. If disabled, the root frame is given the name of the
+    /// profiled process.
+    ///
+    /// Default is `false`.
+    pub include_pid: bool,
+
+    /// Include TID and PID in the root frame. Implies `include_pid`.
+    ///
+    /// Default is `false`.
+    pub include_tid: bool,
+
+    /// Only keep frames whose function name (without annotations) matches this regular
+    /// expression, and remove all others from each stack.
+    ///
+    /// Removing a frame attaches its children directly to its closest surviving ancestor, so
+    /// `main;lib_a;app_b;lib_c` with a regex of `^app_|^main$` becomes `main;app_b`. The root
+    /// frame with the process name is always kept, but stacks left without any other frames are
+    /// dropped.
+    ///
+    /// Default is `None`.
+    pub keep_frame: Option<Regex>,
+
+    /// Keep the spaces in process names (e.g. `V8 WorkerThread`), rather than replacing them
+    /// with underscores (`V8_WorkerThread`).
+    ///
+    /// Spaces don't clash with the `;` that separates frames, so this is safe for flamegraphs,
+    /// but it may confuse tools that split folded lines on whitespace rather than on the last
+    /// space.
+    ///
+    /// Default is `false`.
+    pub keep_pname_spaces: bool,
+
+    /// Stop once this many events have been counted, e.g. to profile just the startup of a
+    /// program from the beginning of a long capture. The rest of the input is not read at all
+    /// (so when reading from STDIN, it is left unconsumed). Samples are processed on a single
+    /// thread. A value of zero is ignored.
+    ///
+    /// Default is `None`.
+    pub limit_events: Option<usize>,
+
+    /// Annotate frames that call into a different domain with a `_[t]` suffix, e.g. the user
+    /// function that makes a system call, or a kernel function that calls back into user
+    /// space. Frames are attributed to the kernel using the same module-based detection as
+    /// `annotate_kernel`, and only frames that end up in the output are considered. Stacks
+    /// that are entirely in one domain are left unmarked.
+    ///
+    /// Default is `false`.
+    pub mark_transitions: bool,
+
+    /// Fail with an error, rather than write partial results, if more than this many event and
+    /// stack lines could not be parsed. Such lines are always logged as warnings, and otherwise
+    /// skipped. This helps catch input captured with the wrong `perf script -F` fields.
+    ///
+    /// Default is `None`, which allows any number of malformed lines.
+    pub max_malformed: Option<usize>,
+
+    /// The maximum number of frames in a single stack. Events with more frames than this are
+    /// dropped, and a warning is logged. This protects against unbounded memory use on
+    /// corrupted input, such as input without empty lines between events. A value of zero
+    /// disables the limit.
+    ///
+    /// Default is `100_000`, which is far deeper than legitimate stacks get.
+    pub max_stack_frames: usize,
+
+    /// Fail with an error, rather than write the folded stacks, if more than this fraction
+    /// (from 0.0 to 1.0) of all frames have an unknown symbol (`[unknown]` in the input, even if
+    /// they are then named after their module). Frames are counted before any are removed by
+    /// options like `keep_frame`, and stacks read with `from_folded` are not checked. This helps
+    /// catch missing debug symbols, e.g. in CI.
+    ///
+    /// Default is `None`, which allows any number of unknown frames.
+    pub max_unknown_ratio: Option<f64>,
+
+    /// Count the stacks dropped by `min_width_pct` together, as a single `[other]` stack, so that
+    /// the total count is unchanged.
+    ///
+    /// Default is `false`.
+    pub min_width_other: bool,
+
+    /// Drop the folded stacks whose count is less than this percentage (from 0 to 100) of the
+    /// total count of all stacks, like a flame graph renderer drops frames that would be too
+    /// narrow to see. Unlike a fixed minimum count, this adapts to the size of the capture.
+    ///
+    /// Stacks are dropped after their counts are scaled by `sample_rate` and `scale_divisor`,
+    /// and only from the folded stacks, not from `stats` or the flat profile. Has no effect with
+    /// `timeline`.
+    ///
+    /// Default is `None`.
+    pub min_width_pct: Option<f64>,
+
+    /// Reduce the module of every frame to its file name (e.g. `/usr/lib64/libc.so.6` to
+    /// `libc.so.6`) before it is inspected, so that output collapsed on hosts with different
+    /// library paths is comparable. This affects the detection of kernel frames and the
+    /// `module:` annotations of `annotate_regex`. Modules in brackets, like
+    /// `[kernel.kallsyms]`, are left as is, and JIT frames are still detected by the full path
+    /// of their module.
+    ///
+    /// Default is `false`.
+    pub module_basename: bool,
+
+    /// Don't add any annotation suffixes to frames whose symbol is `[unknown]`, and that are
+    /// therefore named after their module instead (e.g. `[nf_conntrack]`). The module of such
+    /// frames is often not a reliable indication of the kind of code they run, so this keeps
+    /// the coloring of annotated frames honest.
+    ///
+    /// Default is `false`.
+    pub no_annotate_unknown: bool,
+
+    /// Drop the idle time that dominates captures of a whole system, to see only the time that
+    /// CPUs were busy: the samples of the `swapper` process (the kernel's idle task), and those
+    /// with any of the `idle_frames` on their stack.
+    ///
+    /// Default is `false`.
+    pub no_idle: bool,
+
+    /// The number of threads to use.
+    ///
+    /// Default is the number of logical cores on your machine.
+    pub nthreads: usize,
+
+    /// Only keep samples (or, with `trim_to_domain`, frames) from the given [`Domain`]. Frames
+    /// are attributed to the kernel using the same module-based detection as `annotate_kernel`.
+    ///
+    /// By default, a sample is kept in its entirety if its leaf frame is in the domain, and
+    /// dropped otherwise. This separates, e.g., time spent in the kernel from time spent in
+    /// user space, while keeping the full call path that led there.
+    ///
+    /// Default is `None`.
+    pub only_domain: Option<Domain>,
+
+    /// The regular expression that `fold_plt` matches function names (without annotations)
+    /// against, instead of the default one, which matches names ending in `@plt`, and the
+    /// `_dl_runtime_resolve*`, `__tls_get_addr`, and `_init` trampolines. Has no effect unless
+    /// `fold_plt` is set.
+    ///
+    /// Default is `None`.
+    pub plt_regex: Option<Regex>,
+
+    /// Truncate process names that are longer than this many characters, and mark them with a
+    /// trailing `...`, e.g. for Java thread names that would make the root frames of the flame
+    /// graph huge. Only the name itself is truncated, not the PID/TID added by `include_pid` or
+    /// `include_tid`. A value of zero disables the limit.
+    ///
+    /// Default is `None`.
+    pub pname_max_len: Option<usize>,
+
+    /// Ignore the frames of every sample, and count samples per process instead, for a flat
+    /// view of which processes used the CPU. Root frames (see `root_frame`, `category_map`, and
+    /// `cpus_per_node`) are still added, and `only_domain` still decides by the leaf frame
+    /// which samples to keep, but the options that act on frames have no effect.
+    ///
+    /// Default is `false`.
+    pub process_only: bool,
+
+    /// Add a frame with the given name at the root of every stack, above the process name. This
+    /// gives a flame graph of several processes a common base to zoom out to. Any `;` in the
+    /// name is replaced with `:`, since `;` separates frames.
+    ///
+    /// The root frame is added even when `skip_after` omits the frames above a match.
+    ///
+    /// Default is `None`.
+    pub root_frame: Option<String>,
+
+    /// Keep track of the longest run of consecutive events with identical (folded) stacks in
+    /// [`Stats::longest_run`]. In time-ordered input, a long run may indicate that the program
+    /// was stuck. Events that are dropped (e.g. by `only_domain`) are skipped, rather than
+    /// ending a run. Has no effect unless `stats` is set.
+    ///
+    /// Default is `false`.
+    pub runs: bool,
+
+    /// Only process one in every given number of events, and multiply the count of every folded
+    /// stack by that number, so that very large captures can be collapsed faster. Events are
+    /// picked deterministically (the first, the `N + 1`th, and so on, among those that pass
+    /// `event_filter`), and are kept or dropped as a whole.
+    ///
+    /// This is **approximate**: the counts are estimates, and rare stacks may be missing from
+    /// the output altogether. Samples are processed on a single thread, and events read with
+    /// `from_folded` are not subsampled. A value of zero or one is ignored.
+    ///
+    /// Default is `None`.
+    pub sample_rate: Option<usize>,
+
+    /// Divide the count of every folded stack by the given number before writing it out, e.g.
+    /// to turn sample periods in nanoseconds (see `weight_column`) into microseconds.
+    ///
+    /// Counts are divided with integer division once all samples have been aggregated, so each
+    /// stack's total is rounded down. Stacks whose total rounds down to zero are omitted. A
+    /// divisor of zero is ignored.
+    ///
+    /// Default is `None`.
+    pub scale_divisor: Option<usize>,
+
+    /// If a stack function name is equal to any of the specified strings it will omit all the
+    /// following stackframes for that event.
+    /// In case no function is matched the whole stack is returned.
+    /// Default is not omitting any.
+    pub skip_after: Vec<String>,
+
+    /// Write the folded stacks in the order in which they first appear in the input, rather than
+    /// sorted, e.g. to correlate them with the output of other tools over time. Renderers like
+    /// `inferno-flamegraph` ignore the order of their input, but other consumers may not.
+    ///
+    /// This remembers the position of every unique stack, which takes an extra word of memory per
+    /// stack. As the position of a stack depends on all the events before it, samples are
+    /// processed on a single thread. Has no effect with `timeline` or `topk`.
+    ///
+    /// Default is `false`.
+    pub sort_by_appearance: bool,
+
+    /// Call the given [`StackHook`] with the frames of every stack right before it is counted,
+    /// so that they can be modified arbitrarily (e.g. to drop or rename frames).
+    ///
+    /// The hook runs after all other per-stack processing (but before `root_frame` and the
+    /// process name are prepended), and stacks it leaves without frames are dropped.
+    ///
+    /// Default is `None`.
+    pub stack_hook: Option<StackHook>,
+
+    /// Gather statistics about the collapsed events, which [`Folder::stats`] returns once the
+    /// input has been collapsed. Samples are processed on a single thread.
+    ///
+    /// Default is `false`.
+    pub stats: bool,
+
+    /// Fail with an error naming the line and its number on the first event, stack or folded
+    /// line that cannot be parsed, instead of warning about it and carrying on. Lines matched by
+    /// `ignore_prefix` are skipped before parsing, and so never fail.
+    ///
+    /// Default is `false`.
+    pub strict: bool,
+
+    /// Remove ANSI escape sequences (e.g. color codes) from every input line before parsing it,
+    /// for input that went through a terminal logger (like `script`) in a color-forcing
+    /// environment.
+    ///
+    /// Default is `false`.
+    pub strip_ansi: bool,
+
+    /// Remove the first of these prefixes that a process name starts with, e.g. to drop noise
+    /// that a container runtime adds to it. Only a prefix at the very start of the name is
+    /// removed, and a name that consists of just the prefix is left as is. The category of a
+    /// process (see `category_map`) is decided by its name after the prefix is removed.
+    ///
+    /// Default is `[]`.
+    pub strip_comm_prefix: Vec<String>,
+
+    /// Remove these prefixes from the paths in the output, e.g. the build directory of a CI host,
+    /// so that output collapsed on different hosts is comparable. Prefixes are removed from the
+    /// start of the module of every frame (before it is inspected, as with `module_basename`),
+    /// and from the start of every path in function names, such as the script path in V8's
+    /// `LazyCompile:~main /app/index.js:10`; a path starts at the start of the name, or after a
+    /// space, `(`, or `:`. Of several matching prefixes, the longest is removed. Empty prefixes
+    /// are ignored.
+    ///
+    /// Default is `[]`.
+    pub strip_path_prefix: Vec<String>,
+
+    /// Resolve frames whose symbol perf couldn't resolve (`[unknown]`) by looking up their
+    /// address in the given map, e.g. for stripped binaries whose symbols are available
+    /// separately. Frames whose address isn't in the map are named after their module as usual.
+    ///
+    /// See [`SymbolMap`] for the syntax used to parse a map from a string.
+    ///
+    /// Default is `None`.
+    pub symbol_map: Option<SymbolMap>,
+
+    /// Add a frame with the given name at the leaf of every stack, below the innermost function,
+    /// such as the host or environment the capture came from. Unlike `root_frame`, this keeps the
+    /// stacks of several captures merged into one flame graph together, while still telling
+    /// their origins apart at the top. Any `;` in the name is replaced with `:`, since `;`
+    /// separates frames.
+    ///
+    /// Default is `None`.
+    pub tag_all: Option<String>,
+
+    /// Only keep the events with a timestamp before this many seconds, to zoom into a time
+    /// window of a long capture along with `time_start`. The value is compared as is with the
+    /// timestamp column of `perf script`, so it is relative to the start of the capture if the
+    /// timestamps are (e.g. with `perf script --reltime`), and absolute otherwise.
+    ///
+    /// Events without a timestamp are dropped when either bound is set, and a warning is logged
+    /// the first time this happens. Events read with `from_folded` are not filtered.
+    ///
+    /// Default is `None`.
+    pub time_end: Option<f64>,
+
+    /// Only keep the events with a timestamp at or after this many seconds; see `time_end`.
+    ///
+    /// Default is `None`.
+    pub time_start: Option<f64>,
+
+    /// Rather than aggregating stacks, write one line per event with its timestamp and stack,
+    /// like `72.176760 vote;main;run`, in the order of the input. This is intended for tools
+    /// that show samples over time (e.g. time-ordered flame charts), and is **not** the folded
+    /// format expected by [`crate::flamegraph`], as the lines have no counts.
+    ///
+    /// Events without a timestamp are dropped, and a warning is logged the first time this
+    /// happens. Samples are processed on a single thread, and the options that act on the
+    /// aggregated counts (`scale_divisor`, `topk`, and `weight_column`) have no effect.
+    ///
+    /// Default is `false`.
+    pub timeline: bool,
+
+    /// Only keep track of (approximately) the given number of most frequent stacks, so that
+    /// memory use stays bounded even for very large captures.
+    ///
+    /// This uses the Space-Saving algorithm, and is **approximate**: a stack that makes up more
+    /// than `1 / topk` of the total is always kept, but other stacks may be missing, and the
+    /// count of a kept stack may be overestimated by the counts of stacks evicted to make room
+    /// for it. Samples are processed on a single thread. A value of zero is ignored.
+    ///
+    /// Default is `None`.
+    pub topk: Option<usize>,
+
+    /// Change the behavior of `only_domain` to remove all frames outside the domain from each
+    /// stack, rather than keeping or dropping whole stacks. Stacks left without any frames are
+    /// dropped. Has no effect unless `only_domain` is set.
+    ///
+    /// Default is `false`.
+    pub trim_to_domain: bool,
+
+    /// The process name to use for events whose comm is `-` or empty, as perf sometimes reports
+    /// for unknown or exiting processes.
+    ///
+    /// Default is `"unknown"`.
+    pub unknown_comm: String,
+
+    /// Count the stacks with suspicious program counters in [`Stats::suspicious_pcs`], to spot
+    /// unwinding problems or a corrupted capture: a frame at address 0 anywhere but at the
+    /// outermost end of the stack, where perf legitimately ends an unwind that went no further
+    /// (as in `0 [unknown] ([unknown])`). Has no effect on the folded stacks, nor unless `stats`
+    /// is set.
+    ///
+    /// Default is `false`.
+    pub validate_pcs: bool,
+
+    /// Annotate functions whose name matches this regular expression with a `_[vdso]` suffix,
+    /// whatever their module, to identify vDSO functions by their symbol when perf reports
+    /// their module as `[unknown]`. Common vDSO symbols are `__vdso_` functions (like
+    /// `__vdso_gettimeofday`) on x86, and `__kernel_` functions (like `__kernel_clock_gettime`)
+    /// on arm64 and powerpc, so `^__(vdso|kernel)_` matches both.
+    ///
+    /// Matching frames are annotated with `_[vdso]` rather than `_[k]` (as perf may also report
+    /// their module as `[vdso]`), `_[d]`, or `_[j]`.
+    ///
+    /// Default is `None`.
+    pub vdso_regex: Option<Regex>,
+
+    /// Weight each sample by the number in the given whitespace-delimited column of the event
+    /// line, rather than counting each sample once. Columns are numbered starting at 1 from
+    /// the first column after the timestamp. For example, in
+    ///
+    /// ```text
+    /// vote   913    72.176760:     257597 cycles:uppp:
+    /// ```
+    ///
+    /// column 1 holds the sample period (`257597`). This is useful for off-CPU profiles, where
+    /// a sample should be weighted by a duration.
+    ///
+    /// Samples whose event line lacks a timestamp, or a numeric value in that column, are
+    /// counted once, and a warning is logged the first time this happens.
+    ///
+    /// Default is `None`.
+    pub weight_column: Option<usize>,
+
+    /// Weight each sample by its period if the `perf script --header` metadata at the start of
+    /// the input shows that the period was recorded for its event (`PERIOD` in the
+    /// `sample_type` of the `# event :` line), rather than counting each sample once. The unit
+    /// of the period, such as `cycles` for `cycles` and `nanoseconds` for `cpu-clock`, is then
+    /// available from `Folder::count_name`. Has no effect if `weight_column` is set, or if the
+    /// input has no such metadata.
+    ///
+    /// Default is `false`.
+    pub weight_from_header: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            addr_prefix: false,
+            annotate_event: Vec::default(),
+            annotate_jit: false,
+            annotate_kernel: false,
+            annotate_regex: Vec::default(),
+            canonical_event: false,
+            case_fold_frames: false,
+            category_map: None,
+            collect_warnings: false,
+            comment_prefix: None,
+            cpus_per_node: None,
+            custom_parser: None,
+            cut_above: None,
+            cut_below: None,
+            cut_leafmost: false,
+            debug_counts: false,
+            deopt_regex: None,
+            deterministic: false,
+            dump_lines: None,
+            event_filter: None,
+            flat_profile: false,
+            fold_closures: false,
+            fold_gcc_clones: false,
+            fold_module: Vec::new(),
+            fold_plt: false,
+            fold_wrappers: None,
+            fold_wrappers_innermost: false,
+            from_folded: false,
+            group_by_tid_lifetime: None,
+            hot_leaves: false,
+            idle_frames: Vec::default(),
+            ignore_prefix: Vec::default(),
+            include_addrs: false,
+            include_pid: false,
+            include_tid: false,
+            keep_frame: None,
+            keep_pname_spaces: false,
+            limit_events: None,
+            mark_transitions: false,
+            max_malformed: None,
+            max_stack_frames: DEFAULT_MAX_STACK_FRAMES,
+            max_unknown_ratio: None,
+            min_width_other: false,
+            min_width_pct: None,
+            module_basename: false,
+            no_annotate_unknown: false,
+            no_idle: false,
+            nthreads: *common::DEFAULT_NTHREADS,
+            only_domain: None,
+            plt_regex: None,
+            pname_max_len: None,
+            process_only: false,
+            root_frame: None,
+            runs: false,
+            sample_rate: None,
+            scale_divisor: None,
+            skip_after: Vec::default(),
+            sort_by_appearance: false,
+            stack_hook: None,
+            stats: false,
+            strict: false,
+            strip_ansi: false,
+            strip_comm_prefix: Vec::default(),
+            strip_path_prefix: Vec::default(),
+            symbol_map: None,
+            tag_all: None,
+            time_end: None,
+            time_start: None,
+            timeline: false,
+            topk: None,
+            trim_to_domain: false,
+            unknown_comm: "unknown".to_string(),
+            validate_pcs: false,
+            vdso_regex: None,
+            weight_column: None,
+            weight_from_header: false,
+        }
+    }
+}
+
+/// Where a frame executes; see [`Options::only_domain`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Domain {
+    /// Frames in the kernel, e.g. those in `[kernel.kallsyms]`.
+    Kernel,
+    /// All other frames.
+    User,
+}
+
+impl Domain {
+    fn of_module(module: &str) -> Self {
+        if is_kernel(module) {
+            Domain::Kernel
+        } else {
+            Domain::User
+        }
+    }
+}
+
+/// A user-defined annotation of the stacks of an event; see [`Options::annotate_event`].
+///
+/// Can be parsed from a string of the form `EVENT=SUFFIX`. As with [`RegexAnnotation`], the
+/// suffix is everything after the last `=`.
+#[derive(Clone, Debug)]
+pub struct EventAnnotation {
+    /// The event whose stacks to annotate (e.g. `sched:sched_switch`), or a glob pattern; see
+    /// [`Options::event_filter`].
+    pub event: String,
+
+    /// The suffix to append to every function in those stacks (e.g. `_[o]`).
+    pub suffix: String,
+}
+
+impl EventAnnotation {
+    fn is_match(&self, name: &str, full_name: Option<&str>) -> bool {
+        is_filtered_event(&self.event, name, full_name) || full_name == Some(&*self.event)
+    }
+}
+
+impl FromStr for EventAnnotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((event, suffix)) if !event.is_empty() => Ok(EventAnnotation {
+                event: event.to_string(),
+                suffix: suffix.to_string(),
+            }),
+            _ => Err(format!("expected EVENT=SUFFIX, got: {}", s)),
+        }
+    }
+}
+
+/// A user-defined annotation; see [`Options::annotate_regex`].
+///
+/// Can be parsed from a string of the form `RE=SUFFIX` (matching against the function name),
+/// or `module:RE=SUFFIX` (matching against the module name). The suffix is everything after
+/// the last `=`, so the regular expression may itself contain `=`.
+#[derive(Clone, Debug)]
+pub struct RegexAnnotation {
+    /// The regular expression that must match for the suffix to be appended.
+    pub regex: Regex,
+
+    /// Match `regex` against the module (e.g. `/usr/lib/libc-2.28.so`) rather than against
+    /// the function name.
+    pub match_module: bool,
+
+    /// The suffix to append to matching functions (e.g. `_[db]`).
+    pub suffix: String,
+}
+
+impl RegexAnnotation {
+    fn is_match(&self, func: &str, module: &str) -> bool {
+        self.regex
+            .is_match(if self.match_module { module } else { func })
+    }
+}
+
+impl FromStr for RegexAnnotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (match_module, annotation) = match s.strip_prefix("module:") {
+            Some(annotation) => (true, annotation),
+            None => (false, s),
+        };
+        let (regex, suffix) = annotation
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected RE=SUFFIX, got: {}", s))?;
+        let regex = Regex::new(regex).map_err(|e| format!("invalid regex {}: {}", regex, e))?;
+        Ok(RegexAnnotation {
+            regex,
+            match_module,
+            suffix: suffix.to_string(),
+        })
+    }
+}
+
+/// A callback that may modify the frames of every stack; see [`Options::stack_hook`].
+///
+/// The callback receives the frames of a stack, from the root to the leaf, along with the process
+/// name (including the PID and TID, if requested). It runs once for every sample, on the hot path,
+/// so it should be cheap. Since samples may be processed on several threads at once, it must be
+/// `Fn` (rather than `FnMut`) and thread-safe.
+#[derive(Clone)]
+pub struct StackHook(Arc<StackHookFn>);
+
+type StackHookFn = dyn Fn(&mut Vec<String>, &str) + Send + Sync;
+
+impl StackHook {
+    /// Wraps the given callback.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&mut Vec<String>, &str) + Send + Sync + 'static,
+    {
+        StackHook(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for StackHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StackHook(..)")
+    }
+}
+
+/// A parser for an input format other than `perf script` output; see [`Options::custom_parser`].
+///
+/// The parser is called with every line of the input, in order, and reports the events and frames
+/// it finds to the given [`PerfState`], which applies the options of the [`Folder`] to them
+/// (filtering events, annotating and tidying frames, counting stacks, etc.) just as it does for
+/// `perf script` output.
+///
+/// An event is started with [`PerfState::begin_event`], followed by its frames, leaf first, with
+/// [`PerfState::frame`], and is counted when [`PerfState::end_event`] is called. The parser must
+/// end every event before it starts the next one, as starting an event does not end the current
+/// one; only an event that is still open at the end of the input is ended implicitly. Lines in
+/// `perf script` format can be passed on to [`PerfState::event_line`] and
+/// [`PerfState::stack_line`], which is what [`PerfScriptParser`] does.
+pub trait LineParser: Send {
+    /// Parses a line of the input, without its line ending.
+    fn parse(&mut self, line: &str, state: &mut PerfState<'_>);
+}
+
+/// The built-in [`LineParser`] for `perf script` output.
+///
+/// Events are separated by empty lines, and comments are expected to have been skipped already,
+/// as [`Folder`] does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PerfScriptParser;
+
+impl LineParser for PerfScriptParser {
+    fn parse(&mut self, line: &str, state: &mut PerfState<'_>) {
+        if line.is_empty() {
+            state.end_event();
+        } else if state.in_event() {
+            state.stack_line(line);
+        } else {
+            state.event_line(line);
+        }
+    }
+}
+
+/// A [`LineParser`] to use in [`Options::custom_parser`].
+///
+/// The parser is shared by all clones of the options, and of any [`Folder`] created from them.
+#[derive(Clone)]
+pub struct CustomParser(Arc<Mutex<dyn LineParser>>);
+
+impl CustomParser {
+    /// Wraps the given parser.
+    pub fn new<P>(parser: P) -> Self
+    where
+        P: LineParser + 'static,
+    {
+        CustomParser(Arc::new(Mutex::new(parser)))
+    }
+}
+
+impl fmt::Debug for CustomParser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomParser(..)")
+    }
+}
+
+/// The state of a [`Folder`] that a [`LineParser`] reports events and frames to.
+pub struct PerfState<'a> {
+    folder: &'a mut Folder,
+    occurrences: &'a mut Occurrences,
+    line: &'a str,
+}
+
+impl PerfState<'_> {
+    /// Returns the number of the line being parsed, counting from 1.
+    pub fn line_number(&self) -> usize {
+        self.folder.line_number
+    }
+
+    /// Returns whether an event has been started and not yet ended.
+    pub fn in_event(&self) -> bool {
+        self.folder.in_event
+    }
+
+    /// Starts a new event with the given metadata.
+    ///
+    /// The event is subject to `event_filter`, `time_start` and `time_end`, and the other options
+    /// that select events, which may skip it; its frames are then ignored. `period` is only used
+    /// to weight the event if `weight_from_header` found the period of events of its `name` to
+    /// have been recorded; see [`PerfState::set_weight`] to weight events otherwise.
+    pub fn begin_event(&mut self, event: &Event<'_>) {
+        self.folder.in_event = true;
+        self.folder.on_event(event, None, self.line);
+    }
+
+    /// Sets the weight of the current event, i.e. what it adds to the count of its stack.
+    pub fn set_weight(&mut self, weight: usize) {
+        self.folder.weight = weight;
+    }
+
+    /// Adds a frame to the current event, with its program counter, symbol (with or without an
+    /// offset like `+0x1f`), and module, e.g. `ffffffff8103ce3b`, `native_safe_halt`, and
+    /// `[kernel.kallsyms]`. Frames are added leaf first, and ignored outside of an event.
+    pub fn frame(&mut self, pc: &str, func: &str, module: &str) {
+        if self.folder.in_event {
+            self.folder.on_frame(self.line, Some((pc, func, module)));
+        }
+    }
+
+    /// Ends the current event, and counts its stack.
+    pub fn end_event(&mut self) {
+        self.folder.after_event(self.occurrences);
+    }
+
+    /// Starts a new event from a `perf script` event line. If the line also includes the only
+    /// frame of the event, the event is ended right away.
+    pub fn event_line(&mut self, line: &str) {
+        self.folder.on_event_line(line);
+        if !self.folder.stack.is_empty() || (self.folder.process_event && !self.folder.in_event) {
+            // we must have hit a combined event/stack line
+            self.end_event();
+        }
+    }
+
+    /// Adds a frame to the current event from a `perf script` stack line.
+    pub fn stack_line(&mut self, line: &str) {
+        self.folder.on_stack_line(line);
+    }
+}
+
+/// A mapping from process names to categories; see [`Options::category_map`].
+///
+/// Can be parsed from a string with one `RE=CATEGORY` mapping per line, where `RE` is matched
+/// against the process name. Processes get the category of the first mapping that matches, or
+/// `default` if none do. As with [`RegexAnnotation`], the category is everything after the last
+/// `=`. Blank lines and lines starting with `#` are ignored, and any `;` in a category is
+/// replaced with `:`, since `;` separates frames.
+#[derive(Clone, Debug)]
+pub struct CategoryMap {
+    /// The regular expressions to match process names against, with their categories.
+    pub categories: Vec<(Regex, String)>,
+
+    /// The category of processes that match none of `categories`; `uncategorized` when parsed.
+    pub default: String,
+}
+
+impl CategoryMap {
+    fn category(&self, comm: &str) -> &str {
+        self.categories
+            .iter()
+            .find(|(regex, _)| regex.is_match(comm))
+            .map_or(&self.default, |(_, category)| category)
+    }
+}
+
+impl FromStr for CategoryMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut categories = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (regex, category) = line
+                .rsplit_once('=')
+                .ok_or_else(|| format!("line {}: expected RE=CATEGORY, got: {}", i + 1, line))?;
+            let regex = Regex::new(regex)
+                .map_err(|e| format!("line {}: invalid regex {}: {}", i + 1, regex, e))?;
+            categories.push((regex, category.replace(';', ":")));
+        }
+        Ok(CategoryMap {
+            categories,
+            default: "uncategorized".to_string(),
+        })
+    }
+}
+
+/// A mapping from addresses to symbol names; see [`Options::symbol_map`].
+///
+/// Can be parsed from a string with one `0xADDR NAME` mapping per line, where `ADDR` is the
+/// address in hexadecimal, and `NAME` is everything after the first whitespace that follows it.
+/// Blank lines and lines starting with `#` are ignored, and any `;` in a name is replaced with
+/// `:`, since `;` separates frames.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolMap {
+    /// The symbol names, by address.
+    pub symbols: HashMap<u64, String>,
+}
+
+impl SymbolMap {
+    fn symbol(&self, pc: &str) -> Option<&str> {
+        let pc = pc.strip_prefix("0x").unwrap_or(pc);
+        let addr = u64::from_str_radix(pc, 16).ok()?;
+        self.symbols.get(&addr).map(String::as_str)
+    }
+}
+
+impl FromStr for SymbolMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut symbols = HashMap::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (addr, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: expected 0xADDR NAME, got: {}", i + 1, line))?;
+            let addr = addr
+                .strip_prefix("0x")
+                .and_then(|addr| u64::from_str_radix(addr, 16).ok())
+                .ok_or_else(|| format!("line {}: invalid address: {}", i + 1, addr))?;
+            symbols.insert(addr, name.trim_start().replace(';', ":"));
+        }
+        Ok(SymbolMap { symbols })
+    }
+}
+
+/// Statistics about the events collapsed by a [`Folder`]; see [`Options::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The number of events that were counted.
+    pub nevents: usize,
+
+    /// The longest run of consecutive events with identical stacks, as the number of events in
+    /// it and their folded stack, if `Options::runs` is set and any events were counted. Of
+    /// several runs of the same length, this is the first.
+    pub longest_run: Option<(usize, String)>,
+
+    /// The number of counted events by the number of frames on their stack, not counting the
+    /// process name or frames added by options like `root_frame`. This only holds one entry per
+    /// distinct depth, however many events there are; see `Stats::depth_percentile`.
+    pub depths: BTreeMap<usize, usize>,
+
+    /// The inclusive count of every frame, by name, if `Options::debug_counts` is set. This is
+    /// the total count of the events with the frame anywhere on their stack, counting each event
+    /// once (even if the frame recurses), and weighted like the folded stacks. The process name
+    /// and frames added by options like `root_frame` are not counted.
+    pub frame_counts: HashMap<String, usize>,
+
+    /// The number of counted events whose stack has a suspicious program counter, if
+    /// `Options::validate_pcs` is set and any events were counted.
+    pub suspicious_pcs: Option<usize>,
+}
+
+impl Stats {
+    /// Returns the stack depth at the given percentile (from 0 to 100) of the counted events,
+    /// using the nearest-rank method, or `None` if no events were counted. Percentile 0 is the
+    /// smallest depth, 50 the median, and 100 the largest.
+    pub fn depth_percentile(&self, percentile: usize) -> Option<usize> {
+        let nevents: usize = self.depths.values().sum();
+        // the rank is rounded up, and at least 1
+        let rank = (percentile.min(100) * nevents).saturating_sub(1) / 100 + 1;
+        let mut seen = 0;
+        self.depths.iter().find_map(|(&depth, &count)| {
+            seen += count;
+            if seen >= rank {
+                Some(depth)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// A warning about a line of the input of a [`Folder`]; see [`Options::collect_warnings`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Warning {
+    /// The number of the line, counting from 1.
+    pub line_number: usize,
+
+    /// What is wrong with the line.
+    pub kind: WarningKind,
+
+    /// The line, without trailing whitespace.
+    pub text: String,
+}
+
+/// What a [`Warning`] is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// An event line that could not be parsed (`weird-event`).
+    WeirdEvent,
+    /// A stack line that could not be parsed (`weird-stack`).
+    WeirdStack,
+    /// A line of already folded stacks that could not be parsed, with `Options::from_folded`
+    /// (`weird-folded`).
+    WeirdFolded,
+    /// A line that was skipped as `perf` noise, or because of `Options::ignore_prefix`
+    /// (`ignored-line`).
+    IgnoredLine,
+    /// A stack line past `Options::max_stack_frames`, whose event was dropped
+    /// (`too-many-frames`).
+    TooManyFrames,
+    /// The first event line without a timestamp, where one is needed (`missing-timestamp`).
+    MissingTimestamp,
+    /// The first event line without a usable `Options::weight_column`
+    /// (`weird-weight-column`).
+    WeirdWeightColumn,
+}
+
+impl WarningKind {
+    /// Returns the name of this kind of warning, e.g. `weird-stack`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            WarningKind::WeirdEvent => "weird-event",
+            WarningKind::WeirdStack => "weird-stack",
+            WarningKind::WeirdFolded => "weird-folded",
+            WarningKind::IgnoredLine => "ignored-line",
+            WarningKind::TooManyFrames => "too-many-frames",
+            WarningKind::MissingTimestamp => "missing-timestamp",
+            WarningKind::WeirdWeightColumn => "weird-weight-column",
+        }
+    }
+}
+
+/// A line of the input of a [`Folder`], as classified by it; see [`Options::dump_lines`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DumpedLine {
+    /// The number of the line, counting from 1.
+    pub line_number: usize,
+
+    /// How the line was interpreted.
+    pub kind: LineKind,
+
+    /// The line, without trailing whitespace.
+    pub text: String,
+}
+
+/// How a [`DumpedLine`] was interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LineKind {
+    /// A line that starts an event (`event`), possibly including its only frame. This includes
+    /// lines that were expected to start an event, but could not be parsed.
+    Event,
+    /// A line with a frame of the current event (`stack`).
+    Stack,
+    /// An empty line, which ends the current event, if any (`blank`).
+    Blank,
+    /// A line that was skipped as `perf` noise, or because of `Options::ignore_prefix`
+    /// (`ignored`).
+    Ignored,
+    /// A line of already folded stacks, with `Options::from_folded` (`folded`).
+    Folded,
+}
+
+impl LineKind {
+    /// Returns the name of this kind of line, e.g. `stack`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            LineKind::Event => "event",
+            LineKind::Stack => "stack",
+            LineKind::Blank => "blank",
+            LineKind::Ignored => "ignored",
+            LineKind::Folded => "folded",
+        }
+    }
+}
+
+/// A flat profile of the events collapsed by a [`Folder`], like that of `perf report`; see
+/// [`Options::flat_profile`].
+///
+/// The process name and the frames added by options like `root_frame` are not counted as
+/// functions. Counts are weighted like those of the folded stacks, but not scaled by
+/// `scale_divisor` or `sample_rate`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FlatProfile {
+    /// The total count of the events that were counted.
+    pub total: usize,
+
+    /// The inclusive and self count of every function, by name. The inclusive count is that of
+    /// the events with the function anywhere on their stack (counting each event once, even if
+    /// the function recurses), and the self count that of the events with it as their leaf.
+    pub functions: HashMap<String, (usize, usize)>,
+}
+
+impl FlatProfile {
+    fn count(&mut self, stack: &VecDeque<String>, weight: usize) {
+        self.total += weight;
+        let mut seen = HashSet::with_capacity(stack.len());
+        for func in stack {
+            if seen.insert(func.as_str()) {
+                if let Some(counts) = self.functions.get_mut(func) {
+                    counts.0 += weight;
+                } else {
+                    self.functions.insert(func.clone(), (weight, 0));
+                }
+            }
+        }
+        if let Some(leaf) = stack.back() {
+            if let Some(counts) = self.functions.get_mut(leaf) {
+                counts.1 += weight;
+            }
+        }
+    }
+}
+
+/// The most sampled leaf frame of a process; see [`Folder::hot_leaves`].
+///
+/// Counts are weighted like those of the folded stacks, but not scaled by `scale_divisor` or
+/// `sample_rate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HotLeaf {
+    /// The process name, including its PID and TID if requested, as in the folded stacks.
+    pub process: String,
+
+    /// The leaf frame, with any annotations.
+    pub leaf: String,
+
+    /// The total count of the events of the process with this leaf.
+    pub count: usize,
+
+    /// The total count of the events of the process.
+    pub total: usize,
+}
+
+/// The `perf script -F` fields found in a capture; see [`Fields::detect`].
+///
+/// Each field is `true` if it was seen in at least one of the examined events. A lone number
+/// after the comm is taken to be the TID, as that is what `perf script` prints by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Fields {
+    /// The number of events that were examined.
+    pub nevents: usize,
+
+    /// The process name, e.g. `java`.
+    pub comm: bool,
+    /// The process ID, e.g. the `24636` in `24636/25607`.
+    pub pid: bool,
+    /// The thread ID, e.g. the `25607` in `24636/25607`.
+    pub tid: bool,
+    /// The CPU, e.g. `[000]`.
+    pub cpu: bool,
+    /// The timestamp, e.g. `94564.109216:`.
+    pub time: bool,
+    /// The sample period, e.g. the `257597` in `257597 cycles:`.
+    pub period: bool,
+    /// The event name, e.g. `cycles:`.
+    pub event: bool,
+    /// The address of each frame, e.g. `7f1e2215d058`.
+    pub ip: bool,
+    /// The symbol of each frame, e.g. `main`.
+    pub sym: bool,
+    /// The module of each frame, e.g. `(/usr/bin/java)`.
+    pub dso: bool,
+}
+
+impl Fields {
+    /// Infers which fields are present from (at most) the first `max_events` events of the
+    /// given `perf script` output.
+    pub fn detect<R>(mut reader: R, max_events: usize) -> io::Result<Self>
+    where
+        R: io::BufRead,
+    {
+        let folder = Folder::default();
+        let mut fields = Fields::default();
+        let mut in_event = false;
+        let mut line = String::new();
+        while fields.nevents < max_events || in_event {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end();
+            if line.trim_start().starts_with('#') || folder.is_ignored_line(line) {
+                continue;
+            }
+            if line.is_empty() {
+                in_event = false;
+            } else if in_event {
+                fields.on_stack_line(line);
+            } else if fields.nevents < max_events {
+                in_event = true;
+                fields.on_event_line(line);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn on_event_line(&mut self, line: &str) {
+        let (_, pid, _, end) = match Folder::event_line_parts(line) {
+            Some(parts) => parts,
+            None => return,
+        };
+        self.nevents += 1;
+        self.comm = true;
+        self.tid = true;
+        self.pid |= pid != "?";
+
+        let mut words = line[end..].split_whitespace().peekable();
+        if let Some(word) = words.peek() {
+            if word.starts_with('[') && word.ends_with(']') {
+                self.cpu = true;
+                words.next();
+            }
+        }
+        if let Some(word) = words.peek() {
+            if is_timestamp(word) {
+                self.time = true;
+                words.next();
+            }
+        }
+        for word in words.by_ref() {
+            if word.contains(':') {
+                self.event = true;
+                break;
+            } else if word.bytes().all(|b| b.is_ascii_digit()) {
+                self.period = true;
+            }
+        }
+
+        // the event line may include the only frame of the stack
+        let rest = words.collect::<Vec<_>>().join(" ");
+        if is_hex(rest.split(' ').next().unwrap_or_default()) {
+            self.on_stack_line(&rest);
+        }
+    }
+
+    fn on_stack_line(&mut self, line: &str) {
+        let line = line.trim();
+        let first = line.split(' ').next().unwrap_or_default();
+        let line = if is_hex(first) {
+            self.ip = true;
+            &line[first.len()..]
+        } else {
+            line
+        };
+
+        // parse the rest with a dummy address, so that frames without one can be handled too
+        match Folder::stack_line_parts(&format!("0  {}", line.trim())) {
+            Some((_, rawfunc, _)) => {
+                self.sym |= !rawfunc.trim().is_empty();
+                self.dso = true;
+            }
+            None => self.sym |= !line.trim().is_empty(),
+        }
+    }
+}
+
+/// The metadata of an event in `perf script` output; see [`EventSink`].
+///
+/// Fields that are missing from the event line are `None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Event<'a> {
+    /// The process name, e.g. `V8 WorkerThread`.
+    pub comm: &'a str,
+    /// The process ID, e.g. the `24636` in `24636/25607`.
+    pub pid: Option<&'a str>,
+    /// The thread ID, e.g. the `25607` in `24636/25607`.
+    pub tid: &'a str,
+    /// The CPU, e.g. the `2` in `[002]`.
+    pub cpu: Option<usize>,
+    /// The timestamp in seconds, e.g. `94564.109216`.
+    pub timestamp: Option<&'a str>,
+    /// The sample period, e.g. the `257597` in `257597 cycles:uppp:`.
+    pub period: Option<usize>,
+    /// The event name without modifiers, e.g. `cycles`.
+    pub name: Option<&'a str>,
+    /// The event as it appears on the event line, without the `:` after it, e.g. `cycles:uppp`
+    /// or `sched:sched_switch`.
+    pub full_name: Option<&'a str>,
+}
+
+impl<'a> Event<'a> {
+    /// Creates the metadata of an event of the given process name (comm) and thread ID, with
+    /// all other fields missing.
+    pub fn new(comm: &'a str, tid: &'a str) -> Self {
+        Event {
+            comm,
+            pid: None,
+            tid,
+            cpu: None,
+            timestamp: None,
+            period: None,
+            name: None,
+            full_name: None,
+        }
+    }
+}
+
+/// A consumer of the events in `perf script` output, as parsed by [`read_events`].
+///
+/// This is the extension point for aggregations that [`Folder`] doesn't support, such as
+/// histograms of samples over time, or custom groupings. [`read_events`] only parses the input,
+/// and leaves everything else (filtering events, annotating or tidying frames, counting, etc.)
+/// to the sink.
+pub trait EventSink {
+    /// Called at the start of every event.
+    fn on_event(&mut self, event: &Event<'_>);
+
+    /// Called for every frame of the current event, leaf first, with the symbol (without its
+    /// offset) and module of the frame, e.g. `native_safe_halt` and `[kernel.kallsyms]`.
+    fn on_frame(&mut self, func: &str, module: &str);
+
+    /// Called at the end of every event, after all its frames.
+    fn on_event_end(&mut self);
+}
+
+/// Parses the given `perf script` output, and passes the events in it to `sink`.
+///
+/// Comments, and the `perf` warnings that [`Folder`] skips, are skipped. Events whose event
+/// line can't be parsed are skipped too, and a warning with the number of the line is logged.
+pub fn read_events<R, S>(mut reader: R, sink: &mut S) -> io::Result<()>
+where
+    R: io::BufRead,
+    S: EventSink + ?Sized,
+{
+    let folder = Folder::default();
+    let warnings = logging::Warnings::default();
+    // whether we are in an event, and whether it was passed to the sink, respectively
+    let mut in_event = false;
+    let mut in_sink = false;
+    let mut buffer = Vec::new();
+    let mut line_number = 0;
+    loop {
+        buffer.clear();
+        if reader.read_until(0x0A, &mut buffer)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.trim_end();
+        if line.trim_start().starts_with('#') || folder.is_ignored_line(line) {
+            continue;
+        }
+
+        if line.is_empty() {
+            if in_sink {
+                sink.on_event_end();
+            }
+            in_event = false;
+            in_sink = false;
+        } else if in_event {
+            if !in_sink {
+                continue;
+            }
+            match frame_parts(line) {
+                Some((func, module)) => sink.on_frame(func, module),
+                None => logging::weird_stack_line(&warnings, line_number, line),
+            }
+        } else {
+            in_event = true;
+            match event_parts(line) {
+                Some((event, frame)) => {
+                    in_sink = true;
+                    sink.on_event(&event);
+                    if let Some((func, module)) = frame.and_then(frame_parts) {
+                        sink.on_frame(func, module);
+                    }
+                }
+                None => logging::weird_event_line(&warnings, line_number, line),
+            }
+        }
+    }
+    if in_sink {
+        sink.on_event_end();
+    }
+    Ok(())
+}
+
+/// Parses a `perf script` event line into its process name (comm), PID, and TID.
+///
+/// The PID is `?` if the line only has a TID. Returns `None` if `line` isn't an event line.
+///
+/// ```
+/// use inferno::collapse::perf::parse_event_line;
+///
+/// let line = "java 24636/25607 [000] 4794564.109216: cycles:";
+/// assert_eq!(parse_event_line(line), Some(("java", "24636", "25607")));
+/// assert_eq!(parse_event_line("java 4794564.109216: cycles:"), None);
+/// ```
+pub fn parse_event_line(line: &str) -> Option<(&str, &str, &str)> {
+    let (comm, pid, tid, _) = Folder::event_line_parts(line)?;
+    Some((comm, pid, tid))
+}
+
+/// Parses a `perf script` stack line into its program counter, symbol (including its offset, if
+/// any), and module. A missing module, as in `foo ()`, is returned as `[unknown]`.
+///
+/// Returns `None` if `line` isn't a stack line.
+///
+/// ```
+/// use inferno::collapse::perf::parse_stack_line;
+///
+/// let line = "\t7f533952bc77 _dl_check_map_versions+0x597 (/usr/lib/ld-2.28.so)";
+/// assert_eq!(
+///     parse_stack_line(line),
+///     Some(("7f533952bc77", "_dl_check_map_versions+0x597", "/usr/lib/ld-2.28.so"))
+/// );
+/// assert_eq!(parse_stack_line("7f533952bc77 foo(int)"), None);
+/// ```
+pub fn parse_stack_line(line: &str) -> Option<(&str, &str, &str)> {
+    Folder::stack_line_parts(line)
+}
+
+// parses an event line into its metadata, and the only frame of the stack if the line
+// includes it; see `Folder::on_event_line` for examples.
+fn event_parts(line: &str) -> Option<(Event<'_>, Option<&str>)> {
+    let (comm, pid, tid, end) = Folder::event_line_parts(line)?;
+    Some(event_metadata(comm, pid, tid, &line[end..]))
+}
+
+// parses the `rest` of an event line that follows the PID/TID. the event name is only taken
+// from a whole word (after the CPU and timestamp, if any) that contains a `:`, so that lines
+// without an event name, like `app 1001/1001 100.000001:`, don't yield one.
+fn event_metadata<'a>(
+    comm: &'a str,
+    pid: &'a str,
+    tid: &'a str,
+    mut rest: &'a str,
+) -> (Event<'a>, Option<&'a str>) {
+    let mut event = Event {
+        comm,
+        pid: if pid == "?" { None } else { Some(pid) },
+        tid,
+        cpu: event_cpu(rest),
+        timestamp: None,
+        period: None,
+        name: None,
+        full_name: None,
+    };
+
+    // walk the words like `Fields::on_event_line` does, but keep track of what follows them
+    let mut word = next_word(&mut rest);
+    if event.cpu.is_some() {
+        word = next_word(&mut rest);
+    }
+    if is_timestamp(word) {
+        event.timestamp = Some(&word[..(word.len() - 1)]);
+        word = next_word(&mut rest);
+    }
+    while !word.is_empty() {
+        if word.contains(':') {
+            event.name = word.split(':').next().filter(|name| !name.is_empty());
+            if event.name.is_some() {
+                event.full_name = Some(word.strip_suffix(':').unwrap_or(word));
+            }
+            break;
+        } else if let Ok(period) = word.parse() {
+            event.period = Some(period);
+        }
+        word = next_word(&mut rest);
+    }
+
+    let rest = rest.trim();
+    let frame = if is_hex(rest.split(' ').next().unwrap_or_default()) {
+        Some(rest)
+    } else {
+        None
+    };
+    (event, frame)
+}
 
-    /// Include TID and PID in the root frame. Implies `include_pid`.
-    ///
-    /// Default is `false`.
-    pub include_tid: bool,
+// Parses an event description of the `perf script --header` metadata, like (without the `#`):
+//
+//     # event : name = cycles:u, , id = { 11, 12 }, size = 120, { sample_period, sample_freq } = 4000, sample_type = IP|TID|TIME|CALLCHAIN|PERIOD, ...
+//
+// into `Some(("cycles", "cycles"))`, the name of the event as it appears on event lines and the
+// unit of its period, if the period of the event was recorded.
+fn header_period(comment: &str) -> Option<(String, String)> {
+    let description = comment.trim_start().strip_prefix("event")?;
+    let description = description.trim_start().strip_prefix(':')?;
+    let field = |key: &str| {
+        description
+            .split(", ")
+            .find_map(|field| {
+                field
+                    .trim()
+                    .strip_prefix(key)?
+                    .trim_start()
+                    .strip_prefix('=')
+            })
+            .map(str::trim)
+    };
+    let name = field("name")?;
+    if !field("sample_type")?.split('|').any(|ty| ty == "PERIOD") {
+        return None;
+    }
 
-    /// The number of threads to use.
-    ///
-    /// Default is the number of logical cores on your machine.
-    pub nthreads: usize,
+    // event lines only show the name up to the first `:`, like the `cycles` of `cycles:u`
+    let (event, modifiers) = name.split_once(':').unwrap_or((name, ""));
+    let unit = match event {
+        "cpu-clock" | "task-clock" => "nanoseconds",
+        "cycles" | "cpu-cycles" | "ref-cycles" | "bus-cycles" => "cycles",
+        // the period of a tracepoint, like `sched:sched_switch`, is the number of times it was hit
+        _ if !modifiers.bytes().all(|b| b"ukhIGHpPSDWe".contains(&b)) => "events",
+        _ => event,
+    };
+    Some((event.to_string(), unit.to_string()))
+}
 
-    /// If a stack function name is equal to any of the specified strings it will omit all the
-    /// following stackframes for that event.
-    /// In case no function is matched the whole stack is returned.
-    /// Default is not omitting any.
-    pub skip_after: Vec<String>,
+// splits the first whitespace-delimited word off `rest`, or returns "" if there is none
+fn next_word<'a>(rest: &mut &'a str) -> &'a str {
+    let trimmed = rest.trim_start();
+    let word_len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    let (word, after) = trimmed.split_at(word_len);
+    *rest = after;
+    word
 }
 
-impl Default for Options {
-    fn default() -> Self {
-        Self {
-            annotate_jit: false,
-            annotate_kernel: false,
-            event_filter: None,
-            include_addrs: false,
-            include_pid: false,
-            include_tid: false,
-            nthreads: *common::DEFAULT_NTHREADS,
-            skip_after: Vec::default(),
+// parses a stack line into its symbol (without the offset) and module
+fn frame_parts(line: &str) -> Option<(&str, &str)> {
+    let (_, mut func, module) = Folder::stack_line_parts(line)?;
+    if let Some(offset) = func.rfind("+0x") {
+        if func[(offset + 3)..].bytes().all(|b| b.is_ascii_hexdigit()) {
+            func = &func[..offset];
         }
     }
+    Some((func, module))
 }
 
 /// A stack collapser for the output of `perf script`.
 ///
+/// Stack lines may be indented with any mix of tabs and spaces, and events are separated by
+/// lines that are empty or contain only whitespace.
+///
 /// To construct one, either use `perf::Folder::default()` or create an [`Options`] and use
-/// `perf::Folder::from(options)`.
+/// `perf::Folder::from(options)`. To aggregate the events in other ways, see [`EventSink`].
 pub struct Folder {
     // State...
     /// General String cache that can be used while processing lines. Currently only used to keep
     /// track of functions for Java inlining.
     cache_line: Vec<String>,
 
+    /// The first-seen casing of every frame name, by its lowercase form; see
+    /// `Options::case_fold_frames`.
+    casings: HashMap<String, String>,
+
+    /// The category of the current event's process; see `Options::category_map`.
+    category: String,
+
+    /// The warnings collected from the most recently collapsed input; see
+    /// `Options::collect_warnings`.
+    collected_warnings: Vec<Warning>,
+
+    /// The frame from the latest marker comment, if any; see `Options::comment_prefix`.
+    comment_marker: String,
+
+    /// The first lines of the most recently collapsed input, as classified; see
+    /// `Options::dump_lines`.
+    dumped_lines: Vec<DumpedLine>,
+
+    /// The index in `Options::annotate_event` of the annotation of the current event, if any.
+    event_annotation: Option<usize>,
+
     /// Similar to, but different from, the `event_filter` field on `Options`
     ///
     /// * Field on `Options` represents user's provided configuration and will never change.
@@ -113,23 +2018,116 @@ pub struct Folder {
     ///   different event types, such as instructions and cycles, would produce misleading results.
     event_filter: Option<String>,
 
+    /// The flat profile gathered thus far; see `Options::flat_profile`.
+    flat_profile: FlatProfile,
+
+    /// Whether the most rootward frame on `stack` is a closure (or a GCC clone) that was folded
+    /// into the name of its enclosing (or base) function; see `Options::fold_closures` and
+    /// `Options::fold_gcc_clones`.
+    folded_closure: bool,
+
+    /// Whether the most rootward frame on `stack` stands for a run of frames from a module
+    /// matching `Options::fold_module`.
+    folded_module: bool,
+
+    /// The unit of the period of every event whose period was recorded, by event name, as
+    /// found in the `perf script --header` metadata; see `Options::weight_from_header`.
+    header_periods: HashMap<String, String>,
+
+    /// The total count of every process, and the counts of its leaf frames, by process name; see
+    /// `Options::hot_leaves`.
+    hot_leaves: HashMap<String, (usize, HashMap<String, usize>)>,
+
     /// All lines until the next empty line are stack lines.
     in_event: bool,
 
+    /// The domain of the first (i.e., leaf) frame in this event, if any.
+    leaf_domain: Option<Domain>,
+
+    /// The number of the input line being processed, counting from 1.
+    line_number: usize,
+
+    /// The number of input lines consumed by `pre_process`, which precede every chunk of input
+    /// that a worker thread is given.
+    line_offset: usize,
+
+    /// The number of malformed event and stack lines seen thus far, shared with the worker
+    /// threads; see `Options::max_malformed`.
+    malformed: Arc<AtomicUsize>,
+
+    /// The number of events counted thus far; see `Options::limit_events`.
+    nevents: usize,
+
+    /// The number of frames seen thus far, shared with the worker threads; see
+    /// `Options::max_unknown_ratio`.
+    nframes: Arc<AtomicUsize>,
+
+    /// The NUMA node frame of the current event; see `Options::cpus_per_node`.
+    node: String,
+
     /// The number of stacks per job to send to the threadpool.
     nstacks_per_job: usize,
 
+    /// The domain of the last (i.e., most rootward) frame pushed onto `stack`, if any.
+    outer_domain: Option<Domain>,
+
     /// Current comm name.
     ///
     /// Called pname after original stackcollapse-perf source.
     pname: String,
 
+    /// Whether the current event is counted for its process alone; see `Options::process_only`.
+    process_event: bool,
+
+    /// The length and folded stack of the current run of identical stacks; see `Options::runs`.
+    run: (usize, String),
+
+    /// The number of events that passed the event filter since (and including) the last one that
+    /// was processed, modulo `Options::sample_rate`.
+    sampled_events: usize,
+
     /// Whether to skip stack lines in this event.
     stack_filter: StackFilter,
 
     /// Function entries on the stack in this entry thus far.
     stack: VecDeque<String>,
 
+    /// The statistics gathered thus far; see `Options::stats`.
+    stats: Stats,
+
+    /// The first line that could not be parsed, to fail with; see `Options::strict`.
+    strict_error: Option<String>,
+
+    /// Whether the current event has a suspicious program counter; see `Options::validate_pcs`.
+    suspicious_pcs: bool,
+
+    /// The timestamp of the last event of every thread, and the generation of the thread, by TID;
+    /// see `Options::group_by_tid_lifetime`.
+    tid_generations: HashMap<String, (f64, usize)>,
+
+    /// The timestamp of the current event, if any; see `Options::timeline`.
+    timestamp: String,
+
+    /// The number of frames with an unknown symbol seen thus far, shared with the worker
+    /// threads; see `Options::max_unknown_ratio`.
+    unknown_frames: Arc<AtomicUsize>,
+
+    /// Whether we have already warned about an event line without a timestamp.
+    warned_timestamp: bool,
+
+    /// Whether we have already warned about an event line without a usable weight column.
+    warned_weight_column: bool,
+
+    /// Where to log warnings about the input; see `Options::deterministic`.
+    warnings: logging::Warnings,
+
+    /// The count to add for the current event; see `Options::weight_column`.
+    weight: usize,
+
+    /// Whether the last frame of the current event was at address 0; see
+    /// `Options::validate_pcs`.
+    zero_pc: bool,
+
     // Options...
     opt: Options,
 }
@@ -139,15 +2137,82 @@ impl From<Options> for Folder {
         if opt.nthreads == 0 {
             opt.nthreads = 1;
         }
+        opt.include_tid = opt.include_tid || opt.group_by_tid_lifetime.is_some();
         opt.include_pid = opt.include_pid || opt.include_tid;
+        if let Some(ref mut root_frame) = opt.root_frame {
+            *root_frame = root_frame.replace(';', ":");
+        }
+        if let Some(ref mut tag_all) = opt.tag_all {
+            *tag_all = tag_all.replace(';', ":");
+        }
+        if opt.canonical_event {
+            if let Some(ref mut event_filter) = opt.event_filter {
+                // a glob may match modifiers on purpose
+                if !is_glob(event_filter) {
+                    event_filter.truncate(event_filter.find(':').unwrap_or(event_filter.len()));
+                }
+            }
+        }
+        if let Some(ref mut category_map) = opt.category_map {
+            category_map.default = category_map.default.replace(';', ":");
+        }
+        // the longest matching prefix is the first one
+        opt.strip_path_prefix.retain(|prefix| !prefix.is_empty());
+        opt.strip_path_prefix
+            .sort_by_key(|prefix| Reverse(prefix.len()));
+        if opt.fold_plt && opt.plt_regex.is_none() {
+            opt.plt_regex = Some(Regex::new(DEFAULT_PLT_REGEX).unwrap());
+        }
+        if opt.no_idle && opt.idle_frames.is_empty() {
+            opt.idle_frames = DEFAULT_IDLE_FRAMES.iter().map(|f| f.to_string()).collect();
+        }
         Self {
             cache_line: Vec::default(),
+            casings: HashMap::default(),
+            category: String::new(),
+            collected_warnings: Vec::new(),
+            comment_marker: String::new(),
+            dumped_lines: Vec::new(),
+            event_annotation: None,
             event_filter: opt.event_filter.clone(),
+            flat_profile: FlatProfile::default(),
+            folded_closure: false,
+            folded_module: false,
+            header_periods: HashMap::default(),
+            hot_leaves: HashMap::default(),
             in_event: false,
+            leaf_domain: None,
+            line_number: 0,
+            line_offset: 0,
+            malformed: Arc::default(),
+            nevents: 0,
+            nframes: Arc::default(),
+            node: String::new(),
             nstacks_per_job: common::DEFAULT_NSTACKS_PER_JOB,
+            outer_domain: None,
             pname: String::default(),
+            process_event: false,
+            run: (0, String::new()),
+            sampled_events: 0,
             stack_filter: StackFilter::Keep,
             stack: VecDeque::default(),
+            stats: Stats::default(),
+            strict_error: None,
+            suspicious_pcs: false,
+            tid_generations: HashMap::default(),
+            timestamp: String::new(),
+            unknown_frames: Arc::default(),
+            warned_timestamp: false,
+            warned_weight_column: false,
+            warnings: if opt.collect_warnings {
+                logging::Warnings::collected()
+            } else if opt.deterministic {
+                logging::Warnings::deferred()
+            } else {
+                logging::Warnings::default()
+            },
+            weight: 1,
+            zero_pc: false,
             opt,
         }
     }
@@ -164,28 +2229,126 @@ impl CollapsePrivate for Folder {
     where
         R: io::BufRead,
     {
-        // If user has provided an event filter, do nothing...
-        if self.event_filter.is_some() {
+        self.casings.clear();
+        self.collected_warnings.clear();
+        self.comment_marker.clear();
+        self.dumped_lines.clear();
+        self.flat_profile = FlatProfile::default();
+        self.header_periods.clear();
+        self.hot_leaves.clear();
+        self.line_number = 0;
+        self.malformed.store(0, Ordering::Relaxed);
+        self.nevents = 0;
+        self.nframes.store(0, Ordering::Relaxed);
+        self.run = (0, String::new());
+        self.stats = Stats::default();
+        self.strict_error = None;
+        self.tid_generations.clear();
+        self.unknown_frames.store(0, Ordering::Relaxed);
+
+        // If user has provided an event filter (or there are no events), do nothing...
+        // unless the worker threads also need the header metadata that precedes the first stack.
+        if (self.event_filter.is_some() && !self.opt.weight_from_header) || self.opt.from_folded {
             return Ok(());
         }
 
         // Otherwise, we don't know what the event filter should be; so process
         // the first stack to figure it out (the worker threads need this
-        // information to get started). Only read one stack, however, as we would
-        // like the remaining stacks to be processed on the worker threads.
+        // information to get started). Only read up to the first stack, however, as we
+        // would like the remaining stacks to be processed on the worker threads.
+        //
+        // Note that the first "stack" may turn out to have been nothing but ignored
+        // noise lines followed by an empty line, in which case we keep going.
         let mut line_buffer = Vec::new();
-        let eof = self.process_single_stack(&mut line_buffer, reader, occurrences)?;
+        loop {
+            let eof = self.process_single_stack(&mut line_buffer, reader, occurrences)?;
+            if eof || self.event_filter.is_some() {
+                // If we hit EOF, it may be that the input was completely empty.
+                break;
+            }
+        }
 
-        if eof {
-            // If we hit EOF, it may be that the input was completely empty.
-            // In that case, we don't do the event_filter assertion below.
-            return Ok(());
+        if let (Some(event), Some(count_name)) = (&self.event_filter, self.count_name()) {
+            logging::weighting_by_period(event, count_name);
+        }
+
+        Ok(())
+    }
+
+    fn new_occurrences(&self) -> Occurrences {
+        if self.opt.timeline {
+            return Occurrences::new_timeline();
+        }
+        match self.opt.topk {
+            Some(topk) if topk != 0 => Occurrences::new_bounded(topk),
+            _ if self.opt.sort_by_appearance => Occurrences::new_by_appearance(),
+            // events are picked by a running counter, marked by the comments before them,
+            // reported by a custom parser, counted in the statistics, the flat profile and the hot
+            // leaves,
+            // assigned to thread generations, and limited in number, and frames are named as they
+            // were first seen, and the first lines are dumped, and the first malformed line is
+            // reported by strict collapsing, all of which need to see all events in order
+            _ if self.sample_rate() > 1
+                || self.opt.comment_prefix.is_some()
+                || self.opt.custom_parser.is_some()
+                || matches!(self.opt.dump_lines, Some(n) if n != 0)
+                || self.opt.stats
+                || self.opt.strict
+                || self.opt.flat_profile
+                || self.opt.hot_leaves
+                || self.opt.case_fold_frames
+                || self.opt.group_by_tid_lifetime.is_some()
+                || self.event_limit().is_some() =>
+            {
+                Occurrences::new(1)
+            }
+            _ => Occurrences::new(self.nthreads()),
+        }
+    }
+
+    fn post_process(&mut self, occurrences: &mut Occurrences) -> io::Result<()> {
+        self.collected_warnings = self.warnings.flush();
+
+        if let Some(max_malformed) = self.opt.max_malformed {
+            let malformed = self.malformed.load(Ordering::Relaxed);
+            if malformed > max_malformed {
+                return invalid_data_error!(
+                    "{} event or stack lines could not be parsed, more than the maximum of {}",
+                    malformed,
+                    max_malformed
+                );
+            }
         }
 
-        // If we didn't find an event filter, there is something wrong with
-        // our processing code.
-        assert!(self.event_filter.is_some());
+        if let Some(max_unknown_ratio) = self.opt.max_unknown_ratio {
+            let nframes = self.nframes.load(Ordering::Relaxed);
+            let unknown_frames = self.unknown_frames.load(Ordering::Relaxed);
+            if nframes != 0 && unknown_frames as f64 / nframes as f64 > max_unknown_ratio {
+                return invalid_data_error!(
+                    "{} of {} frames have an unknown symbol, more than the maximum ratio of {}",
+                    unknown_frames,
+                    nframes,
+                    max_unknown_ratio
+                );
+            }
+        }
+
+        let multiplier = self.sample_rate();
+        let divisor = match self.opt.scale_divisor {
+            Some(divisor) if divisor != 0 => divisor,
+            _ => 1,
+        };
+        if multiplier != 1 || divisor != 1 {
+            occurrences.scale_counts(multiplier, divisor);
+        }
 
+        if let (Some(min_width_pct), false) = (self.opt.min_width_pct, self.opt.timeline) {
+            let min_count = occurrences.total() as f64 * min_width_pct / 100.0;
+            let dropped = occurrences.retain_counts(|count| count as f64 >= min_count);
+            if self.opt.min_width_other && dropped != 0 {
+                occurrences.insert_or_add(OTHER_STACK.to_string(), dropped);
+            }
+        }
         Ok(())
     }
 
@@ -203,8 +2366,15 @@ impl CollapsePrivate for Folder {
 
         // Reset state...
         self.in_event = false;
+        self.leaf_domain = None;
+        self.folded_closure = false;
+        self.folded_module = false;
+        self.outer_domain = None;
+        self.process_event = false;
+        self.sampled_events = 0;
         self.stack_filter = StackFilter::Keep;
         self.stack.clear();
+        self.timestamp.clear();
         Ok(())
     }
 
@@ -226,7 +2396,7 @@ impl CollapsePrivate for Folder {
 
             let line = line.trim();
             // Skip comments
-            if line.starts_with('#') {
+            if line.starts_with('#') || self.is_ignored_line(line) {
                 continue;
             }
 
@@ -250,22 +2420,64 @@ impl CollapsePrivate for Folder {
     }
 
     fn would_end_stack(&mut self, line: &[u8]) -> bool {
-        line.iter().all(|b| (*b as char).is_whitespace())
+        if self.opt.strip_ansi && line.contains(&ESC) {
+            let mut line = line.to_vec();
+            strip_ansi_escapes(&mut line);
+            return self.would_end_stack(&line);
+        }
+        self.opt.from_folded || line.iter().all(|b| (*b as char).is_whitespace())
     }
 
     fn clone_and_reset_stack_context(&self) -> Self {
         Self {
             cache_line: self.cache_line.clone(),
+            casings: self.casings.clone(),
+            category: String::new(),
+            collected_warnings: Vec::new(),
+            comment_marker: String::new(),
+            dumped_lines: Vec::new(),
+            event_annotation: None,
             event_filter: self.event_filter.clone(),
+            flat_profile: FlatProfile::default(),
+            folded_closure: false,
+            folded_module: false,
+            header_periods: self.header_periods.clone(),
+            hot_leaves: HashMap::default(),
             in_event: false,
+            leaf_domain: None,
+            line_number: self.line_number,
+            line_offset: self.line_number,
+            malformed: Arc::clone(&self.malformed),
+            nevents: 0,
+            nframes: Arc::clone(&self.nframes),
+            node: String::new(),
             nstacks_per_job: self.nstacks_per_job,
+            outer_domain: None,
             pname: String::new(),
+            process_event: false,
+            run: (0, String::new()),
+            sampled_events: 0,
             stack_filter: StackFilter::Keep,
             stack: VecDeque::default(),
+            stats: Stats::default(),
+            strict_error: None,
+            suspicious_pcs: false,
+            tid_generations: HashMap::default(),
+            timestamp: String::new(),
+            unknown_frames: Arc::clone(&self.unknown_frames),
+            warned_timestamp: self.warned_timestamp,
+            warned_weight_column: self.warned_weight_column,
+            warnings: self.warnings.clone(),
+            weight: 1,
+            zero_pc: false,
             opt: self.opt.clone(),
         }
     }
 
+    fn start_chunk(&mut self, nlines_before: usize) {
+        self.line_number = self.line_offset + nlines_before;
+    }
+
     fn nstacks_per_job(&self) -> usize {
         self.nstacks_per_job
     }
@@ -284,6 +2496,72 @@ impl CollapsePrivate for Folder {
 }
 
 impl Folder {
+    /// Returns the statistics about the events of the most recently collapsed input. These are
+    /// only gathered if `Options::stats` is set.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the warnings about the lines of the most recently collapsed input, by line
+    /// number. These are only collected if `Options::collect_warnings` is set.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.collected_warnings
+    }
+
+    /// Returns the first lines of the most recently collapsed input, by line number, along with
+    /// how they were classified. These are only recorded if `Options::dump_lines` is set.
+    pub fn dumped_lines(&self) -> &[DumpedLine] {
+        &self.dumped_lines
+    }
+
+    /// Returns the flat profile of the most recently collapsed input. This is only gathered if
+    /// `Options::flat_profile` is set.
+    pub fn flat_profile(&self) -> &FlatProfile {
+        &self.flat_profile
+    }
+
+    /// Returns the most sampled leaf frame of every process in the most recently collapsed input,
+    /// ordered by process name. Ties are broken by frame name, so the result is stable. This is
+    /// only gathered if `Options::hot_leaves` is set.
+    pub fn hot_leaves(&self) -> Vec<HotLeaf> {
+        let mut hot_leaves: Vec<HotLeaf> = self
+            .hot_leaves
+            .iter()
+            .filter_map(|(process, (total, leaves))| {
+                let (leaf, count) = leaves
+                    .iter()
+                    .max_by(|(l1, c1), (l2, c2)| c1.cmp(c2).then_with(|| l2.cmp(l1)))?;
+                Some(HotLeaf {
+                    process: process.clone(),
+                    leaf: leaf.clone(),
+                    count: *count,
+                    total: *total,
+                })
+            })
+            .collect();
+        hot_leaves.sort_by(|l1, l2| l1.process.cmp(&l2.process));
+        hot_leaves
+    }
+
+    /// Returns the type of the events that were collapsed from the most recently collapsed input,
+    /// such as `cycles`: `Options::event_filter` if it is set (even if it is a glob pattern), or
+    /// the type of the first event otherwise. Returns `None` if the input had no events.
+    pub fn event(&self) -> Option<&str> {
+        self.event_filter.as_deref()
+    }
+
+    /// Returns the unit of the counts of the most recently collapsed input, such as `cycles` or
+    /// `nanoseconds`, if they were weighted by the sample period; see
+    /// `Options::weight_from_header`. Counts are numbers of samples if this returns `None`.
+    pub fn count_name(&self) -> Option<&str> {
+        if self.opt.weight_column.is_some() {
+            return None;
+        }
+        self.header_periods
+            .get(self.event_filter.as_deref()?)
+            .map(String::as_str)
+    }
+
     /// Processes a stack. On success, returns `true` if at end of data; `false` otherwise.
     fn process_single_stack<R>(
         &mut self,
@@ -294,68 +2572,190 @@ impl Folder {
     where
         R: io::BufRead,
     {
+        let custom_parser = self.opt.custom_parser.clone();
+        let mut custom_parser = custom_parser
+            .as_ref()
+            .map(|parser| parser.0.lock().unwrap());
         loop {
+            if let Some(error) = self.strict_error.take() {
+                return invalid_data_error!("{}", error);
+            }
+            if matches!(self.event_limit(), Some(limit) if self.nevents >= limit) {
+                // stop early, as if the input ended here
+                return Ok(true);
+            }
             line_buffer.clear();
             if reader.read_until(0x0A, line_buffer)? == 0 {
-                if !self.stack.is_empty() {
+                if self.process_event || !self.stack.is_empty() {
                     self.after_event(occurrences);
                 }
+                if let Some(error) = self.strict_error.take() {
+                    return invalid_data_error!("{}", error);
+                }
                 return Ok(true);
             }
+            self.line_number += 1;
+            if self.opt.strip_ansi {
+                strip_ansi_escapes(line_buffer);
+            }
             let line = String::from_utf8_lossy(line_buffer);
-            if line.starts_with('#') {
+            if let Some(ref mut parser) = custom_parser {
+                let line = line.trim_end();
+                if self.is_ignored_line(line) {
+                    logging::ignoring_line(&self.warnings, self.line_number, line);
+                } else {
+                    let mut state = PerfState {
+                        folder: self,
+                        occurrences,
+                        line,
+                    };
+                    parser.parse(line, &mut state);
+                }
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                if self.opt.weight_from_header {
+                    if let Some((event, unit)) = header_period(comment) {
+                        self.header_periods.insert(event, unit);
+                    }
+                }
+                if let Some(ref prefix) = self.opt.comment_prefix {
+                    if let Some(marker) = comment.trim_start().strip_prefix(prefix.as_str()) {
+                        self.comment_marker.clear();
+                        self.comment_marker
+                            .push_str(&marker.trim().replace(';', ":"));
+                    }
+                }
                 continue;
             }
             let line = line.trim_end();
+            if self.is_ignored_line(line) {
+                self.dump_line(LineKind::Ignored, line);
+                logging::ignoring_line(&self.warnings, self.line_number, line);
+                continue;
+            }
+            if self.opt.dump_lines.is_some() {
+                let kind = if self.opt.from_folded {
+                    LineKind::Folded
+                } else if line.is_empty() {
+                    LineKind::Blank
+                } else if self.in_event {
+                    LineKind::Stack
+                } else {
+                    LineKind::Event
+                };
+                self.dump_line(kind, line);
+            }
+            if self.opt.from_folded {
+                if !line.is_empty() {
+                    self.on_folded_line(line, occurrences);
+                }
+                return Ok(false);
+            }
+
+            let mut state = PerfState {
+                folder: self,
+                occurrences,
+                line,
+            };
+            PerfScriptParser.parse(line, &mut state);
             if line.is_empty() {
-                self.after_event(occurrences);
                 return Ok(false);
-            } else if self.in_event {
-                self.on_stack_line(line);
-            } else {
-                assert!(self.stack.is_empty());
-                self.on_event_line(line);
-                if !self.stack.is_empty() {
-                    // we must have hit a combined event/stack line
-                    self.after_event(occurrences);
-                }
             }
         }
     }
 
+    /// Records how the current line was classified, if it is among the first
+    /// `Options::dump_lines` lines.
+    fn dump_line(&mut self, kind: LineKind, line: &str) {
+        if matches!(self.opt.dump_lines, Some(n) if self.dumped_lines.len() < n) {
+            self.dumped_lines.push(DumpedLine {
+                line_number: self.line_number,
+                kind,
+                text: line.to_string(),
+            });
+        }
+    }
+
+    /// Returns `true` if the line is `perf` (or user-specified) noise rather than sample data.
+    fn is_ignored_line(&self, line: &str) -> bool {
+        let line = line.trim_start();
+        PERF_NOISE_PREFIXES
+            .iter()
+            .any(|prefix| line.starts_with(prefix))
+            || PERF_NOISE_SUFFIXES
+                .iter()
+                .any(|suffix| line.ends_with(suffix))
+            || self
+                .opt
+                .ignore_prefix
+                .iter()
+                .any(|prefix| line.starts_with(prefix.as_str()))
+    }
+
     fn event_line_parts(line: &str) -> Option<(&str, &str, &str, usize)> {
-        let mut word_start = 0;
-        let mut all_digits = false;
-        let mut last_was_space = false;
-        let mut contains_slash_at = None;
+        // If the line has a timestamp, anchor on it: the PID/TID is the word just before it
+        // (or before the CPU, if present), and the comm is everything before that. This way,
+        // comms that contain all-digit words or colons (e.g. `worker: 2`) can't throw us off.
+        if let Some(parts) = Self::event_line_parts_by_timestamp(line) {
+            return Some(parts);
+        }
+
+        // Otherwise, the PID/TID is the first PID-like word after the comm. Words are only
+        // accepted whole, so cgroup-style comms like `user-1000.slice/session-2.scope` or
+        // `/1000` can't be mistaken for one.
+        let mut word_start = None;
         for (idx, c) in line.char_indices() {
-            if c == ' ' {
-                if all_digits && !last_was_space {
-                    // found an all-digit word
-                    let (pid, tid) = if let Some(slash) = contains_slash_at {
-                        // found PID + TID
-                        (&line[word_start..slash], &line[(slash + 1)..idx])
-                    } else {
-                        // found TID
-                        ("?", &line[word_start..idx])
-                    };
-                    // also trim comm in case multiple spaces were used to separate
-                    let comm = line[..(word_start - 1)].trim();
-                    return Some((comm, pid, tid, idx + 1));
-                }
-                word_start = idx + 1;
-                all_digits = true;
-            } else if c == '/' {
-                if all_digits {
-                    contains_slash_at = Some(idx);
-                }
-            } else if c.is_ascii_digit() {
-                // we're still all digits if we were all digits
-            } else {
-                all_digits = false;
-                contains_slash_at = None;
+            if c != ' ' {
+                word_start.get_or_insert(idx);
+                continue;
+            }
+            let start = match word_start.take() {
+                Some(start) => start,
+                None => continue,
+            };
+            // also trim comm in case multiple spaces were used to separate
+            let comm = line[..start].trim();
+            if comm.is_empty() {
+                continue;
+            }
+            if let Some((pid, tid)) = pid_and_tid(&line[start..idx]) {
+                let (comm, pid) = without_leading_pid(comm, pid);
+                return Some((comm, pid, tid, idx + 1));
+            }
+        }
+        None
+    }
+
+    fn event_line_parts_by_timestamp(line: &str) -> Option<(&str, &str, &str, usize)> {
+        // the (start, end) of the two words preceding the current one
+        let mut prev_words: [Option<(usize, usize)>; 2] = [None, None];
+        let mut word_start = None;
+        for (idx, c) in line.char_indices().chain(iter::once((line.len(), ' '))) {
+            if c != ' ' {
+                word_start.get_or_insert(idx);
+                continue;
+            }
+            let start = match word_start.take() {
+                Some(start) => start,
+                None => continue,
+            };
+
+            if is_timestamp(&line[start..idx]) {
+                let pid_word = match prev_words {
+                    [before_cpu, Some((s, e))] if line[s..e].starts_with('[') => before_cpu,
+                    [_, word] => word,
+                };
+                if let Some((s, e)) = pid_word {
+                    // the comm may be empty, as perf reports for some exiting processes
+                    let comm = line[..s].trim();
+                    if let Some((pid, tid)) = pid_and_tid(&line[s..e]) {
+                        let (comm, pid) = without_leading_pid(comm, pid);
+                        return Some((comm, pid, tid, e + 1));
+                    }
+                }
             }
-            last_was_space = c == ' ';
+            prev_words = [prev_words[1], Some((start, idx))];
         }
         None
     }
@@ -373,92 +2773,309 @@ impl Folder {
     fn on_event_line(&mut self, line: &str) {
         self.in_event = true;
 
-        if let Some((comm, pid, tid, end)) = Self::event_line_parts(line) {
-            let mut by_colons = line[end..].splitn(3, ':').skip(1);
-            let event = by_colons
-                .next()
-                .and_then(|has_event| has_event.rsplit(' ').next());
-            if let Some(event) = event {
-                if let Some(ref event_filter) = self.event_filter {
-                    if event != event_filter {
-                        self.stack_filter = StackFilter::Skip;
-                        return;
-                    }
-                } else {
-                    // By default only show events of the first encountered event type.
-                    // Merging together different types, such as instructions and cycles,
-                    // produces misleading results.
-                    logging::filtering_for_events_of_type(event);
-                    self.event_filter = Some(event.to_string());
+        if let Some((comm, pid, tid, end)) = Self::event_line_parts(line) {
+            let (metadata, _) = event_metadata(comm, pid, tid, &line[end..]);
+            if !self.on_event(&metadata, Some(&line[end..]), line) {
+                return;
+            }
+
+            // some event lines _include_ a stack line if the stack only has one frame.
+            // in that case, the event will be followed by the stack.
+            let single_stack = if let Some(post_event) = line[end..].splitn(3, ':').nth(2) {
+                // we need to deal with a couple of cases here:
+                //
+                //     vote   913    72.176760:     257597 cycles:uppp:
+                //     false 64414 20110.539270:      34467 cycles:u:  ffffffff9aa3c8de [unknown] ([unknown])
+                //     false 64414 20110.539270:      34467 cycles:  ffffffff9aa3c8de [unknown] ([unknown])
+                //
+                // the first should not be handled as a stack, whereas the latter two both should
+                // the trick is going to be to trim until we encounter a space or a :, whichever
+                // comes first, and then evaluate from there.
+                let post_event_start = post_event
+                    .find(|c| c == ':' || c == ' ')
+                    .map(|i| i + 1)
+                    .unwrap_or(0);
+                let post_event = post_event[post_event_start..].trim();
+                if !post_event.is_empty() {
+                    // we have a stack!
+                    Some(post_event)
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+
+            if let Some(stack_line) = single_stack {
+                self.on_stack_line(stack_line);
+                self.in_event = false;
+            }
+        } else {
+            logging::weird_event_line(&self.warnings, self.line_number, line);
+            self.malformed.fetch_add(1, Ordering::Relaxed);
+            self.fail_strictly("event", line);
+            self.in_event = false;
+        }
+    }
+
+    /// Starts an event with the given metadata. Returns `false` if the event is filtered out,
+    /// in which case its stack is skipped.
+    ///
+    /// `rest` is what follows the PID/TID on the `perf script` event line, if the event came from
+    /// one, and `line` is the input line that warnings refer to.
+    fn on_event(&mut self, metadata: &Event<'_>, rest: Option<&str>, line: &str) -> bool {
+        let Event { comm, tid, .. } = *metadata;
+        let pid = metadata.pid.unwrap_or("?");
+        let generation = match self.opt.group_by_tid_lifetime {
+            Some(gap) => self.tid_generation(tid, metadata.timestamp, gap),
+            None => 1,
+        };
+        if let Some(event) = metadata.name {
+            if let Some(ref event_filter) = self.event_filter {
+                if !is_filtered_event(event_filter, event, metadata.full_name) {
+                    self.stack_filter = StackFilter::Skip;
+                    return false;
+                }
+            } else {
+                // By default only show events of the first encountered event type.
+                // Merging together different types, such as instructions and cycles,
+                // produces misleading results.
+                logging::filtering_for_events_of_type(event);
+                self.event_filter = Some(event.to_string());
+            }
+            self.event_annotation = self
+                .opt
+                .annotate_event
+                .iter()
+                .position(|annotation| annotation.is_match(event, metadata.full_name));
+        }
+
+        if self.opt.no_idle && is_idle_comm(comm) {
+            self.stack_filter = StackFilter::Skip;
+            return false;
+        }
+
+        if (self.opt.time_start.is_some() || self.opt.time_end.is_some())
+            && !self.in_time_window(metadata.timestamp, line)
+        {
+            self.stack_filter = StackFilter::Skip;
+            return false;
+        }
+
+        let sample_rate = self.sample_rate();
+        if sample_rate > 1 {
+            let sampled = self.sampled_events == 0;
+            self.sampled_events = (self.sampled_events + 1) % sample_rate;
+            if !sampled {
+                self.stack_filter = StackFilter::Skip;
+                return false;
+            }
+        }
+
+        self.process_event = self.opt.process_only;
+
+        self.weight = match (self.opt.weight_column, rest) {
+            (Some(column), Some(rest)) => {
+                // the columns are counted from the timestamp, which only the CPU may precede;
+                // without one, there's nothing to count from
+                let weight = metadata
+                    .timestamp
+                    .and_then(|_| {
+                        let mut words = rest.split_whitespace();
+                        if metadata.cpu.is_some() {
+                            words.next();
+                        }
+                        words.nth(column)
+                    })
+                    .and_then(|weight| weight.parse().ok());
+                if weight.is_none() && !self.warned_weight_column {
+                    logging::weird_weight_column(&self.warnings, self.line_number, line, column);
+                    self.warned_weight_column = true;
+                }
+                weight.unwrap_or(1)
+            }
+            _ => match metadata.name {
+                Some(event) if self.header_periods.contains_key(event) => {
+                    metadata.period.unwrap_or(1)
+                }
+                _ => 1,
+            },
+        };
+
+        if let Some(cpus_per_node) = self.opt.cpus_per_node.filter(|&n| n != 0) {
+            self.node.clear();
+            self.node.push_str("node");
+            match metadata.cpu {
+                Some(cpu) => self.node.push_str(&(cpu / cpus_per_node).to_string()),
+                None => self.node.push('?'),
+            }
+        }
+
+        let comm = match comm {
+            "" | "-" => self.opt.unknown_comm.as_str(),
+            comm => comm,
+        };
+        let comm = self
+            .opt
+            .strip_comm_prefix
+            .iter()
+            .find_map(|prefix| comm.strip_prefix(prefix.as_str()))
+            .filter(|stripped| !stripped.is_empty())
+            .unwrap_or(comm);
+
+        if let Some(ref category_map) = self.opt.category_map {
+            self.category.clear();
+            self.category.push_str(category_map.category(comm));
+        }
+
+        if self.opt.timeline {
+            self.timestamp.clear();
+            let timestamp = match rest {
+                Some(rest) => rest
+                    .split_whitespace()
+                    .find(|w| is_timestamp(w))
+                    .map(|timestamp| &timestamp[..(timestamp.len() - 1)]),
+                None => metadata.timestamp,
+            };
+            if let Some(timestamp) = timestamp {
+                self.timestamp.push_str(timestamp);
+            } else if !self.warned_timestamp {
+                logging::missing_timestamp(&self.warnings, self.line_number, line);
+                self.warned_timestamp = true;
+            }
+        }
+
+        // XXX: re-use existing memory in pname if possible
+        self.pname = if self.opt.keep_pname_spaces {
+            comm.to_string()
+        } else {
+            comm.replace(' ', "_")
+        };
+        if let Some(max_len) = self.opt.pname_max_len.filter(|&n| n != 0) {
+            if let Some((end, _)) = self.pname.char_indices().nth(max_len) {
+                self.pname.truncate(end);
+                self.pname.push_str("...");
+            }
+        }
+        if self.opt.include_tid {
+            self.pname.push('-');
+            self.pname.push_str(pid);
+            self.pname.push('/');
+            self.pname.push_str(tid);
+            if generation > 1 {
+                self.pname.push('#');
+                self.pname.push_str(&generation.to_string());
+            }
+        } else if self.opt.include_pid {
+            self.pname.push('-');
+            self.pname.push_str(pid);
+        }
+        true
+    }
+
+    /// Returns whether an event with the given timestamp is within the time window set by
+    /// `Options::time_start` and `Options::time_end`.
+    fn in_time_window(&mut self, timestamp: Option<&str>, line: &str) -> bool {
+        let timestamp = match timestamp.and_then(|timestamp| timestamp.parse::<f64>().ok()) {
+            Some(timestamp) => timestamp,
+            None => {
+                if !self.warned_timestamp {
+                    logging::missing_timestamp(&self.warnings, self.line_number, line);
+                    self.warned_timestamp = true;
                 }
+                return false;
             }
+        };
+        !matches!(self.opt.time_start, Some(start) if timestamp < start)
+            && !matches!(self.opt.time_end, Some(end) if timestamp >= end)
+    }
 
-            // some event lines _include_ a stack line if the stack only has one frame.
-            // in that case, the event will be followed by the stack.
-            let single_stack = if let Some(post_event) = by_colons.next() {
-                // we need to deal with a couple of cases here:
-                //
-                //     vote   913    72.176760:     257597 cycles:uppp:
-                //     false 64414 20110.539270:      34467 cycles:u:  ffffffff9aa3c8de [unknown] ([unknown])
-                //     false 64414 20110.539270:      34467 cycles:  ffffffff9aa3c8de [unknown] ([unknown])
-                //
-                // the first should not be handled as a stack, whereas the latter two both should
-                // the trick is going to be to trim until we encounter a space or a :, whichever
-                // comes first, and then evaluate from there.
-                let post_event_start = post_event
-                    .find(|c| c == ':' || c == ' ')
-                    .map(|i| i + 1)
-                    .unwrap_or(0);
-                let post_event = post_event[post_event_start..].trim();
-                if !post_event.is_empty() {
-                    // we have a stack!
-                    Some(post_event)
-                } else {
-                    None
+    /// Returns the generation of the thread with the given TID, starting a new one if its last
+    /// event was more than `gap` seconds before `timestamp`.
+    fn tid_generation(&mut self, tid: &str, timestamp: Option<&str>, gap: f64) -> usize {
+        let timestamp = match timestamp.and_then(|timestamp| timestamp.parse::<f64>().ok()) {
+            Some(timestamp) => timestamp,
+            None => {
+                return self
+                    .tid_generations
+                    .get(tid)
+                    .map_or(1, |&(_, generation)| generation)
+            }
+        };
+        match self.tid_generations.get_mut(tid) {
+            Some((last_seen, generation)) => {
+                if timestamp - *last_seen > gap {
+                    *generation += 1;
                 }
-            } else {
-                None
-            };
-
-            // XXX: re-use existing memory in pname if possible
-            self.pname = comm.replace(' ', "_");
-            if self.opt.include_tid {
-                self.pname.push('-');
-                self.pname.push_str(pid);
-                self.pname.push('/');
-                self.pname.push_str(tid);
-            } else if self.opt.include_pid {
-                self.pname.push('-');
-                self.pname.push_str(pid);
+                *last_seen = timestamp;
+                *generation
             }
-
-            if let Some(stack_line) = single_stack {
-                self.on_stack_line(stack_line);
-                self.in_event = false;
+            None => {
+                self.tid_generations.insert(tid.to_string(), (timestamp, 1));
+                1
             }
-        } else {
-            logging::weird_event_line(line);
-            self.in_event = false;
         }
     }
 
+    // we have a stack line, like:
+    //
+    //         7f0a1b2c3e00 read (/usr/lib/libc-2.31.so)
+    //
+    // perf indents these with a tab and spaces, but any mix of tabs and spaces works, both as
+    // indentation and between the columns, so that captures reformatted into fixed-width
+    // columns, like
+    //
+    //         7f0a1b2c3e00    read        (/usr/lib/libc-2.31.so)
+    //
+    // parse the same. Trailing whitespace is ignored.
     fn stack_line_parts(line: &str) -> Option<(&str, &str, &str)> {
-        let mut line = line.trim_start().splitn(2, ' ');
-        let pc = line.next()?.trim_end();
-        let mut line = line.next()?.rsplitn(2, ' ');
-        let mut module = line.next()?;
+        // the address ends at the first whitespace; the rest of the run is trimmed off the symbol
+        let (pc, line) = line.trim_start().split_once([' ', '\t'])?;
+        let pc = pc.trim_end();
+        let line = line.trim_end();
 
         // Module should always be wrapped in (), so remove those if they exist.
         // We first check for their existence because it's possible this is being
         // called from `is_applicable` on a non-perf profile. This both prevents
         // a panic if `module.len() < 1` and helps detect whether or not we're
         // parsing a `perf` profile and not something else.
-        if !module.starts_with('(') || !module.ends_with(')') {
+        //
+        // We can't simply split on the last space, since both the symbol (if perf has
+        // already demangled it, e.g. `void foo(int) const`) and the module path may
+        // contain spaces. Instead, find the `(` that balances the trailing `)`.
+        if !line.ends_with(')') {
+            return None;
+        }
+        let mut depth = 0;
+        let mut module_start = None;
+        for (idx, c) in line.char_indices().rev() {
+            match c {
+                ')' => depth += 1,
+                '(' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        module_start = Some(idx);
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+        let module_start = module_start?;
+        let (rawfunc, module) = line.split_at(module_start);
+        // The module must be preceded by whitespace. Otherwise, the trailing parens
+        // belong to the symbol itself (e.g. `foo(int)`), and the module is missing.
+        if !rawfunc.ends_with(char::is_whitespace) {
             return None;
         }
-        module = &module[1..(module.len() - 1)];
+        // Strip rather than slice, so that degenerate modules such as `()` can't cause a panic.
+        // An empty (or blank) module is as good as an unknown one.
+        let module = match module.strip_prefix('(')?.strip_suffix(')')?.trim() {
+            "" => "[unknown]",
+            module => module,
+        };
 
-        let rawfunc = match line.next()?.trim() {
+        let rawfunc = match rawfunc.trim() {
             // Sometimes there are two spaces between the pc and the (, like:
             //     7f1e2215d058  (/lib/x86_64-linux-gnu/libc-2.15.so)
             // In order to match the perl version, the rawfunc should be " ", and not "".
@@ -479,6 +3096,12 @@ impl Folder {
     //     7f53389994d0 [unknown] ([unknown])
     //                0 [unknown] ([unknown])
     fn on_stack_line(&mut self, line: &str) {
+        self.on_frame(line, Self::stack_line_parts(line));
+    }
+
+    /// Adds a frame to the current event, given its program counter, symbol and module as parsed
+    /// from `line`, or `None` if `line` is not a stack line.
+    fn on_frame(&mut self, line: &str, parts: Option<(&str, &str, &str)>) {
         let should_omit = matches!(
             self.stack_filter,
             StackFilter::Skip | StackFilter::SkipRemaining
@@ -487,7 +3110,63 @@ impl Folder {
             return;
         }
 
-        if let Some((pc, mut rawfunc, module)) = Self::stack_line_parts(line) {
+        if let (true, Some((_, rawfunc, _))) = (self.opt.no_idle, parts) {
+            if self.is_idle_frame(rawfunc) {
+                // the whole sample is idle time
+                self.stack.clear();
+                self.stack_filter = StackFilter::Skip;
+                self.process_event = false;
+                return;
+            }
+        }
+
+        if let (true, Some((pc, _, _))) = (self.opt.validate_pcs, parts) {
+            // stack lines go from the leaf outwards, so only the last one may be at address 0
+            self.suspicious_pcs |= self.zero_pc;
+            self.zero_pc = is_zero_pc(pc);
+        }
+
+        if self.opt.process_only {
+            // only the domain of the leaf frame matters
+            if self.leaf_domain.is_none() {
+                match parts {
+                    Some((_, _, module)) => {
+                        let module = self.module_name(module);
+                        self.leaf_domain = Some(Domain::of_module(module));
+                    }
+                    None => {
+                        logging::weird_stack_line(&self.warnings, self.line_number, line);
+                        self.malformed.fetch_add(1, Ordering::Relaxed);
+                        self.fail_strictly("stack", line);
+                    }
+                }
+            }
+            return;
+        }
+
+        let max_stack_frames = self.opt.max_stack_frames;
+        if max_stack_frames != 0 && self.stack.len() >= max_stack_frames {
+            logging::too_many_frames(
+                &self.warnings,
+                self.line_number,
+                line,
+                &self.pname,
+                max_stack_frames,
+            );
+            self.stack.clear();
+            self.stack_filter = StackFilter::Skip;
+            return;
+        }
+
+        if let Some((pc, mut rawfunc, full_module)) = parts {
+            let module = self.module_name(full_module);
+            let domain = Domain::of_module(module);
+            self.leaf_domain.get_or_insert(domain);
+            let in_domain = match self.opt.only_domain {
+                Some(only_domain) if self.opt.trim_to_domain => domain == only_domain,
+                _ => true,
+            };
+
             // Strip off symbol offsets
             if let Some(offset) = rawfunc.rfind("+0x") {
                 let end = &rawfunc[(offset + 3)..];
@@ -507,11 +3186,62 @@ impl Folder {
             // but this will fix the things it gets wrong
             let rawfunc = common::fix_partially_demangled_rust_symbol(rawfunc);
 
+            if self
+                .opt
+                .fold_module
+                .iter()
+                .any(|m| full_module.contains(m.as_str()))
+            {
+                if in_domain {
+                    let name = &module[module.rfind('/').map_or(0, |i| i + 1)..];
+                    let func = if name.starts_with('[') {
+                        name.to_string()
+                    } else {
+                        format!("[{}]", name)
+                    };
+                    // the frames of a run in the module share a single frame
+                    if !(self.folded_module && self.stack.front() == Some(&func)) {
+                        self.stack.push_front(func);
+                    }
+                    self.folded_module = true;
+                    self.folded_closure = false;
+                    self.outer_domain = Some(domain);
+                }
+                if self.skips_after(&rawfunc) {
+                    self.stack_filter = StackFilter::SkipRemaining;
+                }
+                return;
+            }
+            self.folded_module = false;
+
             // Support Java inlining by splitting on "->". After the first func, the
             // rest are annotated with "_[i]" to mark them as inlined.
             // See https://github.com/brendangregg/FlameGraph/pull/89.
-            for func in rawfunc.split("->") {
-                let mut func = with_module_fallback(module, func, pc, self.opt.include_addrs);
+            let mut outer_unannotated = false;
+            let mut first_folded = false;
+            for (i, func) in rawfunc.split("->").enumerate() {
+                // frames named after their module because their symbol is unknown may be
+                // left without annotations
+                let func = match self.opt.symbol_map {
+                    Some(ref symbol_map) if func == "[unknown]" => {
+                        symbol_map.symbol(pc).unwrap_or(func)
+                    }
+                    _ => func,
+                };
+                let unannotated = self.opt.no_annotate_unknown && func == "[unknown]";
+                if self.opt.max_unknown_ratio.is_some() {
+                    self.nframes.fetch_add(1, Ordering::Relaxed);
+                    if func == "[unknown]" {
+                        self.unknown_frames.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                let mut func = with_module_fallback(
+                    module,
+                    func,
+                    pc,
+                    self.opt.include_addrs,
+                    self.opt.addr_prefix,
+                );
                 if TIDY_GENERIC {
                     func = tidy_generic(func);
                 }
@@ -520,93 +3250,645 @@ impl Folder {
                     func = tidy_java(func);
                 }
 
+                if let Some(stripped) = strip_path_prefixes(&func, &self.opt.strip_path_prefix) {
+                    func = stripped;
+                }
+
+                let folded = (self.opt.fold_closures && fold_closure(&mut func))
+                    | (self.opt.fold_gcc_clones && fold_gcc_clone(&mut func));
+
+                // tidying may leave nothing behind (e.g. of `(lambda)`), and an empty frame
+                // would show up as an empty name between two `;` in the output
+                if func.is_empty() {
+                    continue;
+                }
+
+                if !self.keeps_frame(&func) {
+                    continue;
+                }
+
+                let func_len = func.len();
+
                 // Annotations
                 //
-                // detect inlined when self.cache_line has funcs
+                // detect inlined when this isn't the first func
+                // detect vDSO from the function name, whatever the module
                 // detect kernel from the module name; eg, frames to parse include:
                 //
                 //     ffffffff8103ce3b native_safe_halt ([kernel.kallsyms])
                 //     8c3453 tcp_sendmsg (/lib/modules/4.3.0-rc1-virtual/build/vmlinux)
                 //     7d8 ipv4_conntrack_local+0x7f8f80b8 ([nf_conntrack_ipv4])
                 //
-                // detect jit from the module name; eg:
+                // detect deoptimized frames from the function name, and jit from the module
+                // name; eg:
                 //
                 //     7f722d142778 Ljava/io/PrintStream;::print (/tmp/perf-19982.map)
-                if !self.cache_line.is_empty() {
-                    func.push_str("_[i]"); // inlined
-                } else if self.opt.annotate_kernel && is_kernel(module) {
-                    func.push_str("_[k]"); // kernel
-                } else if self.opt.annotate_jit
-                    && ((module.starts_with("/tmp/perf-") && module.ends_with(".map"))
-                        || (module.contains("/jitted-") && module.ends_with(".so")))
-                {
-                    func.push_str("_[j]"); // jitted
+                if !unannotated {
+                    if i > 0 {
+                        func.push_str(Annotation::Inline.suffix());
+                    } else if matches!(self.opt.vdso_regex, Some(ref vdso) if vdso.is_match(&func))
+                    {
+                        func.push_str(Annotation::Vdso.suffix());
+                    } else if self.opt.annotate_kernel && is_kernel(module) {
+                        func.push_str(Annotation::Kernel.suffix());
+                    } else if matches!(self.opt.deopt_regex, Some(ref deopt) if deopt.is_match(&func))
+                    {
+                        func.push_str(Annotation::Deopt.suffix());
+                    } else if self.opt.annotate_jit
+                        && ((full_module.starts_with("/tmp/perf-")
+                            && full_module.ends_with(".map"))
+                            || (full_module.contains("/jitted-") && full_module.ends_with(".so")))
+                    {
+                        func.push_str(Annotation::Jit.suffix());
+                    }
+
+                    self.annotate_regex(&mut func, func_len, module);
+                }
+
+                if self.opt.case_fold_frames {
+                    func = self.first_seen_casing(func);
                 }
 
+                outer_unannotated = unannotated;
+                if self.cache_line.is_empty() {
+                    first_folded = folded;
+                }
                 self.cache_line.push(func);
             }
 
-            while let Some(func) = self.cache_line.pop() {
-                self.stack.push_front(func);
+            if in_domain && !self.cache_line.is_empty() {
+                // this line's innermost frame calls the frame below it in the stack
+                if self.opt.mark_transitions
+                    && !outer_unannotated
+                    && matches!(self.outer_domain, Some(callee) if callee != domain)
+                {
+                    if let Some(func) = self.cache_line.last_mut() {
+                        func.push_str(Annotation::Transition.suffix());
+                    }
+                }
+                self.outer_domain = Some(domain);
+                // a closure (or clone) folded into the name of its caller is merged with it
+                if self.folded_closure && self.stack.front() == self.cache_line.last() {
+                    self.stack.pop_front();
+                }
+                self.folded_closure = first_folded;
+                while let Some(func) = self.cache_line.pop() {
+                    self.stack.push_front(func);
+                }
+            } else {
+                self.cache_line.clear();
             }
 
-            if self
-                .opt
-                .skip_after
-                .iter()
-                .any(|skip_after| rawfunc == *skip_after)
-            {
+            if self.skips_after(&rawfunc) {
                 self.stack_filter = StackFilter::SkipRemaining;
             }
         } else {
-            logging::weird_stack_line(line);
+            logging::weird_stack_line(&self.warnings, self.line_number, line);
+            self.malformed.fetch_add(1, Ordering::Relaxed);
+            self.fail_strictly("stack", line);
+        }
+    }
+
+    /// Remembers the first line that could not be parsed, to fail with once the line is done,
+    /// if `Options::strict` is set.
+    fn fail_strictly(&mut self, kind: &str, line: &str) {
+        if self.opt.strict && self.strict_error.is_none() {
+            self.strict_error = Some(format!(
+                "line {}: weird {} line: {}",
+                self.line_number,
+                kind,
+                line.trim_end()
+            ));
+        }
+    }
+
+    /// Returns the index in `stack` of the frame that `cut_above` or `cut_below` cut at, given
+    /// the regex of either; see `Options::cut_leafmost`.
+    fn marker_frame(&self, regex: &Regex) -> Option<usize> {
+        let is_marker = |func: &String| regex.is_match(strip_annotations(func).0);
+        if self.opt.cut_leafmost {
+            self.stack.iter().rposition(is_marker)
+        } else {
+            self.stack.iter().position(is_marker)
+        }
+    }
+
+    /// Merges every chain of adjacent frames in `stack` that match `Options::fold_wrappers`
+    /// into one.
+    fn fold_wrappers(&mut self) {
+        let regex = match self.opt.fold_wrappers {
+            Some(ref regex) => regex,
+            None => return,
+        };
+        let is_wrapper: Vec<bool> = self
+            .stack
+            .iter()
+            .map(|func| regex.is_match(strip_annotations(func).0))
+            .collect();
+        // frames are ordered from the root, so the outermost frame of a chain is its first
+        let innermost = self.opt.fold_wrappers_innermost;
+        let mut i = 0;
+        self.stack.retain(|_| {
+            let keep = !is_wrapper[i]
+                || if innermost {
+                    i + 1 == is_wrapper.len() || !is_wrapper[i + 1]
+                } else {
+                    i == 0 || !is_wrapper[i - 1]
+                };
+            i += 1;
+            keep
+        });
+    }
+
+    /// Adds the current event to the counts of its process and leaf frame; see
+    /// `Options::hot_leaves`.
+    fn count_leaf(&mut self) {
+        let (total, leaves) = match self.hot_leaves.get_mut(&self.pname) {
+            Some(counts) => counts,
+            None => self.hot_leaves.entry(self.pname.clone()).or_default(),
+        };
+        *total += self.weight;
+        if let Some(leaf) = self.stack.back() {
+            match leaves.get_mut(leaf) {
+                Some(count) => *count += self.weight,
+                None => {
+                    leaves.insert(leaf.clone(), self.weight);
+                }
+            }
+        }
+    }
+
+    /// Adds an event with the given folded stack to the statistics; see `Options::stats`.
+    fn count_stats(&mut self, stack_str: &str, depth: usize) {
+        self.stats.nevents += 1;
+        *self.stats.depths.entry(depth).or_default() += 1;
+        if self.opt.validate_pcs {
+            *self.stats.suspicious_pcs.get_or_insert(0) += usize::from(self.suspicious_pcs);
+        }
+        if !self.opt.runs {
+            return;
+        }
+
+        let (ref mut length, ref mut stack) = self.run;
+        if *length != 0 && stack == stack_str {
+            *length += 1;
+        } else {
+            *length = 1;
+            stack.clear();
+            stack.push_str(stack_str);
+        }
+        match self.stats.longest_run {
+            Some((ref mut longest, ref longest_stack)) if longest_stack == stack => {
+                *longest = (*longest).max(*length);
+            }
+            Some((longest, _)) if longest >= *length => {}
+            _ => self.stats.longest_run = Some((*length, stack.clone())),
+        }
+    }
+
+    /// Adds the frames of the current event to `Stats::frame_counts`; see
+    /// `Options::debug_counts`.
+    fn count_frames(&mut self) {
+        let mut seen = HashSet::with_capacity(self.stack.len());
+        for func in &self.stack {
+            if seen.insert(func.as_str()) {
+                if let Some(count) = self.stats.frame_counts.get_mut(func) {
+                    *count += self.weight;
+                } else {
+                    self.stats.frame_counts.insert(func.clone(), self.weight);
+                }
+            }
+        }
+    }
+
+    /// Returns how a frame named `func` (in any casing) was first named; see
+    /// `Options::case_fold_frames`.
+    fn first_seen_casing(&mut self, func: String) -> String {
+        match self.casings.entry(func.to_lowercase()) {
+            Entry::Occupied(casing) => casing.get().clone(),
+            Entry::Vacant(entry) => entry.insert(func).clone(),
+        }
+    }
+
+    /// Returns the `limit_events` in effect, if any.
+    fn event_limit(&self) -> Option<usize> {
+        self.opt.limit_events.filter(|&limit| limit != 0)
+    }
+
+    /// Returns the `sample_rate` in effect, where 1 means that every event is processed.
+    fn sample_rate(&self) -> usize {
+        match self.opt.sample_rate {
+            Some(rate) if rate > 1 => rate,
+            _ => 1,
+        }
+    }
+
+    /// Returns whether the frames after (i.e. the callers of) the function `rawfunc`, without
+    /// its symbol offset, are skipped; see `Options::skip_after`.
+    fn skips_after(&self, rawfunc: &str) -> bool {
+        self.opt
+            .skip_after
+            .iter()
+            .any(|skip_after| rawfunc == skip_after)
+    }
+
+    /// Returns whether `rawfunc` (with any symbol offset) is one of `Options::idle_frames`.
+    fn is_idle_frame(&self, rawfunc: &str) -> bool {
+        let func = rawfunc.rsplit_once("+0x").map_or(rawfunc, |(func, _)| func);
+        self.opt.idle_frames.iter().any(|idle| idle == func)
+    }
+
+    /// Returns whether `keep_frame` (if set) keeps, and `fold_plt` doesn't remove, the frame
+    /// with the given function name.
+    fn keeps_frame(&self, func: &str) -> bool {
+        if let (true, Some(plt_regex)) = (self.opt.fold_plt, &self.opt.plt_regex) {
+            if plt_regex.is_match(func) {
+                return false;
+            }
+        }
+        match self.opt.keep_frame {
+            Some(ref keep_frame) => keep_frame.is_match(func),
+            None => true,
+        }
+    }
+
+    /// Returns the module to inspect for a frame, which is its file name if
+    /// `Options::module_basename` is set, and otherwise without `Options::strip_path_prefix`.
+    fn module_name<'a>(&self, module: &'a str) -> &'a str {
+        if module.starts_with('[') {
+            return module;
+        }
+        if !self.opt.module_basename {
+            return self
+                .opt
+                .strip_path_prefix
+                .iter()
+                .find_map(|prefix| module.strip_prefix(prefix.as_str()))
+                .unwrap_or(module);
+        }
+        match module.rfind('/') {
+            Some(i) => &module[(i + 1)..],
+            None => module,
+        }
+    }
+
+    /// Appends the suffixes of the matching `annotate_regex` annotations to `func`.
+    fn annotate_regex(&self, func: &mut String, func_len: usize, module: &str) {
+        // user-defined annotations match against the function name without the suffix
+        // we may just have added, and accumulate
+        for annotation in &self.opt.annotate_regex {
+            if annotation.is_match(&func[..func_len], module) {
+                func.push_str(&annotation.suffix);
+            }
+        }
+    }
+
+    // we have a line of already folded stacks, like:
+    //
+    //     java;start_thread;java_start;GCTaskThread::run 7
+    fn on_folded_line(&mut self, line: &str, occurrences: &mut Occurrences) {
+        let (stack, count) = match line.rsplit_once(' ').map(|(s, c)| (s, c.parse())) {
+            Some((stack, Ok(count))) => (stack, count),
+            _ => {
+                logging::weird_folded_line(&self.warnings, self.line_number, line);
+                self.fail_strictly("folded", line);
+                return;
+            }
+        };
+
+        let mut frames = stack.split(';');
+        let pname = frames.next().unwrap_or_default();
+        self.pname.clear();
+        self.pname.push_str(pname);
+        if let Some(ref category_map) = self.opt.category_map {
+            self.category.clear();
+            self.category.push_str(category_map.category(pname));
+        }
+
+        for frame in frames {
+            let stripped = strip_path_prefixes(frame, &self.opt.strip_path_prefix);
+            let frame = stripped.as_deref().unwrap_or(frame);
+            let (name, _) = strip_annotations(frame);
+            if !self.keeps_frame(name) {
+                continue;
+            }
+            let mut func = frame.to_string();
+            if !self.opt.annotate_regex.is_empty() {
+                // match against the name without any annotations, but keep those
+                let func_len = name.len();
+                let suffixes = func.split_off(func_len);
+                self.annotate_regex(&mut func, func_len, "");
+                func.insert_str(func_len, &suffixes);
+            }
+            self.stack.push_back(func);
         }
+
+        self.weight = count;
+        self.after_event(occurrences);
     }
 
     fn after_event(&mut self, occurrences: &mut Occurrences) {
+        // unless trimming, only_domain decides by the leaf frame whether to keep the stack
+        let in_domain = match self.opt.only_domain {
+            Some(only_domain) if !self.opt.trim_to_domain && !self.opt.from_folded => {
+                self.leaf_domain == Some(only_domain)
+            }
+            _ => true,
+        };
+
+        if let Some(ref cut_below) = self.opt.cut_below {
+            if let Some(i) = self.marker_frame(cut_below) {
+                self.stack.drain(..i);
+            }
+        }
+        if let Some(ref cut_above) = self.opt.cut_above {
+            if let Some(i) = self.marker_frame(cut_above) {
+                self.stack.truncate(i + 1);
+            }
+        }
+
+        self.fold_wrappers();
+
+        if let (true, Some(stack_hook)) = (in_domain, &self.opt.stack_hook) {
+            let mut frames = Vec::from(mem::take(&mut self.stack));
+            (stack_hook.0)(&mut frames, &self.pname);
+            self.stack = frames.into();
+        }
+
         // end of stack, so emit stack entry
-        if !self.stack.is_empty() {
+        if in_domain && (self.process_event || !self.stack.is_empty()) {
             // allocate a string that is long enough to hold the entire stack string
+            let category = self.opt.category_map.as_ref().map(|_| &*self.category);
+            let node = self
+                .opt
+                .cpus_per_node
+                .filter(|&n| n != 0)
+                .map(|_| &*self.node);
+            let marker = Some(&*self.comment_marker).filter(|marker| !marker.is_empty());
+            let root_frames = self
+                .opt
+                .root_frame
+                .as_deref()
+                .into_iter()
+                .chain(category)
+                .chain(node)
+                .chain(marker);
             let mut stack_str = String::with_capacity(
-                self.pname.len() + self.stack.iter().fold(0, |a, s| a + s.len() + 1),
+                root_frames.clone().fold(0, |a, r| a + r.len() + 1)
+                    + self.pname.len()
+                    + self.stack.iter().fold(0, |a, s| a + s.len() + 1)
+                    + self.opt.tag_all.as_ref().map_or(0, |tag| tag.len() + 1),
             );
 
+            for root_frame in root_frames {
+                stack_str.push_str(root_frame);
+                stack_str.push(';');
+            }
+
+            if self.opt.flat_profile {
+                self.flat_profile.count(&self.stack, self.weight);
+            }
+            if self.opt.hot_leaves {
+                self.count_leaf();
+            }
+
+            let depth = self.stack.len();
+            if self.opt.stats && self.opt.debug_counts {
+                self.count_frames();
+            }
+
             // If we skip remaining frames we want to skip pname as well.
             if self.stack_filter != StackFilter::SkipRemaining {
                 // add the comm name
                 stack_str.push_str(&self.pname);
                 stack_str.push(';');
             }
+            let suffix = match self.event_annotation {
+                Some(i) => &*self.opt.annotate_event[i].suffix,
+                None => "",
+            };
             for e in self.stack.drain(..) {
                 stack_str.push_str(&e);
+                stack_str.push_str(suffix);
+                stack_str.push(';');
+            }
+            if let Some(ref tag_all) = self.opt.tag_all {
+                stack_str.push_str(tag_all);
                 stack_str.push(';');
             }
 
-            // self.stack is not empty, therefore stack_str has at least one frame followed by ';'
+            // self.stack is not empty (or, with process_only, the comm name was added), therefore
+            // stack_str has at least one frame followed by ';'
             stack_str.pop();
 
+            if self.opt.stats {
+                self.count_stats(&stack_str, depth);
+            }
+            self.nevents += 1;
+
             // count it!
-            occurrences.insert_or_add(stack_str, 1);
+            if !self.opt.timeline {
+                occurrences.insert_or_add(stack_str, self.weight);
+            } else if !self.timestamp.is_empty() {
+                stack_str.insert(0, ' ');
+                stack_str.insert_str(0, &self.timestamp);
+                occurrences.insert_or_add(stack_str, 1);
+            }
         }
 
         // reset for the next event
+        self.event_annotation = None;
         self.in_event = false;
+        self.leaf_domain = None;
+        self.folded_closure = false;
+        self.folded_module = false;
+        self.outer_domain = None;
+        self.process_event = false;
         self.stack_filter = StackFilter::Keep;
         self.stack.clear();
+        self.suspicious_pcs = false;
+        self.timestamp.clear();
+        self.zero_pc = false;
+    }
+}
+
+const ESC: u8 = 0x1b;
+
+enum AnsiState {
+    Text,
+    // after `ESC`, and any intermediate bytes, like the `(` in `ESC ( B`
+    Escape,
+    // in a control sequence, like `ESC [ 1 ; 3 3 m`
+    Csi,
+    // in an operating system command, like `ESC ] 0 ; title BEL`
+    Osc,
+    // after an `ESC` in an operating system command, which may start its `ESC \` terminator
+    OscEscape,
+}
+
+// Removes ANSI escape sequences from a line of input in place. A sequence that is cut off by the
+// end of the line is removed up to there.
+fn strip_ansi_escapes(line: &mut Vec<u8>) {
+    let mut state = AnsiState::Text;
+    line.retain(|&b| {
+        if b == b'\n' {
+            state = AnsiState::Text;
+            return true;
+        }
+        let (next, keep) = match state {
+            AnsiState::Text if b == ESC => (AnsiState::Escape, false),
+            AnsiState::Text => (AnsiState::Text, true),
+            AnsiState::Escape => match b {
+                b'[' => (AnsiState::Csi, false),
+                b']' => (AnsiState::Osc, false),
+                0x20..=0x2f => (AnsiState::Escape, false),
+                _ => (AnsiState::Text, false),
+            },
+            AnsiState::Csi => match b {
+                0x40..=0x7e => (AnsiState::Text, false),
+                _ => (AnsiState::Csi, false),
+            },
+            AnsiState::Osc => match b {
+                0x07 => (AnsiState::Text, false),
+                ESC => (AnsiState::OscEscape, false),
+                _ => (AnsiState::Osc, false),
+            },
+            AnsiState::OscEscape => match b {
+                b'\\' => (AnsiState::Text, false),
+                _ => (AnsiState::Osc, false),
+            },
+        };
+        state = next;
+        keep
+    });
+}
+
+// whether a program counter, like `0` or `0x0000`, is address 0; see `Options::validate_pcs`
+fn is_zero_pc(pc: &str) -> bool {
+    let digits = pc.strip_prefix("0x").unwrap_or(pc);
+    !digits.is_empty() && digits.bytes().all(|b| b == b'0')
+}
+
+// whether this is the comm of the kernel's idle task, like `swapper` or `swapper/3`; see
+// `Options::no_idle`
+fn is_idle_comm(comm: &str) -> bool {
+    comm == "swapper" || comm.starts_with("swapper/")
+}
+
+// whether `pattern` has glob wildcards; see `Options::event_filter`
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(&['*', '?'][..])
+}
+
+// whether an event with the given name and full name passes `event_filter`; see
+// `Options::event_filter`
+fn is_filtered_event(event_filter: &str, name: &str, full_name: Option<&str>) -> bool {
+    if is_glob(event_filter) {
+        is_glob_match(event_filter, name)
+            || matches!(full_name, Some(full_name) if is_glob_match(event_filter, full_name))
+    } else {
+        name == event_filter
+    }
+}
+
+// whether this word is a perf timestamp, like `4794564.109216:`
+fn is_timestamp(word: &str) -> bool {
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match word.strip_suffix(':').and_then(|word| word.split_once('.')) {
+        Some((secs, fraction)) => all_digits(secs) && all_digits(fraction),
+        None => false,
+    }
+}
+
+// the CPU number at the start of what follows the PID/TID on an event line, like `[001]`
+fn event_cpu(rest: &str) -> Option<usize> {
+    rest.split_whitespace()
+        .next()?
+        .strip_prefix('[')?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+}
+
+fn is_hex(word: &str) -> bool {
+    !word.is_empty() && word.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// split a `PID/TID` or `TID` word, using `?` for a missing PID
+fn pid_and_tid(word: &str) -> Option<(&str, &str)> {
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match word.split_once('/') {
+        Some((pid, tid)) if all_digits(pid) && all_digits(tid) => Some((pid, tid)),
+        None if all_digits(word) => Some(("?", word)),
+        _ => None,
+    }
+}
+
+// `perf script -F +pid` may add a column with the PID before the comm, like:
+//
+//     24636 java 24636/25607 [000] 4794564.109216: cycles:
+//     24636 java 25607 4794564.109216: cycles:
+//
+// so an all-digit first word of the comm is taken to be the PID if it matches the PID of the event,
+// or if the event has no PID otherwise.
+fn without_leading_pid<'a>(comm: &'a str, pid: &'a str) -> (&'a str, &'a str) {
+    if let Some((first, rest)) = comm.split_once(' ') {
+        let rest = rest.trim_start();
+        let is_pid = !first.is_empty() && first.bytes().all(|b| b.is_ascii_digit());
+        if is_pid && !rest.is_empty() && (pid == "?" || pid == first) {
+            return (rest, first);
+        }
+    }
+    (comm, pid)
+}
+
+// removes the longest of `prefixes` (sorted longest first) from the start of every path in
+// `func`, which is at its start, or after a space, `(`, or `:`; see `Options::strip_path_prefix`.
+// returns `None` if there was nothing to remove.
+fn strip_path_prefixes(func: &str, prefixes: &[String]) -> Option<String> {
+    if prefixes.is_empty() {
+        return None;
     }
+    let mut stripped = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < func.len() {
+        // a path can only start after an ASCII character, so `i` is a char boundary here
+        if i == 0 || matches!(func.as_bytes()[i - 1], b' ' | b'(' | b':') {
+            if let Some(prefix) = prefixes.iter().find(|p| func[i..].starts_with(p.as_str())) {
+                stripped.push_str(&func[copied..i]);
+                i += prefix.len();
+                copied = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    if copied == 0 {
+        return None;
+    }
+    stripped.push_str(&func[copied..]);
+    Some(stripped)
 }
 
 // massage function name to be nicer
 // NOTE: ignoring https://github.com/jvm-profiling-tools/perf-map-agent/pull/35
-fn with_module_fallback(module: &str, func: &str, pc: &str, include_addrs: bool) -> String {
+fn with_module_fallback(
+    module: &str,
+    func: &str,
+    pc: &str,
+    include_addrs: bool,
+    addr_prefix: bool,
+) -> String {
     if func != "[unknown]" {
         return func.to_string();
     }
 
     // try to use part of module name as function if unknown
+    // (an empty module, as in `[unknown] ()`, is no more helpful than an unknown one)
     let func = match (module, include_addrs) {
-        ("[unknown]", true) => "unknown",
-        ("[unknown]", false) => {
+        ("[unknown]" | "", true) => "unknown",
+        ("[unknown]" | "", false) => {
             // no need to process this further
             return func.to_string();
         }
@@ -623,7 +3905,7 @@ fn with_module_fallback(module: &str, func: &str, pc: &str, include_addrs: bool)
     res.push_str(func);
     if include_addrs {
         res.push_str(" <");
-        res.push_str(pc);
+        push_normalized_pc(&mut res, pc, addr_prefix);
         res.push_str(">]");
     } else {
         res.push(']');
@@ -632,6 +3914,87 @@ fn with_module_fallback(module: &str, func: &str, pc: &str, include_addrs: bool)
     res
 }
 
+// the same address may be spelled differently depending on where it came from
+// (e.g. `7F53389994D0`, `0x7f53389994d0`, `00007f53389994d0`), so spell it one way
+fn push_normalized_pc(res: &mut String, pc: &str, addr_prefix: bool) {
+    let digits = pc
+        .strip_prefix("0x")
+        .or_else(|| pc.strip_prefix("0X"))
+        .unwrap_or(pc);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        // not an address we know how to normalize
+        res.push_str(pc);
+        return;
+    }
+
+    if addr_prefix {
+        res.push_str("0x");
+    }
+    match digits.trim_start_matches('0') {
+        "" => res.push('0'),
+        digits => res.extend(digits.chars().map(|c| c.to_ascii_lowercase())),
+    }
+}
+
+// removes closures and lambdas from a (tidied) function name, so that it is attributed to the
+// enclosing function, e.g.:
+//
+//     app::run::{{closure}}::{{closure}}     -> app::run
+//     app::run::{closure#0}                  -> app::run
+//     main::{lambda(int)#1}::operator        -> main
+//
+// names that consist of just a closure are left as is, since there is no enclosing function.
+// returns whether the name was changed.
+fn fold_closure(func: &mut String) -> bool {
+    let start = ["::{{closure}}", "::{closure#", "::{lambda("]
+        .iter()
+        .filter_map(|closure| func.find(closure))
+        .min();
+    match start {
+        Some(start) if start != 0 => {
+            func.truncate(start);
+            true
+        }
+        _ => false,
+    }
+}
+
+// removes the suffixes of a GCC clone from a function name, and returns whether there were any;
+// see `Options::fold_gcc_clones`
+fn fold_gcc_clone(func: &mut String) -> bool {
+    let mut name = func.as_str();
+    loop {
+        // demangled, like `parse(char const*) [clone .cold]` (tidying may have removed the
+        // arguments, and the clone with them)
+        if let Some(clone) = name
+            .strip_suffix(']')
+            .and_then(|name| name.rfind(" [clone ."))
+        {
+            if clone != 0 {
+                name = &name[..clone];
+                continue;
+            }
+        }
+        // mangled, or C, like `parse.part.0`; only `.cold` may lack the number, so that e.g. a
+        // Go function `sha2.part` is left alone
+        let unnumbered = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        let base = match unnumbered.strip_suffix('.') {
+            Some(unnumbered) if unnumbered.len() + 1 < name.len() => GCC_CLONE_SUFFIXES
+                .iter()
+                .find_map(|suffix| unnumbered.strip_suffix(suffix)),
+            _ => name.strip_suffix(".cold"),
+        };
+        match base {
+            Some(base) if !base.is_empty() => name = base,
+            _ => break,
+        }
+    }
+    let len = name.len();
+    let folded = len != func.len();
+    func.truncate(len);
+    folded
+}
+
 fn tidy_generic(mut func: String) -> String {
     func = func.replace(';', ":");
     // remove argument list from function name, but _don't_ remove:
@@ -743,6 +4106,535 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fold_gcc_clone() {
+        let test_expectations = [
+            ("parse.constprop.0", "parse"),
+            ("parse.constprop.12", "parse"),
+            ("parse.isra.3", "parse"),
+            ("parse.part.1", "parse"),
+            ("parse.cold", "parse"),
+            ("parse.cold.7", "parse"),
+            ("parse.constprop.0.isra.0", "parse"),
+            ("parse.part.0.cold", "parse"),
+            ("parse [clone .cold]", "parse"),
+            ("parse [clone .constprop.0] [clone .isra.0]", "parse"),
+            // not clones
+            ("parse", "parse"),
+            ("parse.part", "parse.part"),
+            ("parse.isra", "parse.isra"),
+            ("parse.parts.1", "parse.parts.1"),
+            ("sha2.1", "sha2.1"),
+            (".cold", ".cold"),
+            (".part.1", ".part.1"),
+        ];
+
+        for (input, expected) in test_expectations.iter() {
+            let mut func = input.to_string();
+            assert_eq!(fold_gcc_clone(&mut func), input != expected, "{}", input);
+            assert_eq!(&func, expected);
+        }
+    }
+
+    #[test]
+    fn test_detect_fields() {
+        let all = Fields {
+            nevents: 1,
+            comm: true,
+            pid: true,
+            tid: true,
+            cpu: true,
+            time: true,
+            period: true,
+            event: true,
+            ip: true,
+            sym: true,
+            dso: true,
+        };
+        let test_expectations = [
+            (
+                "java 24636/25607 [000] 94564.109216: 1 cycles:\n\
+                 \t7f1e2215d058 main (/usr/bin/java)\n",
+                all.clone(),
+            ),
+            (
+                "# a comment\n\
+                 java 25607 cycles:\n\
+                 \t7f1e2215d058 main\n\
+                 \n\
+                 java 25608 cycles:\n\
+                 \t7f1e2215d058 main\n",
+                Fields {
+                    nevents: 2,
+                    cpu: false,
+                    pid: false,
+                    time: false,
+                    period: false,
+                    dso: false,
+                    ..all.clone()
+                },
+            ),
+            (
+                "java 25607 94564.109216:\n\
+                 \t(/usr/bin/java)\n",
+                Fields {
+                    cpu: false,
+                    pid: false,
+                    period: false,
+                    event: false,
+                    ip: false,
+                    sym: false,
+                    ..all.clone()
+                },
+            ),
+            (
+                "false 64414 20110.539270:      34467 cycles:u:  ffffffff9aa3c8de [unknown] ([unknown])\n",
+                Fields {
+                    cpu: false,
+                    pid: false,
+                    ..all.clone()
+                },
+            ),
+            ("not perf\n", Fields::default()),
+        ];
+
+        for (input, expected) in test_expectations.iter() {
+            let fields = Fields::detect(input.as_bytes(), 2).unwrap();
+            assert_eq!(&fields, expected, "{}", input);
+        }
+
+        // only the first events are examined
+        let input =
+            "java 25607 cycles:\n\tmain\n\njava 24636/25607 [000] 94564.109216: 1 cycles:\n";
+        let fields = Fields::detect(input.as_bytes(), 1).unwrap();
+        assert_eq!(fields.nevents, 1);
+        assert!(!fields.pid);
+    }
+
+    #[test]
+    fn test_event_line_parts() {
+        let test_expectations = [
+            (
+                "java 25607 4794564.109216: cycles:",
+                Some(("java", "?", "25607", 11)),
+            ),
+            (
+                "V8 WorkerThread 24636/25607 [000] 94564.109216: cycles:",
+                Some(("V8 WorkerThread", "24636", "25607", 28)),
+            ),
+            (
+                "vote   913    72.176760:     257597 cycles:uppp:",
+                Some(("vote", "?", "913", 11)),
+            ),
+            (
+                "worker: 1501/1502 [001] 3030.123456: cycles:",
+                Some(("worker:", "1501", "1502", 18)),
+            ),
+            (
+                "worker: 2 1501/1503 [002] 3030.223456: cycles:",
+                Some(("worker: 2", "1501", "1503", 20)),
+            ),
+            (
+                "job 3: 7 1701/1702 3030.423456: cycles:",
+                Some(("job 3: 7", "1701", "1702", 19)),
+            ),
+            // degenerate comms of exiting processes
+            (
+                "- 4242/4242 [000] 200.000001: cycles:",
+                Some(("-", "4242", "4242", 12)),
+            ),
+            (
+                "          4243/4243 [001] 200.000002: cycles:",
+                Some(("", "4243", "4243", 20)),
+            ),
+            // without a timestamp, we fall back to the first all-digit word
+            ("java 25607 cycles:", Some(("java", "?", "25607", 11))),
+            ("java cycles:", None),
+            // cgroup-style comms with numeric path components
+            (
+                "user-1000.slice/session-2.scope 4242/4243 [000] 3030.523456: cycles:",
+                Some(("user-1000.slice/session-2.scope", "4242", "4243", 42)),
+            ),
+            (
+                "system.slice/1000/2000 4242 3030.623456: cycles:",
+                Some(("system.slice/1000/2000", "?", "4242", 28)),
+            ),
+            (
+                "user-1000.slice/session-2.scope 4242/4243 cycles:",
+                Some(("user-1000.slice/session-2.scope", "4242", "4243", 42)),
+            ),
+            (
+                "cgroup /1000 1000/2000/3000 4242 cycles:",
+                Some(("cgroup /1000 1000/2000/3000", "?", "4242", 33)),
+            ),
+        ];
+
+        for (input, expected) in test_expectations.iter() {
+            assert_eq!(&Folder::event_line_parts(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_strip_path_prefixes() {
+        let prefixes = ["/home/ci/build/".to_string(), "/home/ci/".to_string()];
+        let test_expectations = [
+            ("/home/ci/build/lib/x.so", Some("lib/x.so")),
+            ("/home/ci/app/x.so", Some("app/x.so")),
+            (
+                "LazyCompile:~main /home/ci/app/main.js:10",
+                Some("LazyCompile:~main app/main.js:10"),
+            ),
+            ("py::main:/home/ci/app.py", Some("py::main:app.py")),
+            ("foo (/home/ci/build/a.c)", Some("foo (a.c)")),
+            // only at the start of paths
+            ("/opt/home/ci/x.so", None),
+            ("main", None),
+            ("日本 /home/ci/x", Some("日本 x")),
+        ];
+        for (input, expected) in test_expectations.iter() {
+            assert_eq!(
+                strip_path_prefixes(input, &prefixes).as_deref(),
+                *expected,
+                "{}",
+                input
+            );
+        }
+        assert_eq!(strip_path_prefixes("/home/ci/x", &[]), None);
+    }
+
+    #[test]
+    fn test_event_line_parts_comm_matrix() {
+        // (comm, pid, tid) of every kind of comm, with and without a PID, and with and without
+        // a CPU; a missing PID is `?`
+        let test_expectations = [
+            // a normal comm
+            (
+                "java 24636/25607 [000] 94564.109216: cycles:",
+                ("java", "24636", "25607"),
+            ),
+            (
+                "java 24636/25607 94564.109216: cycles:",
+                ("java", "24636", "25607"),
+            ),
+            (
+                "java 25607 [000] 94564.109216: cycles:",
+                ("java", "?", "25607"),
+            ),
+            ("java 25607 94564.109216: cycles:", ("java", "?", "25607")),
+            // a multi-word comm
+            (
+                "V8 WorkerThread 24636/25607 [000] 94564.109216: cycles:",
+                ("V8 WorkerThread", "24636", "25607"),
+            ),
+            (
+                "V8 WorkerThread 24636/25607 94564.109216: cycles:",
+                ("V8 WorkerThread", "24636", "25607"),
+            ),
+            (
+                "V8 WorkerThread 25607 [000] 94564.109216: cycles:",
+                ("V8 WorkerThread", "?", "25607"),
+            ),
+            (
+                "V8 WorkerThread 25607 94564.109216: cycles:",
+                ("V8 WorkerThread", "?", "25607"),
+            ),
+            // a comm with trailing digits
+            (
+                "python3 1001/1002 [001] 100.000001: cycles:",
+                ("python3", "1001", "1002"),
+            ),
+            ("python3 1002 100.000001: cycles:", ("python3", "?", "1002")),
+            (
+                "kworker/0:1 34/34 [000] 100.000001: cycles:",
+                ("kworker/0:1", "34", "34"),
+            ),
+            (
+                "kworker/0:1 34 100.000001: cycles:",
+                ("kworker/0:1", "?", "34"),
+            ),
+            (
+                "Thread 2 1001/1003 [001] 100.000001: cycles:",
+                ("Thread 2", "1001", "1003"),
+            ),
+            (
+                "Thread 2 1003 100.000001: cycles:",
+                ("Thread 2", "?", "1003"),
+            ),
+            // a purely numeric comm
+            (
+                "1234 1001/1002 [001] 100.000001: cycles:",
+                ("1234", "1001", "1002"),
+            ),
+            (
+                "1234 1001/1002 100.000001: cycles:",
+                ("1234", "1001", "1002"),
+            ),
+            ("1234 1002 [001] 100.000001: cycles:", ("1234", "?", "1002")),
+            ("1234 1002 100.000001: cycles:", ("1234", "?", "1002")),
+            // a leading all-digit word is taken to be the PID added by `-F +pid` if there is no
+            // other PID, or if it is the same, but is otherwise part of the comm
+            ("12 34 1002 100.000001: cycles:", ("34", "12", "1002")),
+            (
+                "1001 java 1001/1002 [001] 100.000001: cycles:",
+                ("java", "1001", "1002"),
+            ),
+            (
+                "12 34 1001/1002 [001] 100.000001: cycles:",
+                ("12 34", "1001", "1002"),
+            ),
+            // without a timestamp, the first PID-like word is used, even if it is part of the comm
+            ("java 24636/25607 [000] cycles:", ("java", "24636", "25607")),
+            ("java 25607 cycles:", ("java", "?", "25607")),
+            ("Thread 2 1003 cycles:", ("Thread", "?", "2")),
+        ];
+
+        for (input, expected) in test_expectations.iter() {
+            let parts = Folder::event_line_parts(input).map(|(comm, pid, tid, _)| (comm, pid, tid));
+            assert_eq!(parts.as_ref(), Some(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_stack_line_parts_demangled() {
+        let test_expectations = [
+            (
+                "7f0a1b2c3d4e void foo(int) const (/usr/lib/libfoo.so)",
+                Some(("7f0a1b2c3d4e", "void foo(int) const", "/usr/lib/libfoo.so")),
+            ),
+            (
+                "7f0a1b2c3d4e std::function<void (int)>::operator()(int)+0x1f ([unknown])",
+                Some((
+                    "7f0a1b2c3d4e",
+                    "std::function<void (int)>::operator()(int)+0x1f",
+                    "[unknown]",
+                )),
+            ),
+            (
+                "7f0a1b2c3d4e (anonymous namespace)::bar() (/opt/My App/bin/app (v2))",
+                Some((
+                    "7f0a1b2c3d4e",
+                    "(anonymous namespace)::bar()",
+                    "/opt/My App/bin/app (v2)",
+                )),
+            ),
+            (
+                "7f1e2215d058  (/lib/x86_64-linux-gnu/libc-2.15.so)",
+                Some(("7f1e2215d058", " ", "/lib/x86_64-linux-gnu/libc-2.15.so")),
+            ),
+            ("7f0a1b2c3d4e void foo(int)", None),
+            ("7f0a1b2c3d4e foo (bar", None),
+        ];
+
+        for (input, expected) in test_expectations.iter() {
+            assert_eq!(&Folder::stack_line_parts(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_stack_line_parts_short_module() {
+        let test_expectations = [
+            (
+                "7f0a1b2c3d4e foo(bar) ()",
+                Some(("7f0a1b2c3d4e", "foo(bar)", "[unknown]")),
+            ),
+            (
+                "7f0a1b2c3d4e foo (  )",
+                Some(("7f0a1b2c3d4e", "foo", "[unknown]")),
+            ),
+            ("7f0a1b2c3d4e foo ( x )", Some(("7f0a1b2c3d4e", "foo", "x"))),
+            (
+                "7f0a1b2c3d4e foo(bar) (x)",
+                Some(("7f0a1b2c3d4e", "foo(bar)", "x")),
+            ),
+            (
+                "7f0a1b2c3d4e [unknown] ()",
+                Some(("7f0a1b2c3d4e", "[unknown]", "[unknown]")),
+            ),
+            ("7f0a1b2c3d4e foo )", None),
+            ("7f0a1b2c3d4e foo (", None),
+            ("7f0a1b2c3d4e ()", None),
+        ];
+
+        for (input, expected) in test_expectations.iter() {
+            assert_eq!(&Folder::stack_line_parts(input), expected);
+        }
+
+        // Make sure such frames also make it through the rest of the folder unscathed.
+        let input = "foo 1234 1.000000: cycles:\n\
+                     \t7f0a1b2c3d4e foo(bar) ()\n\
+                     \t7f0a1b2c3d4f [unknown] ()\n\
+                     \t7f0a1b2c3d50 [unknown] (x)\n\
+                     \t7f0a1b2c3d51 [unknown] (  )\n";
+        for &include_addrs in &[false, true] {
+            let mut folder = Folder::from(Options {
+                include_addrs,
+                nthreads: 1,
+                ..Default::default()
+            });
+            let mut buf_actual = Vec::new();
+            <Folder as Collapse>::collapse(&mut folder, input.as_bytes(), &mut buf_actual).unwrap();
+            let expected = if include_addrs {
+                "foo;[unknown <7f0a1b2c3d51>];[x <7f0a1b2c3d50>];[unknown <7f0a1b2c3d4f>];foo 1\n"
+            } else {
+                "foo;[unknown];[x];[unknown];foo 1\n"
+            };
+            assert_eq!(std::str::from_utf8(&buf_actual).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_lines_random_input() {
+        // Lines made of the characters that matter to the parsers (including multi-byte ones,
+        // to catch slicing off char boundaries) must never make them panic.
+        const CHARS: &[char] = &[
+            ' ', '\t', '/', ':', '.', '(', ')', '[', ']', '+', 'x', '0', '7', 'f', 'a', 'é', '→',
+        ];
+        let mut rng = SmallRng::seed_from_u64(140);
+        let mut line = String::new();
+        for _ in 0..10_000 {
+            line.clear();
+            for _ in 0..rng.gen_range(0..32) {
+                line.push(*CHARS.choose(&mut rng).unwrap());
+            }
+            if let Some((comm, pid, tid)) = parse_event_line(&line) {
+                assert!(line.contains(comm) && line.contains(tid), "{:?}", line);
+                assert!(pid == "?" || line.contains(pid), "{:?}", line);
+            }
+            if let Some((pc, func, module)) = parse_stack_line(&line) {
+                assert!(line.contains(pc), "{:?}", line);
+                assert!(module == "[unknown]" || line.contains(module), "{:?}", line);
+                assert!(func == " " || line.contains(func), "{:?}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_module_fallback_normalizes_pc() {
+        let test_expectations = [
+            ("7F53389994D0", false, "[unknown <7f53389994d0>]"),
+            ("00007f53389994d0", false, "[unknown <7f53389994d0>]"),
+            ("0x7F53389994d0", false, "[unknown <7f53389994d0>]"),
+            ("7f53389994d0", true, "[unknown <0x7f53389994d0>]"),
+            ("0X00007F53389994D0", true, "[unknown <0x7f53389994d0>]"),
+            ("0", false, "[unknown <0>]"),
+            ("0", true, "[unknown <0x0>]"),
+            ("not-hex", true, "[unknown <not-hex>]"),
+        ];
+
+        for (pc, addr_prefix, expected) in test_expectations.iter() {
+            assert_eq!(
+                &with_module_fallback("[unknown]", "[unknown]", pc, true, *addr_prefix),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_event_metadata_name() {
+        let test_expectations = [
+            (" 72.176760:     257597 cycles:uppp:", Some("cycles")),
+            (" [002] 94564.109216: cycles:", Some("cycles")),
+            (" cpu-clock:", Some("cpu-clock")),
+            (" 100.000001:", None),
+            (" 100.000001: 250000", None),
+            (" 100.000001: :", None),
+            (" [000]", None),
+            ("", None),
+        ];
+
+        for (rest, expected) in test_expectations.iter() {
+            let (event, _) = event_metadata("app", "1001", "1001", rest);
+            assert_eq!(event.name, *expected, "{}", rest);
+        }
+    }
+
+    #[test]
+    fn test_event_parts_multi_word_comm() {
+        let line = "V8 WorkerThread 24636/25607 [000] 94564.109216: cycles:";
+        let (event, frame) = event_parts(line).unwrap();
+        assert_eq!(
+            event,
+            Event {
+                comm: "V8 WorkerThread",
+                pid: Some("24636"),
+                tid: "25607",
+                cpu: Some(0),
+                timestamp: Some("94564.109216"),
+                period: None,
+                name: Some("cycles"),
+                full_name: Some("cycles"),
+            }
+        );
+        assert_eq!(frame, None);
+    }
+
+    #[test]
+    fn test_header_period() {
+        let test_expectations = [
+            (
+                " event : name = cycles:uppp, , id = { 11 }, sample_type = IP|TID|TIME|PERIOD, disabled = 1",
+                Some(("cycles", "cycles")),
+            ),
+            (
+                " event : name = cpu-clock, , sample_type = IP|TID|PERIOD|IDENTIFIER",
+                Some(("cpu-clock", "nanoseconds")),
+            ),
+            (
+                " event : name = sched:sched_switch, , sample_type = IP|TID|TIME|CALLCHAIN|PERIOD",
+                Some(("sched", "events")),
+            ),
+            (
+                " event : name = cache-misses, , sample_type = IP|PERIOD",
+                Some(("cache-misses", "cache-misses")),
+            ),
+            (" event : name = cycles, , sample_type = IP|TID|TIME", None),
+            (" event : name = cycles", None),
+            (" cmdline : /usr/bin/perf record -e cycles", None),
+            (" captured on    : Tue Mar  5 10:12:44 2024", None),
+        ];
+
+        for (comment, expected) in test_expectations.iter() {
+            let period = header_period(comment);
+            let period = period
+                .as_ref()
+                .map(|(event, unit)| (event.as_str(), unit.as_str()));
+            assert_eq!(period, *expected, "{}", comment);
+        }
+    }
+
+    #[test]
+    fn test_parse_event_annotation() {
+        let annotation: EventAnnotation = "sched:sched_switch=_[o]".parse().unwrap();
+        assert_eq!(annotation.event, "sched:sched_switch");
+        assert_eq!(annotation.suffix, "_[o]");
+        assert!(annotation.is_match("sched", Some("sched:sched_switch")));
+        assert!(!annotation.is_match("cycles", Some("cycles:uppp")));
+
+        let annotation: EventAnnotation = "cyc*=_[c]".parse().unwrap();
+        assert!(annotation.is_match("cycles", Some("cycles:uppp")));
+
+        assert!("no-suffix".parse::<EventAnnotation>().is_err());
+        assert!("=_[o]".parse::<EventAnnotation>().is_err());
+    }
+
+    #[test]
+    fn test_parse_regex_annotation() {
+        let annotation: RegexAnnotation = "^foo=bar=_[fb]".parse().unwrap();
+        assert!(!annotation.match_module);
+        assert_eq!(annotation.regex.as_str(), "^foo=bar");
+        assert_eq!(annotation.suffix, "_[fb]");
+
+        let annotation: RegexAnnotation = "module:libc=_[c]".parse().unwrap();
+        assert!(annotation.match_module);
+        assert_eq!(annotation.regex.as_str(), "libc");
+        assert_eq!(annotation.suffix, "_[c]");
+        assert!(annotation.is_match("malloc", "/usr/lib/libc-2.28.so"));
+        assert!(!annotation.is_match("libc_start_main", "/usr/bin/app"));
+
+        assert!("no-suffix".parse::<RegexAnnotation>().is_err());
+        assert!("(unclosed=_[x]".parse::<RegexAnnotation>().is_err());
+    }
+
     static INPUT: Lazy<Vec<PathBuf>> = Lazy::new(|| {
         [
             "./flamegraph/example-perf-stacks.txt.gz",
@@ -764,6 +4656,23 @@ mod tests {
             "./tests/data/collapse-perf/java-inline.txt",
             "./tests/data/collapse-perf/weird-stack-line.txt",
             "./tests/data/collapse-perf/cpp-stacks-std-function.txt",
+            "./tests/data/collapse-perf/demangled-stacks.txt",
+            "./tests/data/collapse-perf/stderr-merged.txt",
+            "./tests/data/collapse-perf/comm-with-colon.txt",
+            "./tests/data/collapse-perf/cgroup-comm.txt",
+            "./tests/data/collapse-perf/empty-frames.txt",
+            "./tests/data/collapse-perf/multiple-events.txt",
+            "./tests/data/collapse-perf/tab-indented.txt",
+            "./tests/data/collapse-perf/wide-columns.txt",
+            "./tests/data/collapse-perf/vdso-unknown-module.txt",
+            "./tests/data/collapse-perf/gcc-clones.txt",
+            "./tests/data/collapse-perf/idle-stacks.txt",
+            "./tests/data/collapse-perf/zero-pcs.txt",
+            "./tests/data/collapse-perf/unknown-comms.txt",
+            "./tests/data/collapse-perf/module-runs.txt",
+            "./tests/data/collapse-perf/degenerate-events.txt",
+            "./tests/data/collapse-perf/plt-stubs.txt",
+            "./tests/data/collapse-perf/pid-column-augmented.txt",
         ]
         .iter()
         .map(PathBuf::from)
@@ -843,6 +4752,71 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_line_numbers_in_chunks() -> io::Result<()> {
+        // The first event is consumed by `pre_process` on the main thread...
+        let mut reader = &b"perf 1 1.0: cycles:\n\t1 main (/bin/perf)\n\n"[..];
+        let mut folder = Folder::default();
+        let mut occurrences = Occurrences::new(1);
+        folder.pre_process(&mut reader, &mut occurrences)?;
+
+        // ...so a chunk that is preceded by three more lines starts at line 7.
+        let mut worker = folder.clone_and_reset_stack_context();
+        worker.start_chunk(3);
+        testing_logger::setup();
+        let chunk = &b"perf 1 2.0: cycles:\n\tWEIRD\n\n"[..];
+        worker.collapse_single_threaded(chunk, &mut occurrences)?;
+        testing_logger::validate(|captured_logs| {
+            let bodies: Vec<_> = captured_logs.iter().map(|log| &log.body[..]).collect();
+            assert_eq!(bodies, ["line 8: Weird stack line: \tWEIRD"]);
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_deterministic_warnings() -> io::Result<()> {
+        let mut reader = &b"perf 1 1.0: 1 2 3 4 5 cycles:\n\t1 main (/bin/perf)\n\n"[..];
+        let mut folder = Folder::from(Options {
+            deterministic: true,
+            weight_column: Some(5),
+            ..Options::default()
+        });
+        let mut occurrences = Occurrences::new(1);
+        testing_logger::setup();
+        folder.pre_process(&mut reader, &mut occurrences)?;
+
+        // workers may finish their chunks in any order...
+        let chunks = [
+            (3, &b"perf 1 2.0: cycles:\n\tWEIRD\n\n"[..]),
+            (6, &b"perf 1 3.0: cycles:\n\tWEIRDER\n\n"[..]),
+        ];
+        for &(nlines_before, chunk) in chunks.iter().rev() {
+            let mut worker = folder.clone_and_reset_stack_context();
+            worker.start_chunk(nlines_before);
+            worker.collapse_single_threaded(chunk, &mut occurrences)?;
+        }
+
+        // ...but their warnings are logged by line, and those that are only logged once per input
+        // are not repeated by every worker
+        folder.post_process(&mut occurrences)?;
+        testing_logger::validate(|captured_logs| {
+            let bodies: Vec<_> = captured_logs
+                .iter()
+                .filter(|log| log.level == log::Level::Warn)
+                .map(|log| &log.body[..])
+                .collect();
+            assert_eq!(
+                bodies,
+                [
+                    "line 7: Weight column 5 is missing or not numeric on event line: perf 1 2.0: cycles:",
+                    "line 8: Weird stack line: \tWEIRD",
+                    "line 11: Weird stack line: \tWEIRDER",
+                ]
+            );
+        });
+        Ok(())
+    }
+
     /// Varies the nstacks_per_job parameter and outputs the 10 fastests configurations by file.
     ///
     /// Command: `cargo test bench_nstacks_perf --release -- --ignored --nocapture`
@@ -868,18 +4842,121 @@ mod tests {
         let mut count = 0;
 
         let inputs = common::testing::read_inputs(&INPUT)?;
+        let keep_frame = Regex::new("^[a-m]").unwrap();
+        let fold_wrappers = Regex::new("^_").unwrap();
+        let vdso = Regex::new("^__vdso_").unwrap();
 
         loop {
             let nstacks_per_job = rng.gen_range(1..=500);
             let options = Options {
+                addr_prefix: rng.gen(),
+                annotate_event: if rng.gen() {
+                    vec!["cycles=_[c]".parse().unwrap()]
+                } else {
+                    Vec::default()
+                },
                 annotate_jit: rng.gen(),
                 annotate_kernel: rng.gen(),
+                annotate_regex: if rng.gen() {
+                    vec!["module:^/usr/lib/=_[u]".parse().unwrap()]
+                } else {
+                    Vec::default()
+                },
+                canonical_event: rng.gen(),
+                case_fold_frames: rng.gen(),
+                category_map: if rng.gen() {
+                    Some("^[a-m]=early\n^[n-z]=late".parse().unwrap())
+                } else {
+                    None
+                },
+                collect_warnings: rng.gen(),
+                comment_prefix: None,
+                cpus_per_node: if rng.gen() { Some(2) } else { None },
+                custom_parser: None,
+                cut_above: None,
+                cut_below: None,
+                cut_leafmost: rng.gen(),
+                debug_counts: false,
+                deopt_regex: None,
+                deterministic: rng.gen(),
+                dump_lines: if rng.gen() { Some(3) } else { None },
                 event_filter: None,
+                flat_profile: rng.gen(),
+                fold_closures: rng.gen(),
+                fold_gcc_clones: rng.gen(),
+                fold_module: if rng.gen() {
+                    vec!["libc".to_string()]
+                } else {
+                    Vec::new()
+                },
+                fold_plt: rng.gen(),
+                fold_wrappers: if rng.gen() {
+                    Some(fold_wrappers.clone())
+                } else {
+                    None
+                },
+                fold_wrappers_innermost: rng.gen(),
+                from_folded: false,
+                group_by_tid_lifetime: if rng.gen() { Some(0.5) } else { None },
+                hot_leaves: rng.gen(),
+                idle_frames: Vec::default(),
+                ignore_prefix: Vec::default(),
                 include_addrs: rng.gen(),
                 include_pid: rng.gen(),
                 include_tid: rng.gen(),
+                keep_frame: if rng.gen() {
+                    Some(keep_frame.clone())
+                } else {
+                    None
+                },
+                keep_pname_spaces: rng.gen(),
+                limit_events: None,
+                mark_transitions: rng.gen(),
+                max_malformed: None,
+                max_stack_frames: DEFAULT_MAX_STACK_FRAMES,
+                max_unknown_ratio: None,
+                min_width_other: false,
+                min_width_pct: None,
+                module_basename: rng.gen(),
+                no_annotate_unknown: rng.gen(),
+                no_idle: rng.gen(),
                 nthreads: rng.gen_range(2..=32),
+                only_domain: [None, Some(Domain::Kernel), Some(Domain::User)][rng.gen_range(0..3)],
+                plt_regex: None,
+                pname_max_len: if rng.gen() { Some(8) } else { None },
+                process_only: rng.gen(),
+                root_frame: if rng.gen() {
+                    Some("all".to_string())
+                } else {
+                    None
+                },
+                runs: false,
+                sample_rate: None,
+                scale_divisor: if rng.gen() { Some(3) } else { None },
                 skip_after: Vec::default(),
+                sort_by_appearance: false,
+                stack_hook: None,
+                stats: false,
+                strict: false,
+                strip_ansi: rng.gen(),
+                strip_comm_prefix: Vec::default(),
+                strip_path_prefix: Vec::default(),
+                symbol_map: None,
+                tag_all: if rng.gen() {
+                    Some("host".to_string())
+                } else {
+                    None
+                },
+                time_end: None,
+                time_start: None,
+                timeline: false,
+                topk: None,
+                trim_to_domain: rng.gen(),
+                unknown_comm: "unknown".to_string(),
+                validate_pcs: rng.gen(),
+                vdso_regex: if rng.gen() { Some(vdso.clone()) } else { None },
+                weight_column: if rng.gen() { Some(1) } else { None },
+                weight_from_header: rng.gen(),
             };
 
             for (path, input) in inputs.iter() {
@@ -0,0 +1,239 @@
+use std::io::{self, BufRead};
+
+use log::warn;
+
+use crate::collapse::common::Occurrences;
+use crate::collapse::Collapse;
+
+/// `perf trace` folder configuration options.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct Options {
+    /// Include the TID with the process name, e.g. `ls-2187`.
+    ///
+    /// Default is `false`.
+    pub include_tid: bool,
+}
+
+/// A stack collapser for the output of `perf trace`.
+///
+/// Every system call is counted once, as a stack of the process name, the call graph of the
+/// system call (if any), and the name of the system call itself. For example,
+///
+/// ```text
+///      0.254 ( 0.004 ms): ls/2187 openat(dfd: CWD, filename: 0x6f3f0d90) = 3
+///                                        __GI___open64_nocancel (/usr/lib/libc-2.31.so)
+///                                        main (/usr/bin/ls)
+/// ```
+///
+/// becomes `ls;main;__GI___open64_nocancel;openat 1`. Input with call graphs can be produced with
+/// `perf trace --call-graph dwarf -o trace.txt -- <command>`.
+///
+/// To construct one, either use `perf_trace::Folder::default()` or create an [`Options`] and use
+/// `perf_trace::Folder::from(options)`.
+#[derive(Clone, Default)]
+pub struct Folder {
+    /// The process name and system call of the current event, if we're in one.
+    event: Option<(String, String)>,
+
+    /// The number of the input line being processed, counting from 1.
+    line_number: usize,
+
+    /// Function on the stack in this event thus far, leaf first.
+    stack: Vec<String>,
+
+    opt: Options,
+}
+
+impl Collapse for Folder {
+    fn collapse<R, W>(&mut self, mut reader: R, writer: W) -> io::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        let mut occurrences = Occurrences::new(1);
+        let mut line = Vec::new();
+        self.line_number = 0;
+        loop {
+            line.clear();
+            if reader.read_until(0x0A, &mut line)? == 0 {
+                break;
+            }
+            self.line_number += 1;
+            let l = String::from_utf8_lossy(&line);
+            self.on_line(l.trim_end(), &mut occurrences);
+        }
+        self.after_event(&mut occurrences);
+
+        occurrences.write_and_clear(writer)
+    }
+
+    /// Check whether the first line is a `perf trace` event line.
+    fn is_applicable(&mut self, input: &str) -> Option<bool> {
+        let mut input = input.as_bytes();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if let Ok(n) = input.read_line(&mut line) {
+                if n == 0 {
+                    break;
+                }
+            } else {
+                return Some(false);
+            }
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return Some(event_line_parts(line).is_some());
+        }
+        None
+    }
+}
+
+impl From<Options> for Folder {
+    fn from(opt: Options) -> Self {
+        Folder {
+            opt,
+            ..Default::default()
+        }
+    }
+}
+
+impl Folder {
+    fn on_line(&mut self, line: &str, occurrences: &mut Occurrences) {
+        if line.trim_start().starts_with('#') {
+            return;
+        }
+
+        if line.trim().is_empty() {
+            self.after_event(occurrences);
+        } else if let Some(parts) = event_line_parts(line) {
+            // events are not necessarily separated by empty lines
+            self.after_event(occurrences);
+            if let Some((comm, tid, syscall)) = parts {
+                let mut pname = comm.replace(' ', "_");
+                if self.opt.include_tid {
+                    pname.push('-');
+                    pname.push_str(tid);
+                }
+                self.event = Some((pname, syscall.to_string()));
+            }
+        } else if self.event.is_some() {
+            match stack_line_func(line) {
+                Some(func) => self.stack.push(func.replace(';', ":")),
+                None => warn!("line {}: Weird stack line: {}", self.line_number, line),
+            }
+        } else {
+            warn!("line {}: Weird event line: {}", self.line_number, line);
+        }
+    }
+
+    fn after_event(&mut self, occurrences: &mut Occurrences) {
+        if let Some((pname, syscall)) = self.event.take() {
+            let mut stack_str = pname;
+            for func in self.stack.drain(..).rev() {
+                stack_str.push(';');
+                stack_str.push_str(&func);
+            }
+            stack_str.push(';');
+            stack_str.push_str(&syscall);
+            occurrences.insert_or_add(stack_str, 1);
+        }
+        self.stack.clear();
+    }
+}
+
+// Parses an event line, like:
+//
+//     0.254 ( 0.004 ms): ls/2187 openat(dfd: CWD, filename: 0x6f3f0d90) = 3
+//
+// into `Some(Some(("ls", "2187", "openat")))`. A system call that doesn't return right away is
+// printed twice, first as unfinished, and then as continued once it returns:
+//
+//     500.391 (         ): sleep/2188 nanosleep(rqtp: 0x7ffd5e3fa4f0) ...
+//     1500.512 (1000.121 ms): sleep/2188  ... [continued]: nanosleep()) = 0
+//
+// Only the latter (which also carries the call graph) is counted; the former is recognized as an
+// event line, but yields `Some(None)`.
+#[allow(clippy::option_option)]
+fn event_line_parts(line: &str) -> Option<Option<(&str, &str, &str)>> {
+    let (timestamp, rest) = line.trim_start().split_once(' ')?;
+    let (secs, fraction) = timestamp.split_once('.')?;
+    if !is_all_digits(secs) || !is_all_digits(fraction) {
+        return None;
+    }
+    let (_duration, rest) = rest.trim_start().strip_prefix('(')?.split_once("): ")?;
+
+    // the comm may contain spaces and slashes, so look for the first `/TID ` that follows it
+    let (comm, tid, call) = rest.match_indices('/').find_map(|(slash, _)| {
+        let after = &rest[(slash + 1)..];
+        let tid_len = after.find(|c: char| !c.is_ascii_digit())?;
+        let (tid, call) = after.split_at(tid_len);
+        if tid.is_empty() || !call.starts_with(' ') {
+            return None;
+        }
+        Some((&rest[..slash], tid, call.trim_start()))
+    })?;
+    if comm.is_empty() {
+        return None;
+    }
+
+    let (call, unfinished) = match call.strip_prefix("... [continued]: ") {
+        Some(call) => (call, false),
+        None => (call, call.ends_with(" ...")),
+    };
+    let syscall = &call[..call.find('(')?];
+    if syscall.is_empty()
+        || !syscall
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    {
+        return None;
+    }
+
+    if unfinished {
+        Some(None)
+    } else {
+        Some(Some((comm, tid, syscall)))
+    }
+}
+
+// Extracts the function name from a call graph line, like:
+//
+//     __GI___open64_nocancel+0x1f (/usr/lib/libc-2.31.so)
+//     7f1e2215d058 __GI___open64_nocancel (/usr/lib/libc-2.31.so)
+//     [unknown] ([unknown])
+fn stack_line_func(line: &str) -> Option<&str> {
+    let mut line = line.trim();
+    if let Some((addr, rest)) = line.split_once(' ') {
+        if addr.bytes().all(|b| b.is_ascii_hexdigit()) {
+            line = rest.trim_start();
+        }
+    }
+
+    // strip the module, e.g. ` (/usr/lib/libc-2.31.so)`
+    if let Some(module_start) = line.rfind(" (") {
+        if line.ends_with(')') {
+            line = line[..module_start].trim_end();
+        }
+    }
+
+    // strip the symbol offset, e.g. `+0x1f`
+    if let Some(offset) = line.rfind("+0x") {
+        if line[(offset + 3)..].bytes().all(|b| b.is_ascii_hexdigit()) {
+            line = &line[..offset];
+        }
+    }
+
+    if line.is_empty() {
+        None
+    } else {
+        Some(line)
+    }
+}
+
+fn is_all_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
@@ -1,4 +1,6 @@
 pub(super) mod java {
+    use crate::collapse::annotation::trailing_annotation;
+    use crate::collapse::Annotation;
     use crate::flamegraph::color::BasicPalette;
 
     /// Handle both annotations (_[j], _[i], ...; which are
@@ -6,20 +8,12 @@ pub(super) mod java {
     /// best as possible. Without annotations, we get a little hacky
     /// and match on java|org|com, etc.
     pub(in super::super) fn resolve(name: &str) -> BasicPalette {
-        if name.ends_with(']') {
-            if let Some(ai) = name.rfind("_[") {
-                if name[ai..].len() == 4 {
-                    match &name[ai + 2..ai + 3] {
-                        // kernel annotation
-                        "k" => return BasicPalette::Orange,
-                        // inline annotation
-                        "i" => return BasicPalette::Aqua,
-                        // jit annotation
-                        "j" => return BasicPalette::Green,
-                        _ => {}
-                    }
-                }
-            }
+        match trailing_annotation(name) {
+            Some(Annotation::Kernel) => return BasicPalette::Orange,
+            Some(Annotation::Inline) => return BasicPalette::Aqua,
+            Some(Annotation::Jit) => return BasicPalette::Green,
+            Some(Annotation::Deopt) => return BasicPalette::Purple,
+            _ => {}
         }
 
         let java_prefix = name.strip_prefix('L').unwrap_or(name);
@@ -44,10 +38,11 @@ pub(super) mod java {
 }
 
 pub(super) mod perl {
+    use crate::collapse::Annotation;
     use crate::flamegraph::color::BasicPalette;
 
     pub(in super::super) fn resolve(name: &str) -> BasicPalette {
-        if name.ends_with("_[k]") {
+        if name.ends_with(Annotation::Kernel.suffix()) {
             BasicPalette::Orange
         } else if name.contains("Perl") || name.contains(".pl") {
             BasicPalette::Green
@@ -60,14 +55,17 @@ pub(super) mod perl {
 }
 
 pub(super) mod js {
+    use crate::collapse::Annotation;
     use crate::flamegraph::color::BasicPalette;
 
     pub(in super::super) fn resolve(name: &str) -> BasicPalette {
         if !name.is_empty() && name.trim().is_empty() {
             return BasicPalette::Green;
-        } else if name.ends_with("_[k]") {
+        } else if name.ends_with(Annotation::Kernel.suffix()) {
             return BasicPalette::Orange;
-        } else if name.ends_with("_[j]") {
+        } else if name.ends_with(Annotation::Deopt.suffix()) {
+            return BasicPalette::Blue;
+        } else if name.ends_with(Annotation::Jit.suffix()) {
             if name.contains('/') {
                 return BasicPalette::Green;
             } else {
@@ -161,6 +159,10 @@ mod tests {
                 input: String::from("_[k]_[j]"),
                 output: BasicPalette::Green,
             },
+            TestData {
+                input: String::from("Interpreter_[d]"),
+                output: BasicPalette::Purple,
+            },
             TestData {
                 input: String::from("_[]_[j]"),
                 output: BasicPalette::Green,
@@ -458,6 +460,10 @@ mod tests {
                 input: String::from("something_[j]"),
                 output: BasicPalette::Aqua,
             },
+            TestData {
+                input: String::from("JS:~main /app/index.js:10_[d]"),
+                output: BasicPalette::Blue,
+            },
             TestData {
                 input: String::from("some::thing"),
                 output: BasicPalette::Yellow,
@@ -25,6 +25,8 @@ use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::Writer;
 use str_stack::StrStack;
 
+use crate::collapse::annotation::trailing_annotation;
+
 #[cfg(feature = "nameattr")]
 use self::attrs::FrameAttrs;
 
@@ -44,6 +46,10 @@ const FRAMEPAD: usize = 1; // vertical padding for frames
 // the embedded JavaScript.
 const DEFAULT_IMAGE_WIDTH: usize = 1200;
 
+// The comment line that sets the count type label, unless one was given; see
+// `Options::count_name`.
+const COUNT_NAME_COMMENT: &str = "# countname ";
+
 /// Default values for [`Options`].
 pub mod defaults {
     macro_rules! doc {
@@ -188,6 +194,9 @@ pub struct Options<'a> {
 
     /// Count type label for the flame graph.
     ///
+    /// If this is left at its default, and the input has a `# countname UNIT` comment line (as
+    /// written by `inferno-collapse-perf --emit-metadata`), `UNIT` is used instead.
+    ///
     /// [Default value](defaults::COUNT_NAME).
     pub count_name: String,
 
@@ -400,10 +409,13 @@ where
     W: Write,
 {
     let mut reversed = StrStack::new();
-    let lines = lines
-        .into_iter()
-        .map(|line| line.trim())
-        .filter(|line| !(line.is_empty() || line.starts_with("# ")));
+    let mut input_count_name = None;
+    let lines = lines.into_iter().map(|line| line.trim()).filter(|line| {
+        if let Some(count_name) = line.strip_prefix(COUNT_NAME_COMMENT) {
+            input_count_name = Some(count_name.trim().to_string());
+        }
+        !(line.is_empty() || line.starts_with("# "))
+    });
 
     let (mut frames, time, ignored, delta_max) = if opt.reverse_stack_order {
         if opt.no_sort {
@@ -454,6 +466,11 @@ where
         warn!("Ignored {} lines with invalid format", ignored);
     }
 
+    let count_name = match input_count_name {
+        Some(count_name) if opt.count_name == defaults::COUNT_NAME => count_name,
+        _ => opt.count_name.clone(),
+    };
+
     let mut buffer = StrStack::new();
 
     // let's start writing the svg!
@@ -586,7 +603,7 @@ where
         let samples_txt = samples_txt_buffer.as_str();
 
         let info = if frame.location.function.is_empty() && frame.location.depth == 0 {
-            write!(buffer, "all ({} {}, 100%)", samples_txt, opt.count_name)
+            write!(buffer, "all ({} {}, 100%)", samples_txt, count_name)
         } else {
             let pct = (100 * samples) as f64 / (timemax as f64 * opt.factor);
             let function = deannotate(frame.location.function);
@@ -594,13 +611,13 @@ where
                 None => write!(
                     buffer,
                     "{} ({} {}, {:.2}%)",
-                    function, samples_txt, opt.count_name, pct
+                    function, samples_txt, count_name, pct
                 ),
                 // Special case delta == 0 so we don't format percentage with a + sign.
                 Some(delta) if delta == 0 => write!(
                     buffer,
                     "{} ({} {}, {:.2}%; 0.00%)",
-                    function, samples_txt, opt.count_name, pct,
+                    function, samples_txt, count_name, pct,
                 ),
                 Some(mut delta) => {
                     if opt.negate_differentials {
@@ -610,7 +627,7 @@ where
                     write!(
                         buffer,
                         "{} ({} {}, {:.2}%; {:+.2}%)",
-                        function, samples_txt, opt.count_name, pct, delta_pct
+                        function, samples_txt, count_name, pct, delta_pct
                     )
                 }
             }
@@ -856,14 +873,10 @@ pub fn from_files<W: Write>(
 }
 
 fn deannotate(f: &str) -> &str {
-    if f.ends_with(']') {
-        if let Some(ai) = f.rfind("_[") {
-            if f[ai..].len() == 4 && "kwij".contains(&f[ai + 2..ai + 3]) {
-                return &f[..ai];
-            }
-        }
+    match trailing_annotation(f) {
+        Some(annotation) => &f[..f.len() - annotation.suffix().len()],
+        None => f,
     }
-    f
 }
 
 fn filled_rectangle<W: Write>(
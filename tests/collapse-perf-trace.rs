@@ -0,0 +1,86 @@
+mod common;
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor};
+use std::process::Command;
+
+use assert_cmd::prelude::*;
+use inferno::collapse::perf_trace::{Folder, Options};
+use inferno::collapse::Collapse;
+use log::Level;
+
+fn test_collapse_perf_trace(
+    test_file: &str,
+    expected_file: &str,
+    options: Options,
+) -> io::Result<()> {
+    common::test_collapse(Folder::from(options), test_file, expected_file, false)
+}
+
+#[test]
+fn collapse_perf_trace_default() {
+    let test_file = "./tests/data/collapse-perf-trace/perf-trace.txt";
+    let result_file = "./tests/data/collapse-perf-trace/results/perf-trace-collapsed.txt";
+    test_collapse_perf_trace(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_perf_trace_tid() {
+    let test_file = "./tests/data/collapse-perf-trace/perf-trace.txt";
+    let result_file = "./tests/data/collapse-perf-trace/results/perf-trace-collapsed-tid.txt";
+
+    let mut options = Options::default();
+    options.include_tid = true;
+
+    test_collapse_perf_trace(test_file, result_file, options).unwrap()
+}
+
+#[test]
+fn collapse_perf_trace_call_graph() {
+    let test_file = "./tests/data/collapse-perf-trace/perf-trace-call-graph.txt";
+    let result_file =
+        "./tests/data/collapse-perf-trace/results/perf-trace-call-graph-collapsed.txt";
+    test_collapse_perf_trace(test_file, result_file, Options::default()).unwrap()
+}
+
+#[test]
+fn collapse_perf_trace_should_warn_about_perf_script_input() {
+    common::test_collapse_logs(
+        Folder::default(),
+        "./tests/data/collapse-perf/demangled-stacks.txt",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| log.body.contains(": Weird event line: ") && log.level == Level::Warn)
+                .count();
+            assert!(nwarnings > 0, "expected warnings about weird event lines");
+        },
+    );
+}
+
+#[test]
+fn collapse_perf_trace_is_applicable() {
+    let mut folder = Folder::default();
+    let input = std::fs::read_to_string("./tests/data/collapse-perf-trace/perf-trace.txt").unwrap();
+    assert_eq!(folder.is_applicable(&input), Some(true));
+
+    let input = std::fs::read_to_string("./tests/data/collapse-perf/demangled-stacks.txt").unwrap();
+    assert_eq!(folder.is_applicable(&input), Some(false));
+}
+
+#[test]
+fn collapse_perf_trace_cli() {
+    let input_file = "./tests/data/collapse-perf-trace/perf-trace-call-graph.txt";
+    let expected_file =
+        "./tests/data/collapse-perf-trace/results/perf-trace-call-graph-collapsed.txt";
+
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--format")
+        .arg("perf-trace")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+}
@@ -1,14 +1,19 @@
 mod common;
 
-use std::fs::File;
-use std::io::{self, BufReader, Cursor};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read};
 use std::path::Path;
 use std::process::{Command, Stdio};
 
 use assert_cmd::cargo::CommandCargoExt;
-use inferno::collapse::perf::{Folder, Options};
+use inferno::collapse::perf::{
+    read_events, CategoryMap, CustomParser, Domain, Event, EventSink, Folder, LineKind, LineParser,
+    Options, PerfScriptParser, PerfState, StackHook, SymbolMap, WarningKind,
+};
+use inferno::collapse::Collapse;
 use log::Level;
 use pretty_assertions::assert_eq;
+use regex::Regex;
 use testing_logger::CapturedLog;
 
 fn test_collapse_perf(
@@ -59,6 +64,15 @@ fn options_from_vec(opt_vec: Vec<&str>) -> Options {
                 options.annotate_jit = true;
                 options.annotate_kernel = true;
             }
+            "kernelonly" => options.only_domain = Some(Domain::Kernel),
+            "useronly" => options.only_domain = Some(Domain::User),
+            "trim" => options.trim_to_domain = true,
+            "transitions" => options.mark_transitions = true,
+            "noannotateunknown" => options.no_annotate_unknown = true,
+            "foldclosures" => options.fold_closures = true,
+            "foldgccclones" => options.fold_gcc_clones = true,
+            "foldplt" => options.fold_plt = true,
+            "processonly" => options.process_only = true,
             opt => panic!("invalid option: {}", opt),
         }
     }
@@ -231,6 +245,41 @@ collapse_perf_tests! {
     collapse_perf_no_events,
     collapse_perf_single_line_stacks,
     collapse_perf_single_event,
+    collapse_perf_demangled_stacks,
+    collapse_perf_mixed_case_addrs__addrs,
+    collapse_perf_comm_with_colon__tid,
+    collapse_perf_cgroup_comm__tid,
+    collapse_perf_pid_column_standard__tid,
+    collapse_perf_pid_column_augmented__tid,
+    collapse_perf_v8_worker_thread,
+    collapse_perf_v8_worker_thread__tid,
+    collapse_perf_empty_frames,
+    collapse_perf_multiple_events,
+    collapse_perf_degenerate_events,
+    collapse_perf_tab_indented,
+    collapse_perf_wide_columns,
+    collapse_perf_closures,
+    collapse_perf_closures__foldclosures,
+    collapse_perf_gcc_clones,
+    collapse_perf_gcc_clones__foldgccclones,
+    collapse_perf_zero_pcs,
+    collapse_perf_unknown_comms,
+    collapse_perf_module_runs,
+    collapse_perf_plt_stubs,
+    collapse_perf_plt_stubs__foldplt,
+    collapse_perf_single_line_stacks__processonly,
+    collapse_perf_kernel_user_stacks__processonly,
+    collapse_perf_kernel_user_stacks__processonly_kernelonly,
+    collapse_perf_multiple_events__processonly,
+    collapse_perf_unknown_kernel_module__kernel,
+    collapse_perf_unknown_kernel_module__kernel_transitions_noannotateunknown,
+    collapse_perf_kernel_user_stacks,
+    collapse_perf_kernel_user_stacks__kernelonly,
+    collapse_perf_kernel_user_stacks__useronly,
+    collapse_perf_kernel_user_stacks__transitions,
+    collapse_perf_kernel_user_stacks__kernel_transitions,
+    collapse_perf_kernel_user_stacks__kernelonly_trim,
+    collapse_perf_kernel_user_stacks__useronly_trim,
     collapse_perf_go_stacks,
     collapse_perf_java_inline,
     collapse_perf_versioned_vmlinux__kernel,
@@ -248,6 +297,1298 @@ fn collapse_perf_example_perf_stacks() {
     .unwrap();
 }
 
+#[test]
+fn collapse_perf_weight_column() {
+    let mut options = Options::default();
+    options.weight_column = Some(1);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/weight-column.txt",
+        "./tests/data/collapse-perf/results/weight-column-collapsed.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_scale_divisor() {
+    let mut options = Options::default();
+    options.scale_divisor = Some(100);
+    options.weight_column = Some(1);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/weight-column.txt",
+        "./tests/data/collapse-perf/results/weight-column-collapsed-scale-divisor.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_sample_rate() {
+    // Every other event is kept (starting with the first), and its count doubled.
+    let mut options = Options::default();
+    options.sample_rate = Some(2);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/kernel-user-stacks.txt",
+        "./tests/data/collapse-perf/results/kernel-user-stacks-collapsed-sample-rate.txt",
+        options,
+        false,
+    )
+    .unwrap();
+
+    // A rate of one processes every event.
+    let mut options = Options::default();
+    options.sample_rate = Some(1);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/kernel-user-stacks.txt",
+        "./tests/data/collapse-perf/results/kernel-user-stacks-collapsed.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_topk() {
+    // With room for every stack, the counts are exact...
+    let mut options = Options::default();
+    options.topk = Some(2);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/demangled-stacks.txt",
+        "./tests/data/collapse-perf/results/demangled-stacks-collapsed.txt",
+        options,
+        false,
+    )
+    .unwrap();
+
+    // ...otherwise, evicted stacks add to the count of the stacks that replace them.
+    let mut options = Options::default();
+    options.topk = Some(1);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/demangled-stacks.txt",
+        "./tests/data/collapse-perf/results/demangled-stacks-collapsed-topk.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_category_map() {
+    let mut options = Options::default();
+    options.category_map = Some(
+        fs::read_to_string("./tests/data/collapse-perf/category-map.txt")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    test_collapse_perf(
+        "./tests/data/collapse-perf/cgroup-comm.txt",
+        "./tests/data/collapse-perf/results/cgroup-comm-collapsed-category-map.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_category_map_invalid() {
+    let err = "^swapper=idle\n\n(=broken"
+        .parse::<CategoryMap>()
+        .unwrap_err();
+    assert!(err.starts_with("line 3: invalid regex"), "{}", err);
+    let err = "idle".parse::<CategoryMap>().unwrap_err();
+    assert_eq!(err, "line 1: expected RE=CATEGORY, got: idle");
+}
+
+#[test]
+fn collapse_perf_stats() {
+    let input_file = "./tests/data/collapse-perf/runs.txt";
+    for runs in [false, true] {
+        let mut options = Options::default();
+        options.stats = true;
+        options.runs = runs;
+        let mut folder = Folder::from(options);
+        folder.collapse_file(Some(input_file), io::sink()).unwrap();
+        let stats = folder.stats();
+        assert_eq!(stats.nevents, 9);
+        if runs {
+            assert_eq!(stats.longest_run, Some((3, "app;main;spin".to_string())));
+        } else {
+            assert_eq!(stats.longest_run, None);
+        }
+    }
+
+    // suspicious program counters are only counted if requested
+    for validate_pcs in [false, true] {
+        let mut options = Options::default();
+        options.stats = true;
+        options.validate_pcs = validate_pcs;
+        let mut folder = Folder::from(options);
+        folder
+            .collapse_file(Some("./tests/data/collapse-perf/zero-pcs.txt"), io::sink())
+            .unwrap();
+        let expected = if validate_pcs { Some(2) } else { None };
+        assert_eq!(folder.stats().suspicious_pcs, expected);
+    }
+
+    // the depths of the stacks are kept as a histogram
+    let mut options = Options::default();
+    options.stats = true;
+    let mut folder = Folder::from(options);
+    folder
+        .collapse_file(
+            Some("./tests/data/collapse-perf/kernel-user-stacks.txt"),
+            io::sink(),
+        )
+        .unwrap();
+    let stats = folder.stats();
+    assert_eq!(stats.depths.len(), 3);
+    assert_eq!(stats.depth_percentile(0), Some(2));
+    assert_eq!(stats.depth_percentile(40), Some(3));
+    assert_eq!(stats.depth_percentile(50), Some(3));
+    assert_eq!(stats.depth_percentile(61), Some(5));
+    assert_eq!(stats.depth_percentile(100), Some(5));
+
+    assert!(stats.frame_counts.is_empty());
+
+    // frames are counted once per event, even if they recurse
+    let mut options = Options::default();
+    options.stats = true;
+    options.debug_counts = true;
+    let mut folder = Folder::from(options);
+    folder.collapse_file(Some(input_file), io::sink()).unwrap();
+    let frame_counts = &folder.stats().frame_counts;
+    assert_eq!(frame_counts.len(), 4);
+    assert_eq!(frame_counts["main"], 9);
+    assert_eq!(frame_counts["spin"], 5);
+    assert_eq!(frame_counts["compute"], 3);
+    assert_eq!(frame_counts["read"], 1);
+
+    // stats are only gathered on request
+    let mut folder = Folder::default();
+    folder.collapse_file(Some(input_file), io::sink()).unwrap();
+    assert_eq!(folder.stats().nevents, 0);
+    assert_eq!(folder.stats().depth_percentile(50), None);
+}
+
+#[test]
+fn collapse_perf_fold_wrappers() {
+    let wrappers = Regex::new("^(__libc_write|write|__write)$").unwrap();
+    for &(innermost, name) in &[(false, "fold-wrappers"), (true, "fold-wrappers-innermost")] {
+        let mut options = Options::default();
+        options.annotate_kernel = true;
+        options.fold_wrappers = Some(wrappers.clone());
+        options.fold_wrappers_innermost = innermost;
+        let expected_file = format!(
+            "./tests/data/collapse-perf/results/syscall-wrappers-collapsed-{}.txt",
+            name
+        );
+        test_collapse_perf(
+            "./tests/data/collapse-perf/syscall-wrappers.txt",
+            &expected_file,
+            options,
+            false,
+        )
+        .unwrap();
+    }
+}
+
+#[test]
+fn collapse_perf_flat_profile() {
+    let mut options = Options::default();
+    options.annotate_kernel = true;
+    options.flat_profile = true;
+    let mut folder = Folder::from(options);
+    folder
+        .collapse_file(
+            Some("./tests/data/collapse-perf/cut-markers.txt"),
+            io::sink(),
+        )
+        .unwrap();
+    let profile = folder.flat_profile();
+    assert_eq!(profile.total, 3);
+    assert_eq!(profile.functions.len(), 7);
+    assert_eq!(profile.functions["main"], (3, 0));
+    assert_eq!(profile.functions["run"], (2, 0));
+    // recursive functions are counted once per event
+    assert_eq!(profile.functions["walk"], (1, 0));
+    assert_eq!(profile.functions["visit"], (1, 1));
+    assert_eq!(profile.functions["futex_wait_[k]"], (1, 1));
+    assert!(!profile.functions.contains_key("app"));
+}
+
+#[test]
+fn collapse_perf_weight_from_header() {
+    let mut options = Options::default();
+    options.weight_from_header = true;
+    let mut folder = Folder::from(options);
+    let mut folded = Vec::new();
+    folder
+        .collapse_file(
+            Some("./tests/data/collapse-perf/header-periods.txt"),
+            &mut folded,
+        )
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(folded).unwrap(),
+        "app;main;compute 650000\napp;main;spin 120000\n"
+    );
+    assert_eq!(folder.count_name(), Some("cycles"));
+
+    let mut options = Options::default();
+    options.event_filter = Some("cpu-clock".to_string());
+    options.nthreads = 2;
+    options.weight_from_header = true;
+    let mut folder = Folder::from(options);
+    let mut folded = Vec::new();
+    folder
+        .collapse_file(
+            Some("./tests/data/collapse-perf/header-periods.txt"),
+            &mut folded,
+        )
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(folded).unwrap(),
+        "app;main;compute 250000\n"
+    );
+    assert_eq!(folder.count_name(), Some("nanoseconds"));
+
+    // without the metadata, every sample is counted once
+    let mut options = Options::default();
+    options.weight_from_header = true;
+    let mut folder = Folder::from(options);
+    folder
+        .collapse_file(
+            Some("./tests/data/collapse-perf/kernel-user-stacks.txt"),
+            io::sink(),
+        )
+        .unwrap();
+    assert_eq!(folder.count_name(), None);
+}
+
+#[test]
+fn collapse_perf_pname_max_len() {
+    let test_file = "./tests/data/collapse-perf/long-comm.txt";
+    let result_file = "./tests/data/collapse-perf/results/long-comm-collapsed-pname-max-len.txt";
+    let mut options = Options::default();
+    options.pname_max_len = Some(14);
+    test_collapse_perf(test_file, result_file, options, false).unwrap();
+
+    // only the process name is truncated, not the PID/TID after it
+    let result_file =
+        "./tests/data/collapse-perf/results/long-comm-collapsed-pname-max-len-tid.txt";
+    let mut options = Options::default();
+    options.include_tid = true;
+    options.pname_max_len = Some(14);
+    test_collapse_perf(test_file, result_file, options, false).unwrap();
+}
+
+#[test]
+fn collapse_perf_time_window() {
+    let test_file = "./tests/data/collapse-perf/runs.txt";
+    let result_file = "./tests/data/collapse-perf/results/runs-collapsed-time-window.txt";
+    let mut options = Options::default();
+    options.time_start = Some(100.000003);
+    options.time_end = Some(100.000006);
+    test_collapse_perf(test_file, result_file, options, false).unwrap();
+
+    // events without a timestamp are dropped
+    let test_file = "./tests/data/collapse-perf/no-timestamps.txt";
+    let mut options = Options::default();
+    options.time_start = Some(0.0);
+    let mut folder = Folder::from(options);
+    let mut folded = Vec::new();
+    folder.collapse_file(Some(test_file), &mut folded).unwrap();
+    assert!(folded.is_empty());
+}
+
+#[test]
+fn collapse_perf_deopt_regex() {
+    let mut options = Options::default();
+    options.annotate_jit = true;
+    options.deopt_regex =
+        Some(Regex::new("^(LazyCompile|JS):~|^Builtin:Interpreter|^Interpreter$").unwrap());
+    test_collapse_perf(
+        "./tests/data/collapse-perf/jit-deopt.txt",
+        "./tests/data/collapse-perf/results/jit-deopt-collapsed-deopt-regex.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_event_filter_glob() {
+    let test_file = "./tests/data/collapse-perf/sched-tracepoints.txt";
+    for &(event_filter, suffix) in &[
+        ("sched:*", "sched"),
+        ("sched:sched_w*", "sched-wakeup"),
+        ("cycles:?ppp", "cycles"),
+        // without wildcards, only the name before the first `:` is matched, as before
+        ("sched:sched_switch", "none"),
+    ] {
+        let result_file = format!(
+            "./tests/data/collapse-perf/results/sched-tracepoints-collapsed-event-filter-{}.txt",
+            suffix
+        );
+        let mut options = Options::default();
+        options.event_filter = Some(event_filter.to_string());
+        test_collapse_perf(test_file, &result_file, options, false).unwrap();
+    }
+}
+
+#[test]
+fn collapse_perf_strip_path_prefix() {
+    // the longest prefix is removed, so the module of `helper` becomes `lib/libhelper.so`
+    let mut options = Options::default();
+    options.strip_path_prefix = vec!["/home/ci/".to_string(), "/home/ci/build/".to_string()];
+    options.annotate_regex = vec!["module:^lib/=_[l]".parse().unwrap()];
+    test_collapse_perf(
+        "./tests/data/collapse-perf/build-paths.txt",
+        "./tests/data/collapse-perf/results/build-paths-collapsed-strip-path-prefix.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_case_fold_frames() {
+    let mut options = Options::default();
+    options.case_fold_frames = true;
+    test_collapse_perf(
+        "./tests/data/collapse-perf/windows-casing.txt",
+        "./tests/data/collapse-perf/results/windows-casing-collapsed-case-fold-frames.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_symbol_map() {
+    let mut options = Options::default();
+    options.symbol_map = Some(
+        fs::read_to_string("./tests/data/collapse-perf/symbol-map.txt")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    test_collapse_perf(
+        "./tests/data/collapse-perf/stripped.txt",
+        "./tests/data/collapse-perf/results/stripped-collapsed-symbol-map.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_symbol_map_invalid() {
+    let err = "0x1000 main\n\nmain".parse::<SymbolMap>().unwrap_err();
+    assert_eq!(err, "line 3: expected 0xADDR NAME, got: main");
+    let err = "0xmain main".parse::<SymbolMap>().unwrap_err();
+    assert_eq!(err, "line 1: invalid address: 0xmain");
+}
+
+#[test]
+fn collapse_perf_canonical_event() {
+    let mut options = Options::default();
+    options.canonical_event = true;
+    options.event_filter = Some("instructions:u".to_string());
+    test_collapse_perf(
+        "./tests/data/collapse-perf/multiple-events.txt",
+        "./tests/data/collapse-perf/results/multiple-events-collapsed-canonical-event.txt",
+        options,
+        false,
+    )
+    .unwrap();
+
+    // without canonicalization, the modifiers keep the filter from matching anything
+    let mut options = Options::default();
+    options.event_filter = Some("instructions:u".to_string());
+    let mut folder = Folder::from(options);
+    let input = fs::read("./tests/data/collapse-perf/multiple-events.txt").unwrap();
+    let mut output = Vec::new();
+    folder.collapse(&input[..], &mut output).unwrap();
+    assert!(output.is_empty(), "{}", String::from_utf8_lossy(&output));
+}
+
+#[test]
+fn collapse_perf_stack_hook() {
+    let mut options = Options::default();
+    options.include_pid = true;
+    options.stack_hook = Some(StackHook::new(|frames, pname| {
+        if pname.starts_with("swapper-") {
+            frames.clear();
+        } else {
+            frames.retain(|frame| !frame.starts_with("vfs_"));
+            frames.push(format!("from_{}", pname));
+        }
+    }));
+
+    for &n in &[1, 2] {
+        let mut options = options.clone();
+        options.nthreads = n;
+        let mut folder = Folder::from(options);
+        let input = fs::read("./tests/data/collapse-perf/kernel-user-stacks.txt").unwrap();
+        let mut output = Vec::new();
+        folder.collapse(&input[..], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "app-1001;main;compute;from_app-1001 1\n\
+             app-1001;main;nf_hook_slow;memcpy;from_app-1001 1\n\
+             app-1001;main;read;entry_SYSCALL_64;copy_user_generic_string;from_app-1001 2\n"
+        );
+    }
+}
+
+// An example custom parser, for a made-up format with one `sample COMM TID WEIGHT` line per
+// event, followed by its frames as indented `FUNC MODULE` lines, leaf first.
+struct SampleParser;
+
+impl LineParser for SampleParser {
+    fn parse(&mut self, line: &str, state: &mut PerfState<'_>) {
+        if line.starts_with('#') {
+            return;
+        }
+        if let Some(sample) = line.strip_prefix("sample ") {
+            if state.in_event() {
+                state.end_event();
+            }
+            let mut words = sample.split_whitespace();
+            let (comm, tid) = (words.next().unwrap(), words.next().unwrap());
+            state.begin_event(&Event::new(comm, tid));
+            state.set_weight(words.next().unwrap().parse().unwrap());
+        } else if let Some((func, module)) = line.trim_start().split_once(' ') {
+            state.frame("0", func, module);
+        }
+    }
+}
+
+#[test]
+fn collapse_perf_custom_parser() {
+    let input = "# made-up format\n\
+                 sample app 1001 3\n\
+                 \x20 memcpy+0x1f /usr/lib/libc.so.6\n\
+                 \x20 main /usr/bin/app\n\
+                 sample app 1002 1\n\
+                 \x20 native_safe_halt [kernel.kallsyms]\n\
+                 \x20 main /usr/bin/app\n\
+                 sample app 1001 2\n\
+                 \x20 memcpy /usr/lib/libc.so.6\n\
+                 \x20 main /usr/bin/app\n";
+    let mut options = Options::default();
+    options.annotate_kernel = true;
+    options.include_tid = true;
+    options.custom_parser = Some(CustomParser::new(SampleParser));
+    let mut folder = Folder::from(options);
+    let mut output = Vec::new();
+    folder.collapse(input.as_bytes(), &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "app-?/1001;main;memcpy 5\n\
+         app-?/1002;main;native_safe_halt_[k] 1\n"
+    );
+}
+
+#[test]
+fn collapse_perf_custom_parser_perf_script() {
+    let input = fs::read("./tests/data/collapse-perf/kernel-user-stacks.txt").unwrap();
+    let mut expected = Vec::new();
+    Folder::default()
+        .collapse(&input[..], &mut expected)
+        .unwrap();
+
+    let mut options = Options::default();
+    options.custom_parser = Some(CustomParser::new(PerfScriptParser));
+    let mut output = Vec::new();
+    Folder::from(options)
+        .collapse(&input[..], &mut output)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        String::from_utf8(expected).unwrap()
+    );
+}
+
+#[test]
+fn collapse_perf_sort_by_appearance() {
+    let mut options = Options::default();
+    options.sort_by_appearance = true;
+    for &n in &[1, 2] {
+        let mut options = options.clone();
+        options.nthreads = n;
+        let mut folder = Folder::from(options);
+        let input = fs::read("./tests/data/collapse-perf/runs.txt").unwrap();
+        let mut output = Vec::new();
+        folder.collapse(&input[..], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "app;main;compute 3\n\
+             app;main;spin 5\n\
+             app;main;read 1\n"
+        );
+    }
+}
+
+#[test]
+fn collapse_perf_dump_lines() {
+    let input = fs::read("./tests/data/collapse-perf/kernel-user-stacks.txt").unwrap();
+    let mut expected = Vec::new();
+    Folder::default()
+        .collapse(&input[..], &mut expected)
+        .unwrap();
+
+    let mut options = Options::default();
+    options.dump_lines = Some(8);
+    let mut folder = Folder::from(options);
+    let mut output = Vec::new();
+    folder.collapse(&input[..], &mut output).unwrap();
+    assert_eq!(output, expected);
+    let kinds: Vec<_> = folder
+        .dumped_lines()
+        .iter()
+        .map(|line| (line.line_number, line.kind))
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            (1, LineKind::Event),
+            (2, LineKind::Stack),
+            (3, LineKind::Stack),
+            (4, LineKind::Stack),
+            (5, LineKind::Stack),
+            (6, LineKind::Stack),
+            (7, LineKind::Blank),
+            (8, LineKind::Event),
+        ]
+    );
+    assert_eq!(
+        folder.dumped_lines()[0].text,
+        "app 1001/1001 [000] 100.000001: cycles:"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_emit_metadata() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--emit-metadata")
+        .arg("--weight-from-header")
+        .arg("./tests/data/collapse-perf/header-periods.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "# event cycles\n\
+         # countname cycles\n\
+         app;main;compute 650000\n\
+         app;main;spin 120000\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_dump_lines() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--dump-lines")
+        .arg("2")
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "     1 event   app 1001/1001 [000] 100.000001: cycles:\n\
+         \x20    2 stack   \tffffffff8b2e9f54 copy_user_generic_string ([kernel.kallsyms])\n"
+    );
+}
+
+#[test]
+fn collapse_perf_min_width_pct() {
+    let input = fs::read("./tests/data/collapse-perf/runs.txt").unwrap();
+    for &n in &[1, 2] {
+        // `read` has 1 of 9 samples, less than 20%
+        let mut options = Options::default();
+        options.min_width_pct = Some(20.0);
+        options.nthreads = n;
+        let mut output = Vec::new();
+        Folder::from(options.clone())
+            .collapse(&input[..], &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "app;main;compute 3\napp;main;spin 5\n"
+        );
+
+        options.min_width_other = true;
+        let mut output = Vec::new();
+        Folder::from(options)
+            .collapse(&input[..], &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[other] 1\napp;main;compute 3\napp;main;spin 5\n"
+        );
+    }
+}
+
+#[test]
+fn collapse_perf_strict() {
+    let input = fs::read("./tests/data/collapse-perf/weird-stack-line.txt").unwrap();
+    for &n in &[1, 2] {
+        let mut options = Options::default();
+        options.strict = true;
+        options.nthreads = n;
+        let error = Folder::from(options.clone())
+            .collapse(&input[..], io::sink())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "line 5: weird stack line: \t          THIS_IS_A_WEIRD_LINE"
+        );
+
+        // explicitly ignored lines don't count
+        options.ignore_prefix = vec!["THIS_IS_A_WEIRD".to_string()];
+        let mut output = Vec::new();
+        Folder::from(options)
+            .collapse(&input[..], &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "inline-counter;main 1\n"
+        );
+    }
+
+    // with several malformed lines, the first one is reported however many threads are used
+    let mut input = String::new();
+    for i in 0..2000 {
+        input.push_str("app 1001/1001 [000] 100.000001: cycles:\n");
+        input.push_str(if i == 300 || i == 1700 {
+            "\tNOT A STACK LINE\n"
+        } else {
+            "\t    55d0c1a2b600 compute (/usr/bin/app)\n"
+        });
+        input.push('\n');
+    }
+    for &n in &[1, 2, 8] {
+        let mut options = Options::default();
+        options.strict = true;
+        options.nthreads = n;
+        let error = Folder::from(options)
+            .collapse(input.as_bytes(), io::sink())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 902: weird stack line: \tNOT A STACK LINE"
+        );
+    }
+}
+
+#[test]
+fn collapse_perf_annotate_event() {
+    let input = fs::read("./tests/data/collapse-perf/sched-tracepoints.txt").unwrap();
+    let mut options = Options::default();
+    options.event_filter = Some("*".to_string());
+    options.annotate_event = vec!["sched:sched_switch=_[o]".parse().unwrap()];
+    options.annotate_kernel = true;
+    let mut output = Vec::new();
+    Folder::from(options)
+        .collapse(&input[..], &mut output)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "app;main;compute 1\n\
+         app;main;compute;handle_irq_event_[k] 1\n\
+         app;main;write;try_to_wake_up_[k];ttwu_do_wakeup_[k] 1\n\
+         app;main_[o];read_[o];schedule_[k]_[o];__schedule_[k]_[o] 2\n"
+    );
+}
+
+#[test]
+fn collapse_perf_keep_pname_spaces() {
+    let input = fs::read("./tests/data/collapse-perf/v8-worker-thread.txt").unwrap();
+    let mut options = Options::default();
+    options.keep_pname_spaces = true;
+    let mut output = Vec::new();
+    Folder::from(options)
+        .collapse(&input[..], &mut output)
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output
+        .lines()
+        .any(|line| line.starts_with("V8 WorkerThread;start_thread;")));
+    assert!(!output.contains("V8_WorkerThread"));
+}
+
+#[test]
+fn collapse_perf_hot_leaves() {
+    let input = "\
+app 1 [000] 1.0: cycles:
+\t1 zeta (/usr/bin/app)
+\t2 main (/usr/bin/app)
+
+app 1 [000] 2.0: cycles:
+\t3 alpha (/usr/bin/app)
+\t2 main (/usr/bin/app)
+
+db 2 [000] 3.0: cycles:
+\t4 fsync (/usr/bin/db)
+
+db 2 [000] 4.0: cycles:
+\t4 fsync (/usr/bin/db)
+
+db 2 [000] 5.0: cycles:
+\t5 query (/usr/bin/db)
+";
+    let mut options = Options::default();
+    options.hot_leaves = true;
+    let mut folder = Folder::from(options);
+    folder.collapse(input.as_bytes(), io::sink()).unwrap();
+    let hot_leaves: Vec<_> = folder
+        .hot_leaves()
+        .into_iter()
+        .map(|hot_leaf| {
+            (
+                hot_leaf.process,
+                hot_leaf.leaf,
+                hot_leaf.count,
+                hot_leaf.total,
+            )
+        })
+        .collect();
+    // ties are broken by frame name
+    assert_eq!(
+        hot_leaves,
+        vec![
+            ("app".to_string(), "alpha".to_string(), 1, 2),
+            ("db".to_string(), "fsync".to_string(), 2, 3),
+        ]
+    );
+}
+
+#[test]
+fn collapse_perf_cli_report_hot_leaf_per_process() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--report")
+        .arg("hot-leaf-per-process")
+        .arg("--pid")
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "COUNT    SHARE  PROCESS    LEAF\n\
+         \x20   2   50.00%  app-1001   copy_user_generic_string\n\
+         \x20   1  100.00%  swapper-0  native_safe_halt\n"
+    );
+}
+
+#[test]
+fn collapse_perf_no_idle() {
+    let input = fs::read("./tests/data/collapse-perf/idle-stacks.txt").unwrap();
+    let collapse = |options: Options| {
+        let mut output = Vec::new();
+        Folder::from(options)
+            .collapse(&input[..], &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    // the swapper processes, and the idle functions of other processes
+    let mut options = Options::default();
+    options.no_idle = true;
+    assert_eq!(
+        collapse(options.clone()),
+        "app;main;compute 1\napp;main;spin_wait 1\n"
+    );
+
+    // the idle functions can be overridden, but swapper is always idle
+    options.idle_frames = vec!["spin_wait".to_string()];
+    assert_eq!(
+        collapse(options),
+        "app;main;compute 1\nkidled;cpu_startup_entry;do_idle;intel_idle 1\n"
+    );
+}
+
+#[test]
+fn collapse_perf_unknown_comm() {
+    let test_file = "./tests/data/collapse-perf/unknown-comms.txt";
+    let mut options = Options::default();
+    options.include_pid = true;
+    options.unknown_comm = "exiting".to_string();
+    let mut folder = Folder::from(options);
+    let mut output = Vec::new();
+    folder.collapse_file(Some(test_file), &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "app-1001;main;compute 1\n\
+         exiting-4242;main;compute 1\n\
+         exiting-4243;do_group_exit;do_exit 1\n"
+    );
+}
+
+#[test]
+fn collapse_perf_fold_module() {
+    // the stacks enter libssl more than once, and every run of libssl frames is folded
+    let mut options = Options::default();
+    options.fold_module = vec!["libssl".to_string()];
+    test_collapse_perf(
+        "./tests/data/collapse-perf/module-runs.txt",
+        "./tests/data/collapse-perf/results/module-runs-collapsed-fold-module.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_fold_module_skip_after() {
+    // a function of a folded module still cuts off its callers
+    let input = fs::read("./tests/data/collapse-perf/module-runs.txt").unwrap();
+    let mut options = Options::default();
+    options.fold_module = vec!["libssl".to_string()];
+    options.skip_after = vec!["SSL_write".to_string()];
+    let mut output = Vec::new();
+    Folder::from(options)
+        .collapse(&input[..], &mut output)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "[libssl.so.3] 1\n\
+         [libssl.so.3];aesni_encrypt;[libssl.so.3] 1\n\
+         app;main;[libssl.so.3];verify_cb;[libssl.so.3];memcpy 1\n"
+    );
+}
+
+#[test]
+fn collapse_perf_comment_prefix() {
+    let mut options = Options::default();
+    options.comment_prefix = Some("request:".to_string());
+    test_collapse_perf(
+        "./tests/data/collapse-perf/comment-markers.txt",
+        "./tests/data/collapse-perf/results/comment-markers-collapsed-comment-prefix.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_cpus_per_node() {
+    let mut options = Options::default();
+    options.cpus_per_node = Some(2);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/comm-with-colon.txt",
+        "./tests/data/collapse-perf/results/comm-with-colon-collapsed-cpus-per-node.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_v8_worker_thread_cpus_per_node() {
+    // neither the space in the process name nor the CPU after the TID confuse each other
+    let mut options = Options::default();
+    options.cpus_per_node = Some(2);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/v8-worker-thread.txt",
+        "./tests/data/collapse-perf/results/v8-worker-thread-collapsed-cpus-per-node.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_annotate_regex() {
+    let mut options = Options::default();
+    options.annotate_regex = vec![
+        "module:^/opt/My App/=_[app]".parse().unwrap(),
+        "^run_=_[run]".parse().unwrap(),
+        "^void =_[void]".parse().unwrap(),
+    ];
+    test_collapse_perf(
+        "./tests/data/collapse-perf/demangled-stacks.txt",
+        "./tests/data/collapse-perf/results/demangled-stacks-collapsed-annotate-regex.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_timeline() {
+    // Every event is written separately and in input order, even identical ones at the same
+    // timestamp.
+    let mut options = Options::default();
+    options.timeline = true;
+    test_collapse_perf(
+        "./tests/data/collapse-perf/timeline.txt",
+        "./tests/data/collapse-perf/results/timeline-collapsed-timeline.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_timeline_without_timestamps() {
+    let mut options = Options::default();
+    options.timeline = true;
+    test_collapse_perf_logs_with_options(
+        "./tests/data/collapse-perf/no-timestamps.txt",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body
+                        .starts_with("line 1: Dropping events without a timestamp")
+                })
+                .count();
+            assert_eq!(nwarnings, 1);
+        },
+        options,
+    );
+}
+
+#[test]
+fn collapse_perf_max_stack_frames() {
+    // Each event has two frames, so a limit of two keeps them all.
+    let input_file = "./tests/data/collapse-perf/timeline.txt";
+    for (max_stack_frames, expected) in [(1, ""), (2, "app;main;compute 2\napp;main;parse 2\n")] {
+        let mut options = Options::default();
+        options.max_stack_frames = max_stack_frames;
+        let mut folded = Vec::new();
+        Folder::from(options)
+            .collapse_file(Some(input_file), &mut folded)
+            .unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), expected);
+    }
+
+    let mut options = Options::default();
+    options.max_stack_frames = 1;
+    test_collapse_perf_logs_with_options(
+        input_file,
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| {
+                    log.body
+                        .contains(": Dropping event of app with more than 1 frames")
+                })
+                .count();
+            assert_eq!(nwarnings, 4);
+        },
+        options,
+    );
+}
+
+#[test]
+fn collapse_perf_strip_comm_prefix() {
+    // Only true leading prefixes are removed, so `my-k8s_app` is left alone, and so is `k8s_`,
+    // which would otherwise be left without a name.
+    let mut options = Options::default();
+    options.strip_comm_prefix = vec!["k8s_".to_string(), "runc:".to_string()];
+    test_collapse_perf(
+        "./tests/data/collapse-perf/comm-prefixes.txt",
+        "./tests/data/collapse-perf/results/comm-prefixes-collapsed-strip-comm-prefix.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+/// Records the events it's given, one string per event.
+#[derive(Default)]
+struct EventRecorder(Vec<String>);
+
+impl EventSink for EventRecorder {
+    fn on_event(&mut self, event: &Event<'_>) {
+        self.0.push(format!(
+            "{} {:?} {} {:?} {:?} {:?} {:?}:",
+            event.comm, event.pid, event.tid, event.cpu, event.timestamp, event.period, event.name
+        ));
+    }
+
+    fn on_frame(&mut self, func: &str, module: &str) {
+        let event = self.0.last_mut().unwrap();
+        event.push_str(&format!(" {} ({})", func, module));
+    }
+
+    fn on_event_end(&mut self) {
+        self.0.last_mut().unwrap().push_str(" end");
+    }
+}
+
+#[test]
+fn collapse_perf_read_events() {
+    let input = "\
+# a comment
+java 24636/25607 [002] 4794564.109216:     257597 cycles:uppp:
+\t    7f1e2215d058 __GI___open64_nocancel+0x1f (/usr/lib/libc-2.31.so)
+\t    55d0c1a2b500 main (/usr/bin/java)
+
+false 64414 20110.539270:      34467 cycles:u:  ffffffff9aa3c8de [unknown] ([unknown])
+
+V8 WorkerThread 25607 cpu-clock:
+\tffffffff8103ce3b native_safe_halt ([kernel.kallsyms])
+";
+    let mut recorder = EventRecorder::default();
+    read_events(input.as_bytes(), &mut recorder).unwrap();
+    assert_eq!(
+        recorder.0,
+        [
+            "java Some(\"24636\") 25607 Some(2) Some(\"4794564.109216\") Some(257597) \
+             Some(\"cycles\"): __GI___open64_nocancel (/usr/lib/libc-2.31.so) \
+             main (/usr/bin/java) end",
+            "false None 64414 None Some(\"20110.539270\") Some(34467) Some(\"cycles\"): \
+             [unknown] ([unknown]) end",
+            "V8 WorkerThread None 25607 None None None Some(\"cpu-clock\"): \
+             native_safe_halt ([kernel.kallsyms]) end",
+        ]
+    );
+}
+
+#[test]
+fn collapse_perf_read_events_histogram() {
+    // a sink that counts the samples in every second
+    #[derive(Default)]
+    struct Histogram(Vec<(String, usize)>);
+
+    impl EventSink for Histogram {
+        fn on_event(&mut self, event: &Event<'_>) {
+            let second = event.timestamp.unwrap().split('.').next().unwrap();
+            match self.0.last_mut() {
+                Some((last, count)) if last == second => *count += 1,
+                _ => self.0.push((second.to_string(), 1)),
+            }
+        }
+
+        fn on_frame(&mut self, _func: &str, _module: &str) {}
+
+        fn on_event_end(&mut self) {}
+    }
+
+    let mut histogram = Histogram::default();
+    let file = File::open("./tests/data/collapse-perf/timeline.txt").unwrap();
+    read_events(BufReader::new(file), &mut histogram).unwrap();
+    assert_eq!(
+        histogram.0,
+        [
+            ("9".to_string(), 1),
+            ("10".to_string(), 2),
+            ("11".to_string(), 1)
+        ]
+    );
+}
+
+#[test]
+fn collapse_perf_keep_frame() {
+    // The first sample's leaf (memcpy) is dropped, so it's attributed to its closest kept
+    // ancestor, and lib_dispatch is dropped from the middle of the first two samples. The last
+    // sample has no frames left, so it's dropped entirely.
+    let mut options = Options::default();
+    options.keep_frame = Some(Regex::new("^app::|^main$").unwrap());
+    test_collapse_perf(
+        "./tests/data/collapse-perf/keep-frame.txt",
+        "./tests/data/collapse-perf/results/keep-frame-collapsed.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_plt_regex() {
+    let mut options = Options::default();
+    options.fold_plt = true;
+    options.plt_regex = Some(Regex::new("@plt$|^_dl_").unwrap());
+    test_collapse_perf(
+        "./tests/data/collapse-perf/plt-stubs.txt",
+        "./tests/data/collapse-perf/results/plt-stubs-collapsed-plt-regex.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_cut() {
+    let input_file = "./tests/data/collapse-perf/cut-markers.txt";
+    for &(cut_above, cut_below, cut_leafmost, name) in &[
+        (Some("^(lock|walk)$"), None, false, "cut-above"),
+        (Some("^(lock|walk)$"), None, true, "cut-above-leafmost"),
+        (None, Some("^(run|walk)$"), false, "cut-below"),
+        (None, Some("^(run|walk)$"), true, "cut-below-leafmost"),
+        (Some("^walk$"), Some("^run$"), false, "cut-above-below"),
+    ] {
+        let mut options = Options::default();
+        options.annotate_kernel = true;
+        options.cut_above = cut_above.map(|re| Regex::new(re).unwrap());
+        options.cut_below = cut_below.map(|re| Regex::new(re).unwrap());
+        options.cut_leafmost = cut_leafmost;
+        let expected_file = format!(
+            "./tests/data/collapse-perf/results/cut-markers-collapsed-{}.txt",
+            name
+        );
+        test_collapse_perf(input_file, &expected_file, options, false).unwrap();
+    }
+}
+
+#[test]
+fn collapse_perf_from_folded() {
+    // Stacks that differ only in dropped frames are aggregated again, and the `_[k]` annotation
+    // in the input is kept while matching against the bare function name. Stacks with no frames
+    // left, and lines that aren't folded stacks, are dropped.
+    let mut options = Options::default();
+    options.from_folded = true;
+    options.keep_frame = Some(Regex::new("^app::|^main$|^sys_").unwrap());
+    options.annotate_regex = vec![
+        "^sys_=_[sys]".parse().unwrap(),
+        "^app::codec::=_[codec]".parse().unwrap(),
+    ];
+    test_collapse_perf(
+        "./tests/data/collapse-perf/folded.txt",
+        "./tests/data/collapse-perf/results/folded-collapsed-from-folded.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_root_frame() {
+    // The root frame goes above the process name (and its PID).
+    let mut options = Options::default();
+    options.root_frame = Some("all".to_string());
+    options.include_pid = true;
+    test_collapse_perf(
+        "./tests/data/collapse-perf/kernel-user-stacks.txt",
+        "./tests/data/collapse-perf/results/kernel-user-stacks-collapsed-root-frame.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_vdso_regex() {
+    // vDSO functions are identified by their name, whether perf knows their module or not, and
+    // even where the module would make them kernel functions.
+    let mut options = Options::default();
+    options.vdso_regex = Some(Regex::new("^__(vdso|kernel)_").unwrap());
+    options.annotate_kernel = true;
+    options.annotate_jit = true;
+    test_collapse_perf(
+        "./tests/data/collapse-perf/vdso-unknown-module.txt",
+        "./tests/data/collapse-perf/results/vdso-unknown-module-collapsed-vdso-regex.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_tag_all() {
+    // The tag goes below the leaf, whatever the root frames.
+    let mut options = Options::default();
+    options.tag_all = Some("host;1".to_string());
+    options.root_frame = Some("all".to_string());
+    test_collapse_perf(
+        "./tests/data/collapse-perf/kernel-user-stacks.txt",
+        "./tests/data/collapse-perf/results/kernel-user-stacks-collapsed-tag-all.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_weight_column_without_timestamp() {
+    let collapse = |input: &str, column| {
+        let mut options = Options::default();
+        options.weight_column = Some(column);
+        let mut output = Vec::new();
+        Folder::from(options)
+            .collapse(input.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    // the columns are counted from the timestamp, with or without a CPU before it
+    let input = "app 1001/1001 3030.123456: 200 offcpu-time:\n\
+                 \t    55d0c1a2b500 main (/usr/bin/app)\n";
+    assert_eq!(collapse(input, 1), "app;main 200\n");
+
+    // without a timestamp there is nothing to count from, so the PC that follows the event
+    // name isn't mistaken for the weight
+    let input = "app 1001/1001 [000] 1520 cycles:u:  400536 main (/usr/bin/app)\n";
+    assert_eq!(collapse(input, 2), "app;main 1\n");
+}
+
+#[test]
+fn collapse_perf_should_warn_once_about_missing_weight_column() {
+    let mut options = Options::default();
+    options.weight_column = Some(1);
+    test_collapse_perf_logs_with_options(
+        "./tests/data/collapse-perf/weight-column.txt",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| log.body.contains(": Weight column ") && log.level == Level::Warn)
+                .count();
+            assert_eq!(
+                nwarnings, 1,
+                "weight column warning logged {} times, but should be logged exactly once",
+                nwarnings
+            );
+        },
+        options,
+    );
+}
+
+#[test]
+fn collapse_perf_stderr_merged() {
+    let mut options = Options::default();
+    options.ignore_prefix = vec![
+        "Processed ".to_string(),
+        "Check IO/CPU".to_string(),
+        "[site-wrapper]".to_string(),
+    ];
+    test_collapse_perf(
+        "./tests/data/collapse-perf/stderr-merged.txt",
+        "./tests/data/collapse-perf/results/stderr-merged-collapsed.txt",
+        options.clone(),
+        false,
+    )
+    .unwrap();
+
+    test_collapse_perf_logs_with_options(
+        "./tests/data/collapse-perf/stderr-merged.txt",
+        |captured_logs| {
+            let nwarnings = captured_logs
+                .iter()
+                .filter(|log| log.level == Level::Warn)
+                .count();
+            assert_eq!(
+                nwarnings, 0,
+                "perf noise caused {} warnings, but should be skipped silently",
+                nwarnings
+            );
+        },
+        options,
+    );
+}
+
 #[test]
 fn collapse_perf_should_warn_about_empty_input_lines() {
     test_collapse_perf_logs(
@@ -256,7 +1597,7 @@ fn collapse_perf_should_warn_about_empty_input_lines() {
             let nwarnings = captured_logs
                 .iter()
                 .filter(|log| {
-                    log.body.starts_with("Weird event line: ") && log.level == Level::Warn
+                    log.body.starts_with("line 6: Weird event line: ") && log.level == Level::Warn
                 })
                 .count();
             assert_eq!(
@@ -268,6 +1609,175 @@ fn collapse_perf_should_warn_about_empty_input_lines() {
     );
 }
 
+#[test]
+fn collapse_perf_group_by_tid_lifetime() {
+    let mut options = Options::default();
+    options.group_by_tid_lifetime = Some(2.0);
+    test_collapse_perf(
+        "./tests/data/collapse-perf/tid-reuse.txt",
+        "./tests/data/collapse-perf/results/tid-reuse-collapsed-group-by-tid-lifetime.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_strip_ansi() {
+    let mut options = Options::default();
+    options.annotate_kernel = true;
+    options.strip_ansi = true;
+    test_collapse_perf(
+        "./tests/data/collapse-perf/ansi-colors.txt",
+        "./tests/data/collapse-perf/results/ansi-colors-collapsed-strip-ansi.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_module_basename() {
+    let mut options = Options::default();
+    options.annotate_jit = true;
+    options.annotate_kernel = true;
+    options.annotate_regex = vec!["module:^libc\\.so\\.6$=_[libc]".parse().unwrap()];
+    options.module_basename = true;
+    test_collapse_perf(
+        "./tests/data/collapse-perf/module-paths.txt",
+        "./tests/data/collapse-perf/results/module-paths-collapsed-module-basename.txt",
+        options,
+        false,
+    )
+    .unwrap();
+}
+
+#[test]
+fn collapse_perf_limit_events() {
+    let mut options = Options::default();
+    options.limit_events = Some(4);
+    let mut folder = Folder::from(options);
+    let mut reader = BufReader::new(File::open("./tests/data/collapse-perf/runs.txt").unwrap());
+    let mut output = Vec::new();
+    folder.collapse(&mut reader, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "app;main;compute 2\napp;main;spin 2\n"
+    );
+
+    // the input after the last counted event is left unread
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert!(rest.starts_with("app "), "{}", rest);
+}
+
+#[test]
+fn collapse_perf_max_malformed() {
+    let input = fs::read("./tests/data/collapse-perf/weird-stack-line.txt").unwrap();
+    for &n in &[1, 2] {
+        let mut options = Options::default();
+        options.max_malformed = Some(1);
+        options.nthreads = n;
+        let mut output = Vec::new();
+        Folder::from(options.clone())
+            .collapse(&input[..], &mut output)
+            .unwrap();
+        assert!(!output.is_empty());
+
+        options.max_malformed = Some(0);
+        let mut output = Vec::new();
+        let err = Folder::from(options)
+            .collapse(&input[..], &mut output)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(output.is_empty());
+    }
+}
+
+#[test]
+fn collapse_perf_max_unknown_ratio() {
+    // 6 of the 11 frames have an unknown symbol
+    let input = fs::read("./tests/data/collapse-perf/stripped.txt").unwrap();
+    for &n in &[1, 2] {
+        let mut options = Options::default();
+        options.max_unknown_ratio = Some(0.6);
+        options.nthreads = n;
+        let mut output = Vec::new();
+        Folder::from(options.clone())
+            .collapse(&input[..], &mut output)
+            .unwrap();
+        assert!(!output.is_empty());
+
+        options.max_unknown_ratio = Some(0.5);
+        let mut output = Vec::new();
+        let err = Folder::from(options)
+            .collapse(&input[..], &mut output)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(output.is_empty());
+    }
+}
+
+#[test]
+fn collapse_perf_collect_warnings() {
+    let input = b"Warning: Kernel address maps were restricted\n\
+        app 1001/1001 [000] 100.000001: cycles:\n\
+        \t55d0c1a2b600 compute (/usr/bin/app)\n\
+        \tWEIRD\n\
+        \n\
+        NOT AN EVENT\n\
+        \n";
+    for &n in &[1, 2] {
+        let mut options = Options::default();
+        options.collect_warnings = true;
+        options.nthreads = n;
+        let mut folder = Folder::from(options);
+        folder.collapse(&input[..], io::sink()).unwrap();
+        let warnings: Vec<_> = folder
+            .warnings()
+            .iter()
+            .map(|warning| (warning.line_number, warning.kind, &warning.text[..]))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (
+                    1,
+                    WarningKind::IgnoredLine,
+                    "Warning: Kernel address maps were restricted"
+                ),
+                (4, WarningKind::WeirdStack, "\tWEIRD"),
+                (6, WarningKind::WeirdEvent, "NOT AN EVENT"),
+            ]
+        );
+    }
+
+    // warnings are only collected on request
+    let mut folder = Folder::default();
+    folder.collapse(&input[..], io::sink()).unwrap();
+    assert!(folder.warnings().is_empty());
+}
+
+#[test]
+fn collapse_perf_cli_warnings_json() {
+    let warnings_file = std::env::temp_dir().join(format!("test-{}.json", rand::random::<u64>()));
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--warnings-json")
+        .arg(&warnings_file)
+        .arg("./tests/data/collapse-perf/weird-stack-line.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    // the warnings are not logged as well
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+    assert_eq!(
+        fs::read_to_string(&warnings_file).unwrap(),
+        "[\n  { \"line\": 5, \"category\": \"weird-stack\", \"text\": \"\\t          THIS_IS_A_WEIRD_LINE\" }\n]\n"
+    );
+    fs::remove_file(&warnings_file).unwrap();
+}
+
 #[test]
 fn collapse_perf_should_warn_about_weird_input_lines() {
     test_collapse_perf_logs(
@@ -276,7 +1786,8 @@ fn collapse_perf_should_warn_about_weird_input_lines() {
             let nwarnings = captured_logs
                 .iter()
                 .filter(|log| {
-                    log.body.starts_with("Weird stack line: ") && log.level == Level::Warn
+                    log.body == "line 5: Weird stack line: \t          THIS_IS_A_WEIRD_LINE"
+                        && log.level == Level::Warn
                 })
                 .count();
             assert_eq!(
@@ -318,3 +1829,559 @@ fn collapse_perf_cli() {
     let expected = BufReader::new(File::open(expected_file).unwrap());
     common::compare_results(Cursor::new(output.stdout), expected, expected_file, true);
 }
+
+#[test]
+fn collapse_perf_cli_check() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--check")
+        .arg("./tests/data/collapse-perf/single-line-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Examined "), "{}", stdout);
+    assert!(stdout.contains("\ndso     yes\n"), "{}", stdout);
+    assert!(
+        stdout.contains("\n--tid                   no (needs -F pid,tid)\n"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("\n--kernel, --jit, --all  yes\n"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn collapse_perf_cli_opts_env_var() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .env(
+            "INFERNO_COLLAPSE_PERF_OPTS",
+            " --kernel  --root-frame env --skip-after vfs_read ",
+        )
+        .arg("--root-frame")
+        .arg("cli")
+        .arg("--skip-after")
+        .arg("compute")
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    // --kernel comes from the environment, --root-frame from the command line, and both
+    // --skip-after values apply
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "cli;app;main;nf_hook_slow_[k];memcpy 1\n\
+         cli;compute 1\n\
+         cli;swapper;start_kernel_[k];default_idle_[k];native_safe_halt_[k] 1\n\
+         cli;vfs_read_[k];copy_user_generic_string_[k] 2\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_category_map() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--category-map")
+        .arg("./tests/data/collapse-perf/category-map.txt")
+        .arg("--default-category")
+        .arg("apps")
+        .arg("--root-frame")
+        .arg("all")
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "all;apps;app;main;compute 1\n\
+         all;apps;app;main;nf_hook_slow;memcpy 1\n\
+         all;apps;app;main;read;entry_SYSCALL_64;vfs_read;copy_user_generic_string 2\n\
+         all;idle;swapper;start_kernel;default_idle;native_safe_halt 1\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_emit_total() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--emit-total")
+        .arg("--weight-column")
+        .arg("1")
+        .arg("-q")
+        .arg("./tests/data/collapse-perf/weight-column.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    // the total respects the weights, and comes before the unchanged folded output
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "# total_samples 2077\n\
+         mysqld;main;pthread_cond_wait;schedule 2000\n\
+         mysqld;main;read;schedule 77\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_output() {
+    let input_file = "./tests/data/collapse-perf/weight-column.txt";
+    let expected =
+        fs::read_to_string("./tests/data/collapse-perf/results/weight-column-collapsed.txt")
+            .unwrap();
+
+    let rand: u64 = rand::random();
+    for compressed in [false, true] {
+        let extension = if compressed { "folded.gz" } else { "folded" };
+        let output_file = std::env::temp_dir().join(format!("test-{}.{}", rand, extension));
+        let output = Command::cargo_bin("inferno-collapse-perf")
+            .unwrap()
+            .arg("--weight-column")
+            .arg("1")
+            .arg("--output")
+            .arg(&output_file)
+            .arg(input_file)
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+
+        let file = File::open(&output_file).unwrap();
+        let mut folded = String::new();
+        if compressed {
+            libflate::gzip::Decoder::new(file)
+                .unwrap()
+                .read_to_string(&mut folded)
+                .unwrap();
+        } else {
+            BufReader::new(file).read_to_string(&mut folded).unwrap();
+        }
+        fs::remove_file(&output_file).unwrap();
+        assert_eq!(folded, expected);
+    }
+}
+
+#[test]
+fn collapse_perf_cli_merge_into() {
+    let merged_file = std::env::temp_dir().join(format!("test-{}.folded", rand::random::<u64>()));
+    for _ in 0..2 {
+        let output = Command::cargo_bin("inferno-collapse-perf")
+            .unwrap()
+            .arg("--merge-into")
+            .arg(&merged_file)
+            .arg("./tests/data/collapse-perf/runs.txt")
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+    }
+    let merged = fs::read_to_string(&merged_file).unwrap();
+    fs::remove_file(&merged_file).unwrap();
+    assert_eq!(
+        merged,
+        "app;main;compute 6\napp;main;read 2\napp;main;spin 10\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_shard_output() {
+    let input = "./tests/data/collapse-perf/java-inline.txt";
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg(input)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let expected = String::from_utf8(output.stdout).unwrap();
+
+    let prefix = std::env::temp_dir().join(format!("test-{}", rand::random::<u64>()));
+    let shard_path = |shard: usize| {
+        let mut path = prefix.as_os_str().to_owned();
+        path.push(format!(".{}", shard));
+        std::path::PathBuf::from(path)
+    };
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let output = Command::cargo_bin("inferno-collapse-perf")
+            .unwrap()
+            .arg("--shard-output")
+            .arg(&prefix)
+            .arg("--shards")
+            .arg("3")
+            .arg(input)
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        let shards = (0..3)
+            .map(|shard| fs::read_to_string(shard_path(shard)).unwrap())
+            .collect::<Vec<_>>();
+        runs.push(shards);
+    }
+    for shard in 0..3 {
+        fs::remove_file(shard_path(shard)).unwrap();
+    }
+    assert!(!shard_path(3).exists());
+
+    // the shards are the same across runs, each is sorted, and together they hold every stack
+    // exactly once
+    assert_eq!(runs[0], runs[1]);
+    let mut lines = Vec::new();
+    for shard in &runs[0] {
+        let shard_lines = shard.lines().collect::<Vec<_>>();
+        let mut sorted = shard_lines.clone();
+        sorted.sort_unstable();
+        assert_eq!(shard_lines, sorted);
+        lines.extend(shard_lines);
+    }
+    lines.sort_unstable();
+    assert_eq!(lines, expected.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn collapse_perf_cli_emit_schema() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--emit-schema")
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let schema = String::from_utf8(output.stdout).unwrap();
+    assert!(schema.contains(r#""nevents": 1,"#));
+    assert!(schema.contains(r#""comm": { "present": true, "example": "app" }"#));
+    assert!(schema.contains(r#""period": { "present": false, "example": null }"#));
+    assert!(schema.contains(r#""ip": { "present": true, "example": "ffffffff8b2e9f54" }"#));
+    assert!(schema.contains(r#""dso": { "present": true, "example": "[kernel.kallsyms]" }"#));
+
+    // the only frame of single-line stacks is on the event line itself
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--emit-schema")
+        .arg("./tests/data/collapse-perf/single-line-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let schema = String::from_utf8(output.stdout).unwrap();
+    assert!(schema.contains(r#""ip": { "present": true, "example": "ffffffff9b201293" }"#));
+}
+
+#[test]
+#[cfg(not(feature = "remote"))]
+fn collapse_perf_cli_url_without_remote_feature() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("s3://profiles/host01/perf.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("https://profiles.s3.amazonaws.com/host01/perf.txt: reading from a URL"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("`remote` feature"), "{}", stderr);
+}
+
+#[test]
+fn collapse_perf_cli_group_output() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--group-output")
+        .arg("--emit-total")
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let grouped = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        grouped,
+        "# total_samples 5\n\
+         app;main;compute 1\n\
+         app;main;nf_hook_slow;memcpy 1\n\
+         app;main;read;entry_SYSCALL_64;vfs_read;copy_user_generic_string 2\n\
+         \n\
+         swapper;start_kernel;default_idle;native_safe_halt 1\n"
+    );
+
+    // the empty lines don't get in the way of rendering the stacks
+    let mut options = inferno::flamegraph::Options::default();
+    let mut svg = Vec::new();
+    inferno::flamegraph::from_lines(&mut options, grouped.lines(), &mut svg).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(svg.contains("swapper"));
+    assert!(svg.contains("copy_user_generic_string"));
+}
+
+#[test]
+fn collapse_perf_cli_flamegraph() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--flamegraph")
+        .arg("--title")
+        .arg("My App")
+        .arg("--colors")
+        .arg("java")
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    // the same flame graph as that of inferno-flamegraph for the folded stacks
+    let folded =
+        fs::read_to_string("./tests/data/collapse-perf/results/kernel-user-stacks-collapsed.txt")
+            .unwrap();
+    let mut options = inferno::flamegraph::Options::default();
+    options.title = "My App".to_string();
+    options.colors = "java".parse().unwrap();
+    let mut expected = Vec::new();
+    inferno::flamegraph::from_lines(&mut options, folded.lines(), &mut expected).unwrap();
+    let expected = String::from_utf8(expected).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+    // like the folded stacks, the flame graph is compressed if its file ends in .gz
+    let output_file = std::env::temp_dir().join(format!("test-{}.svg.gz", rand::random::<u64>()));
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--flamegraph")
+        .arg("--title")
+        .arg("My App")
+        .arg("--colors")
+        .arg("java")
+        .arg("--output")
+        .arg(&output_file)
+        .arg("./tests/data/collapse-perf/kernel-user-stacks.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let mut svg = String::new();
+    libflate::gzip::Decoder::new(File::open(&output_file).unwrap())
+        .unwrap()
+        .read_to_string(&mut svg)
+        .unwrap();
+    fs::remove_file(&output_file).unwrap();
+    assert_eq!(svg, expected);
+}
+
+#[test]
+fn collapse_perf_cli_cache() {
+    let input_file = "./tests/data/collapse-perf/go-stacks.txt";
+    let expected =
+        fs::read_to_string("./tests/data/collapse-perf/results/go-stacks-collapsed.txt").unwrap();
+
+    let cache_file = std::env::temp_dir().join(format!("test-{}.cache", rand::random::<u64>()));
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--write-cache")
+        .arg(&cache_file)
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    // frames that are shared between stacks are only stored once
+    assert!((fs::metadata(&cache_file).unwrap().len() as usize) < expected.len());
+
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--read-cache")
+        .arg(&cache_file)
+        .output()
+        .expect("failed to execute process");
+    fs::remove_file(&cache_file).unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+    // folded stacks are not a cache
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--read-cache")
+        .arg("./tests/data/collapse-perf/results/go-stacks-collapsed.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("not a valid cache file"), "{}", stderr);
+}
+
+#[test]
+fn collapse_perf_cli_stats() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--stats")
+        .arg("--runs")
+        .arg("./tests/data/collapse-perf/runs.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "app;main;compute 3\napp;main;read 1\napp;main;spin 5\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "events: 9\nstack depth: min 2, median 2, p95 2, max 2\nlongest run: 3 events of app;main;spin\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_validate_pcs() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--stats")
+        .arg("--validate-pcs")
+        .arg("./tests/data/collapse-perf/zero-pcs.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    // only the diagnostic changes, not the folded stacks
+    let expected = fs::read("./tests/data/collapse-perf/results/zero-pcs-collapsed.txt").unwrap();
+    assert_eq!(output.stdout, expected);
+    // of the stacks with a frame at address 0, only the outermost frame of the first is fine
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "events: 4\nstack depth: min 2, median 2, p95 3, max 3\nstacks with suspicious PCs: 2\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_debug_counts() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--stats")
+        .arg("--debug-counts")
+        .arg("./tests/data/collapse-perf/runs.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "events: 9\n\
+         stack depth: min 2, median 2, p95 2, max 2\n\
+         COUNT  FRAME\n\
+         \x20   9  main\n\
+         \x20   5  spin\n\
+         \x20   3  compute\n\
+         \x20   1  read\n"
+    );
+
+    // the counts are a debugging aid for --stats
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--debug-counts")
+        .arg("./tests/data/collapse-perf/runs.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn collapse_perf_cli_count_unique_stacks_only() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--count-unique-stacks-only")
+        .arg("./tests/data/collapse-perf/runs.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "unique stacks: 3\nsamples: 9\ntop 1% of stacks (1): 55.56% of samples\n"
+    );
+
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--count-unique-stacks-only")
+        .arg("./tests/data/collapse-perf/no-events.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "unique stacks: 0\nsamples: 0\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_report_flat() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--report")
+        .arg("flat")
+        .arg("./tests/data/collapse-perf/runs.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "INCLUSIVE    INCL%  SELF    SELF%  FUNCTION\n\
+         \x20       9  100.00%     0    0.00%  main\n\
+         \x20       5   55.56%     5   55.56%  spin\n\
+         \x20       3   33.33%     3   33.33%  compute\n\
+         \x20       1   11.11%     1   11.11%  read\n"
+    );
+}
+
+#[test]
+fn collapse_perf_cli_diff() {
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--weight-column")
+        .arg("1")
+        .arg("--diff")
+        .arg("./tests/data/collapse-perf/diff-before.txt")
+        .arg("./tests/data/collapse-perf/weight-column.txt")
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+
+    // stacks that are missing from either side count as zero there
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines: Vec<_> = stdout.lines().collect();
+    lines.sort_unstable();
+    assert_eq!(
+        lines,
+        [
+            "mysqld;main;pthread_cond_wait;schedule 1500 2000",
+            "mysqld;main;read;schedule 0 77",
+            "mysqld;main;write;schedule 30 0",
+        ]
+    );
+}
+
+#[test]
+fn collapse_perf_cli_top() {
+    let input_file = "./tests/data/collapse-perf/demangled-stacks.txt";
+    let expected_file = "./tests/data/collapse-perf/results/demangled-stacks-collapsed.txt";
+
+    let output = Command::cargo_bin("inferno-collapse-perf")
+        .unwrap()
+        .arg("--top")
+        .arg("1")
+        .arg(input_file)
+        .output()
+        .expect("failed to execute process");
+
+    // The folded output is unaffected...
+    let expected = BufReader::new(File::open(expected_file).unwrap());
+    common::compare_results(Cursor::new(output.stdout), expected, expected_file, false);
+
+    // ...and the hottest stack is summarized on STDERR.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "COUNT  STACK\n\
+         -----  ----------------------\n    \
+             2  demangled;main;run_app\n"
+    );
+}
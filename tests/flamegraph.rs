@@ -629,6 +629,25 @@ fn flamegraph_count_name_with_symbols() {
     test_flamegraph(input_file, expected_result_file, options).unwrap();
 }
 
+#[test]
+fn flamegraph_count_name_from_input() {
+    let input = "# event cycles\n# countname cycles\napp;main 3\napp;spin 1\n";
+
+    let mut svg = Vec::new();
+    flamegraph::from_lines(&mut Options::default(), input.lines(), &mut svg).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(svg.contains("all (4 cycles, 100%)"), "{}", svg);
+    assert!(svg.contains("main (3 cycles, 75.00%)"), "{}", svg);
+
+    // an explicit count name takes precedence
+    let mut options = Options::default();
+    options.count_name = "events".to_owned();
+    let mut svg = Vec::new();
+    flamegraph::from_lines(&mut options, input.lines(), &mut svg).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(svg.contains("all (4 events, 100%)"), "{}", svg);
+}
+
 #[test]
 fn flamegraph_name_type_simple() {
     let input_file =